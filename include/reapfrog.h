@@ -0,0 +1,47 @@
+/*
+ *   reapfrog
+ *   Copyright (C) 2017 The 8472
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+#ifndef REAPFROG_H
+#define REAPFROG_H
+
+#include <stddef.h>
+#include <stdint.h>
+#include <sys/types.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Functions that can fail return a negated errno value. */
+
+typedef struct ReapfrogQueue ReapfrogQueue;
+
+/* Sets *len and returns the bytes of the next path, valid until the next call, or NULL once exhausted. */
+typedef const char *(*reapfrog_next_path)(void *ctx, size_t *len);
+
+ReapfrogQueue *reapfrog_new_paths(const char *const *paths, size_t n);
+ReapfrogQueue *reapfrog_new_callback(reapfrog_next_path next, void *ctx);
+
+void reapfrog_set_budget(ReapfrogQueue *q, uint64_t bytes);
+void reapfrog_set_dropbehind(ReapfrogQueue *q, int enabled);
+
+/* 1 if the next file is available, 0 once exhausted, -errno if it failed to open. */
+int reapfrog_next(ReapfrogQueue *q);
+/* Bytes read from the current file, 0 at its end. */
+ssize_t reapfrog_read(ReapfrogQueue *q, void *buf, size_t len);
+/* Path of the current file, valid until the next reapfrog_next or reapfrog_free. */
+const char *reapfrog_path(const ReapfrogQueue *q);
+
+void reapfrog_free(ReapfrogQueue *q);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
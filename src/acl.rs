@@ -0,0 +1,105 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::fs::File;
+use std::io::{Error, ErrorKind};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use xattr::{self, Xattr};
+use MultiFileReadahead;
+
+const ACL_ACCESS : &[u8] = b"system.posix_acl_access";
+const ACL_DEFAULT : &[u8] = b"system.posix_acl_default";
+const SECURITY_PREFIX : &[u8] = b"security.";
+const ACL_VERSION : u32 = 2;
+
+/// Who an [`AclEntry`](struct.AclEntry.html) applies to, the `ACL_*` tags of `acl(5)`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AclTag {
+    UserObj,
+    User(u32),
+    GroupObj,
+    Group(u32),
+    Mask,
+    Other,
+}
+
+/// One entry of a POSIX ACL, `perm` holds the read, write and execute bits as in `0o7`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct AclEntry {
+    pub tag: AclTag,
+    pub perm: u16,
+}
+
+/// Access control metadata an archiver stores alongside the file contents,
+/// see [`MultiFileReadahead::security_metadata`](struct.MultiFileReadahead.html#method.security_metadata).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SecurityMetadata {
+    /// The extended access ACL, `None` if the file only has its mode bits.
+    pub access_acl: Option<Vec<AclEntry>>,
+    /// The default ACL inherited by new entries of a directory.
+    pub default_acl: Option<Vec<AclEntry>>,
+    /// Attributes of the `security` namespace such as SELinux labels and file capabilities, with their raw values.
+    pub security: Vec<Xattr>,
+}
+
+// the kernel's xattr representation: a version header followed by (tag, perm, id) triples, all little endian
+fn parse_acl(value: &[u8]) -> Result<Vec<AclEntry>, Error> {
+    let invalid = || Error::new(ErrorKind::InvalidData, "malformed POSIX ACL attribute");
+    if value.len() < 4 || !(value.len() - 4).is_multiple_of(8) {
+        return Err(invalid())
+    }
+    let u16_at = |i: usize| u16::from_le_bytes([value[i], value[i + 1]]);
+    let u32_at = |i: usize| u32::from_le_bytes([value[i], value[i + 1], value[i + 2], value[i + 3]]);
+    if u32_at(0) != ACL_VERSION {
+        return Err(invalid())
+    }
+    (4..value.len()).step_by(8).map(|i| {
+        let id = u32_at(i + 4);
+        let tag = match u16_at(i) {
+            0x01 => AclTag::UserObj,
+            0x02 => AclTag::User(id),
+            0x04 => AclTag::GroupObj,
+            0x08 => AclTag::Group(id),
+            0x10 => AclTag::Mask,
+            0x20 => AclTag::Other,
+            _ => return Err(invalid())
+        };
+        Ok(AclEntry {tag, perm: u16_at(i + 2)})
+    }).collect()
+}
+
+fn is_security(name: &[u8]) -> bool {
+    name == ACL_ACCESS || name == ACL_DEFAULT || name.starts_with(SECURITY_PREFIX)
+}
+
+// reuses the attributes if they were read anyway
+pub(crate) fn read_security(f: &File, all: Option<&Result<Vec<Xattr>, Error>>) -> Result<SecurityMetadata, Error> {
+    let attrs = match all {
+        Some(Ok(attrs)) => attrs.iter().filter(|a| is_security(a.name.as_bytes())).cloned().collect(),
+        _ => xattr::read_matching(f, is_security)?
+    };
+    let mut meta = SecurityMetadata::default();
+    for a in attrs {
+        match a.name.as_bytes() {
+            ACL_ACCESS => meta.access_acl = Some(parse_acl(&a.value)?),
+            ACL_DEFAULT => meta.default_acl = Some(parse_acl(&a.value)?),
+            _ => meta.security.push(a)
+        }
+    }
+    Ok(meta)
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Reads POSIX ACLs and `security.*` attributes of files while they are queued, for archivers which
+    /// would otherwise issue those syscalls one file at a time while packing.
+    /// See [`Reader::security_metadata`](struct.Reader.html#method.security_metadata).
+    pub fn security_metadata(&mut self, enabled: bool) {
+        self.security = enabled;
+    }
+}
@@ -0,0 +1,129 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use budget::{memory_share, AUTO_MAX, AUTO_PERCENT, RESIZE_INTERVAL};
+use {sys, MultiFileReadahead, DEFAULT_BUDGET};
+
+// how often the consumption rate is measured
+const RATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The kind of storage the first opened file is on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Device {
+    /// Spinning disks, seeks are expensive so files are prefetched further ahead
+    Rotational,
+    SolidState,
+    /// Not a local block device, e.g. a network filesystem, or the platform can't tell
+    Unknown,
+}
+
+impl Device {
+
+    // how far ahead of the consumer to prefetch and the least to prefetch regardless
+    fn lead(self) -> (Duration, u64) {
+        match self {
+            Device::Rotational => (Duration::from_secs(2), 32 * 1024 * 1024),
+            Device::SolidState => (Duration::from_millis(500), 4 * 1024 * 1024),
+            Device::Unknown => (Duration::from_secs(1), DEFAULT_BUDGET),
+        }
+    }
+}
+
+/// What [`adaptive`](struct.MultiFileReadahead.html#method.adaptive) settled on so far.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AdaptiveSettings {
+    /// `None` until the first file was opened
+    pub device: Option<Device>,
+    /// How much reading time the budget aims to cover
+    pub lead: Duration,
+    /// The budget never shrinks below this
+    pub min_budget: u64,
+    /// A share of the available memory, the budget never grows beyond this
+    pub max_budget: u64,
+    /// The budget currently in use
+    pub budget: u64,
+    /// Bytes per second the consumer read during the last measurement, `None` before the first one
+    pub consume_rate: Option<u64>,
+}
+
+pub(crate) struct Adaptive {
+    settings: AdaptiveSettings,
+    sized_at: Instant,
+    measured_at: Instant,
+    delivered: u64,
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// A queue that picks its budget by itself: large enough to cover a device-dependent lead time
+    /// at the rate the consumer actually reads, bounded by 5% of the available memory, see
+    /// [`Budget::auto`](enum.Budget.html#method.auto). The storage is detected from the first
+    /// opened file. [`adaptive_settings`](#method.adaptive_settings) shows the chosen values.
+    ///
+    /// Setting a [`budget`](#method.budget) afterwards turns the adaptation off.
+    pub fn adaptive(src: Src) -> Self {
+        let mut q = Self::new(src);
+        let (lead, min_budget) = Device::Unknown.lead();
+        let max_budget = memory_share(AUTO_PERCENT, AUTO_MAX).unwrap_or(DEFAULT_BUDGET);
+        let now = Instant::now();
+        q.budget = min_budget;
+        q.adaptive = Some(Adaptive {
+            settings: AdaptiveSettings {device: None, lead, min_budget, max_budget, budget: min_budget, consume_rate: None},
+            sized_at: now,
+            measured_at: now,
+            delivered: 0,
+        });
+        q
+    }
+
+    /// `None` unless the queue was created by [`adaptive`](#method.adaptive).
+    pub fn adaptive_settings(&self) -> Option<AdaptiveSettings> {
+        self.adaptive.as_ref().map(|a| a.settings)
+    }
+
+    pub(crate) fn adapt(&mut self) {
+        let delivered = self.hooks.bytes_delivered;
+        let device = match self.adaptive {
+            Some(ref a) if a.settings.device.is_none() => self.open.iter().find_map(|o| o.as_ref().ok()).map(|f| {
+                f.f.metadata().ok().map_or(Device::Unknown, |m| match sys::is_rotational(m.dev()) {
+                    Some(true) => Device::Rotational,
+                    Some(false) => Device::SolidState,
+                    None => Device::Unknown
+                })
+            }),
+            Some(_) => None,
+            None => return
+        };
+        let a = self.adaptive.as_mut().unwrap();
+        let s = &mut a.settings;
+        if let Some(device) = device {
+            s.device = Some(device);
+            let (lead, min_budget) = device.lead();
+            s.lead = lead;
+            s.min_budget = min_budget;
+        }
+        if a.sized_at.elapsed() >= RESIZE_INTERVAL {
+            a.sized_at = Instant::now();
+            if let Some(bytes) = memory_share(AUTO_PERCENT, AUTO_MAX) {
+                s.max_budget = bytes;
+            }
+        }
+        let elapsed = a.measured_at.elapsed();
+        if elapsed >= RATE_INTERVAL {
+            let rate = ((delivered - a.delivered) as f64 / elapsed.as_secs_f64()) as u64;
+            s.consume_rate = Some(rate);
+            a.measured_at = Instant::now();
+            a.delivered = delivered;
+        }
+        let wanted = s.consume_rate.map_or(0, |r| (r as f64 * s.lead.as_secs_f64()) as u64);
+        s.budget = std::cmp::min(std::cmp::max(wanted, s.min_budget), std::cmp::max(s.max_budget, s.min_budget));
+        self.budget = s.budget;
+    }
+}
@@ -0,0 +1,60 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! One-off `posix_fadvise` calls for files outside of a queue.
+//!
+//! A length of zero extends the range to the end of the file. Where the platform has no
+//! `posix_fadvise` the calls fail with `ErrorKind::Unsupported`.
+
+use std::io::Error;
+use std::os::unix::io::AsFd;
+
+#[cfg(not(target_os = "solaris"))]
+fn fadvise<F: AsFd>(f: &F, offset: u64, len: u64, advice: libc::c_int) -> Result<(), Error> {
+    use std::os::unix::io::AsRawFd;
+
+    // returns the error instead of setting errno
+    match unsafe { libc::posix_fadvise(f.as_fd().as_raw_fd(), offset as libc::off_t, len as libc::off_t, advice) } {
+        0 => Ok(()),
+        e => Err(Error::from_raw_os_error(e))
+    }
+}
+
+#[cfg(target_os = "solaris")]
+fn fadvise<F: AsFd>(_f: &F, _offset: u64, _len: u64, _advice: libc::c_int) -> Result<(), Error> {
+    Err(Error::from(std::io::ErrorKind::Unsupported))
+}
+
+#[cfg(target_os = "solaris")]
+mod consts {
+    pub const POSIX_FADV_SEQUENTIAL: libc::c_int = 2;
+    pub const POSIX_FADV_RANDOM: libc::c_int = 1;
+    pub const POSIX_FADV_WILLNEED: libc::c_int = 3;
+    pub const POSIX_FADV_DONTNEED: libc::c_int = 4;
+}
+#[cfg(not(target_os = "solaris"))]
+use libc as consts;
+
+/// The range will be read soon, start reading it into the cache.
+pub fn willneed<F: AsFd>(f: &F, offset: u64, len: u64) -> Result<(), Error> {
+    fadvise(f, offset, len, consts::POSIX_FADV_WILLNEED)
+}
+
+/// The range won't be needed again, drop it from the cache.
+pub fn dontneed<F: AsFd>(f: &F, offset: u64, len: u64) -> Result<(), Error> {
+    fadvise(f, offset, len, consts::POSIX_FADV_DONTNEED)
+}
+
+/// The range will be read front to back, e.g. to enlarge the kernel's readahead.
+pub fn sequential<F: AsFd>(f: &F, offset: u64, len: u64) -> Result<(), Error> {
+    fadvise(f, offset, len, consts::POSIX_FADV_SEQUENTIAL)
+}
+
+/// The range will be read out of order, kernel readahead would only pull in unwanted data.
+pub fn random<F: AsFd>(f: &F, offset: u64, len: u64) -> Result<(), Error> {
+    fadvise(f, offset, len, consts::POSIX_FADV_RANDOM)
+}
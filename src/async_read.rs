@@ -0,0 +1,222 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use futures_io::AsyncRead;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use MultiFileReadahead;
+
+enum Msg {
+    File(Result<PathBuf, Error>),
+    Data(Vec<u8>),
+    // a read error, ends the current file
+    Failed(Error),
+}
+
+struct State {
+    msgs: VecDeque<Msg>,
+    done: bool,
+    dropped: bool,
+    waker: Option<Waker>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    space: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // blocks while the consumer is `depth` messages behind, false once it's gone
+    fn send(&self, msg: Msg, depth: usize) -> bool {
+        let mut s = self.lock();
+        while s.msgs.len() >= depth && !s.dropped {
+            s = self.space.wait(s).unwrap_or_else(|e| e.into_inner());
+        }
+        if s.dropped {
+            return false
+        }
+        s.msgs.push_back(msg);
+        if let Some(w) = s.waker.take() {
+            w.wake();
+        }
+        true
+    }
+}
+
+// marks the producer as done even if reading panics so that the consumer doesn't wait forever
+struct DoneGuard(Arc<Shared>);
+
+impl Drop for DoneGuard {
+    fn drop(&mut self) {
+        let mut s = self.0.lock();
+        s.done = true;
+        if let Some(w) = s.waker.take() {
+            w.wake();
+        }
+    }
+}
+
+/// Async access to the queue, obtained from [`into_async`](struct.MultiFileReadahead.html#method.into_async).
+///
+/// Files are announced by [`next_file`](#method.next_file), afterwards `AsyncRead` yields the
+/// contents of that file and signals its end with a zero-length read.
+pub struct AsyncReadahead {
+    shared: Arc<Shared>,
+    chunk: Vec<u8>,
+    pos: usize,
+    // whether a file was announced whose end hasn't been reached
+    reading: bool,
+}
+
+/// Future returned by [`AsyncReadahead::next_file`](struct.AsyncReadahead.html#method.next_file).
+pub struct NextFile<'a> {
+    inner: &'a mut AsyncReadahead,
+}
+
+impl<'a> Future for NextFile<'a> {
+    type Output = Option<Result<PathBuf, Error>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.get_mut().inner.poll_next_file(cx)
+    }
+}
+
+impl AsyncReadahead {
+
+    /// Skips the rest of the current file and waits for the next one, `None` once the queue is exhausted.
+    pub fn next_file(&mut self) -> NextFile<'_> {
+        NextFile {inner: self}
+    }
+
+    pub fn poll_next_file(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<PathBuf, Error>>> {
+        self.chunk.clear();
+        self.pos = 0;
+        self.reading = false;
+        let mut s = self.shared.lock();
+        loop {
+            let msg = match s.msgs.pop_front() {
+                Some(m) => m,
+                None if s.done => return Poll::Ready(None),
+                None => {
+                    s.waker = Some(cx.waker().clone());
+                    return Poll::Pending
+                }
+            };
+            self.shared.space.notify_one();
+            if let Msg::File(f) = msg {
+                self.reading = f.is_ok();
+                return Poll::Ready(Some(f))
+            }
+        }
+    }
+}
+
+impl AsyncRead for AsyncReadahead {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<Result<usize, Error>> {
+        let this = self.get_mut();
+        if this.pos == this.chunk.len() && this.reading {
+            let mut s = this.shared.lock();
+            match s.msgs.front() {
+                Some(&Msg::Data(_)) | Some(&Msg::Failed(_)) => {}
+                Some(&Msg::File(_)) => this.reading = false,
+                None if s.done => this.reading = false,
+                None => {
+                    s.waker = Some(cx.waker().clone());
+                    return Poll::Pending
+                }
+            }
+            if this.reading {
+                this.shared.space.notify_one();
+                match s.msgs.pop_front() {
+                    Some(Msg::Data(d)) => {
+                        this.chunk = d;
+                        this.pos = 0;
+                    }
+                    Some(Msg::Failed(e)) => {
+                        this.reading = false;
+                        return Poll::Ready(Err(e))
+                    }
+                    _ => unreachable!()
+                }
+            }
+        }
+        let n = std::cmp::min(buf.len(), this.chunk.len() - this.pos);
+        buf[..n].copy_from_slice(&this.chunk[this.pos..this.pos + n]);
+        this.pos += n;
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl Drop for AsyncReadahead {
+    fn drop(&mut self) {
+        self.shared.lock().dropped = true;
+        self.shared.space.notify_all();
+    }
+}
+
+impl<Src: Iterator<Item=PathBuf> + Send + 'static> MultiFileReadahead<Src> {
+
+    /// Turns the queue into an [`AsyncReadahead`](struct.AsyncReadahead.html) for any async runtime.
+    ///
+    /// The blocking reads are done by a task handed to `spawn` once, which should run it where blocking
+    /// is acceptable, e.g. with `blocking::unblock` or `async_std::task::spawn_blocking`. It reads
+    /// in chunks of `chunk_size` bytes and stays at most `depth` chunks ahead of the consumer.
+    /// The task ends once the queue is exhausted or the `AsyncReadahead` is dropped.
+    pub fn into_async<S>(mut self, chunk_size: usize, depth: usize, spawn: S) -> AsyncReadahead
+        where S: FnOnce(Box<dyn FnOnce() + Send>)
+    {
+        assert!(chunk_size > 0, "chunks must not be empty");
+        let depth = std::cmp::max(depth, 1);
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {msgs: VecDeque::new(), done: false, dropped: false, waker: None}),
+            space: Condvar::new(),
+        });
+        let producer = shared.clone();
+        spawn(Box::new(move || {
+            let _guard = DoneGuard(producer.clone());
+            while let Some(entry) = self.next_entry() {
+                let entry = entry.map(|()| self.open[0].as_ref().expect("expect that next_entry only leaves successfully opened files at the front").info.path.clone());
+                let opened = entry.is_ok();
+                if !producer.send(Msg::File(entry), depth) {
+                    return
+                }
+                if !opened {
+                    continue
+                }
+                loop {
+                    let mut data = vec![0; chunk_size];
+                    let msg = match self.read_entry(0, &mut data) {
+                        Ok(0) => break,
+                        Ok(n) => {
+                            data.truncate(n);
+                            Msg::Data(data)
+                        }
+                        Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                        Err(e) => Msg::Failed(e)
+                    };
+                    let failed = matches!(msg, Msg::Failed(_));
+                    if !producer.send(msg, depth) {
+                        return
+                    }
+                    if failed {
+                        break
+                    }
+                }
+            }
+        }));
+        AsyncReadahead {shared, chunk: Vec::new(), pos: 0, reading: false}
+    }
+}
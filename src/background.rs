@@ -0,0 +1,185 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::fs::File;
+use std::io::{Error, ErrorKind};
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use {MultiFileReadahead, Prefetch};
+
+struct Request {
+    id: u64,
+    // only sent when the worker moves on to another file
+    file: Option<File>,
+    offset: u64,
+    buf: Vec<u8>,
+}
+
+struct Reply {
+    id: u64,
+    offset: u64,
+    buf: Vec<u8>,
+    result: Result<usize, Error>,
+}
+
+pub(crate) struct Background {
+    chunk: usize,
+    worker: Option<(Sender<Request>, Receiver<Reply>)>,
+    // the file the worker holds a descriptor for
+    worker_file: Option<u64>,
+    in_flight: bool,
+    // the chunk handed out to the consumer, read from `offset` of file `id`
+    buf: Vec<u8>,
+    filled: usize,
+    id: Option<u64>,
+    offset: u64,
+    // recycled buffer for the next request
+    spare: Vec<u8>,
+}
+
+fn read_full_at(f: &File, buf: &mut [u8], offset: u64) -> Result<usize, Error> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match f.read_at(&mut buf[filled..], offset + filled as u64) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(filled)
+}
+
+fn spawn_worker() -> (Sender<Request>, Receiver<Reply>) {
+    let (requests, rx) = channel::<Request>();
+    let (tx, replies) = channel();
+    thread::spawn(move || {
+        let mut current = None;
+        for req in rx {
+            if req.file.is_some() {
+                current = req.file;
+            }
+            let mut buf = req.buf;
+            let result = match current {
+                Some(ref f) => read_full_at(f, &mut buf, req.offset),
+                None => Err(Error::other("no file to read from"))
+            };
+            if tx.send(Reply {id: req.id, offset: req.offset, buf, result}).is_err() {
+                return
+            }
+        }
+    });
+    (requests, replies)
+}
+
+impl Background {
+
+    pub(crate) fn new(chunk: usize) -> Self {
+        Background {
+            chunk: std::cmp::max(chunk, 4096),
+            worker: None,
+            worker_file: None,
+            in_flight: false,
+            buf: Vec::new(),
+            filled: 0,
+            id: None,
+            offset: 0,
+            spare: Vec::new(),
+        }
+    }
+
+    pub(crate) fn footprint(&self) -> u64 {
+        let in_flight = if self.in_flight { self.chunk } else { 0 };
+        (self.buf.capacity() + self.spare.capacity() + in_flight) as u64
+    }
+
+    fn request(&mut self, fetch: &Prefetch, offset: u64) -> Result<(), Error> {
+        let file = if self.worker_file == Some(fetch.id) { None } else { Some(fetch.f.try_clone()?) };
+        let mut buf = std::mem::take(&mut self.spare);
+        buf.resize(self.chunk, 0);
+        let req = Request {id: fetch.id, file, offset, buf};
+        let sent = self.worker.get_or_insert_with(spawn_worker).0.send(req).is_ok();
+        if !sent {
+            // the worker died, start over with a fresh one next time
+            self.worker = None;
+            self.worker_file = None;
+            return Err(Error::other("background reader exited"))
+        }
+        self.worker_file = Some(fetch.id);
+        self.in_flight = true;
+        Ok(())
+    }
+
+    fn wait(&mut self) -> Result<Reply, Error> {
+        self.in_flight = false;
+        let reply = self.worker.as_ref().and_then(|w| w.1.recv().ok());
+        reply.ok_or_else(|| {
+            self.worker = None;
+            self.worker_file = None;
+            Error::other("background reader exited")
+        })
+    }
+
+    // serves a read of the file at its read position from the chunks read ahead by the worker
+    pub(crate) fn read(&mut self, fetch: &Prefetch, buf: &mut [u8]) -> Result<usize, Error> {
+        let pos = fetch.read_pos;
+        let covered = self.id == Some(fetch.id) && self.offset <= pos && pos < self.offset + self.filled as u64;
+        if !covered {
+            let mut reply = None;
+            if self.in_flight {
+                let r = self.wait()?;
+                if r.id == fetch.id && r.offset == pos {
+                    reply = Some(r);
+                } else {
+                    // the consumer moved elsewhere
+                    self.spare = r.buf;
+                }
+            }
+            let reply = match reply {
+                Some(r) => r,
+                None => {
+                    self.request(fetch, pos)?;
+                    self.wait()?
+                }
+            };
+            let n = match reply.result {
+                Ok(n) => n,
+                Err(e) => {
+                    self.spare = reply.buf;
+                    return Err(e)
+                }
+            };
+            self.spare = std::mem::replace(&mut self.buf, reply.buf);
+            self.filled = n;
+            self.id = Some(fetch.id);
+            self.offset = pos;
+            if n == 0 {
+                return Ok(0)
+            }
+            // read the following chunk while this one is being consumed, if that fails reads just wait for it
+            let _ = self.request(fetch, pos + n as u64);
+        }
+        let start = (pos - self.offset) as usize;
+        let n = std::cmp::min(buf.len(), self.filled - start);
+        buf[..n].copy_from_slice(&self.buf[start..start + n]);
+        Ok(n)
+    }
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Reads files on a helper thread in chunks of `chunk` bytes, one chunk ahead of the consumer,
+    /// so that cache misses overlap with processing the previous chunk. `None` reads on the calling thread.
+    ///
+    /// Meant for CPU-heavy consumers. Applies to regular files read through the queue, not to streams
+    /// or in-kernel copies, and both chunks count against the prefetch budget.
+    pub fn background_reads(&mut self, chunk: Option<usize>) {
+        self.background = chunk.map(Background::new);
+    }
+}
@@ -0,0 +1,96 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::io::Read;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use {advise, sys, MultiFileReadahead, Totals};
+
+/// Results of a [`benchmark`](struct.MultiFileReadahead.html#method.benchmark) pass.
+#[derive(Clone, Copy, Debug)]
+pub struct Benchmark {
+    /// Bytes of the listed files dropped from the cache before the pass
+    pub evicted: u64,
+    /// Files that failed to open or read
+    pub failed: u64,
+    /// Time spent consuming, excluding the eviction
+    pub elapsed: Duration,
+    pub totals: Totals,
+}
+
+impl Benchmark {
+    /// Bytes delivered per second.
+    pub fn throughput(&self) -> f64 {
+        self.totals.bytes_delivered as f64 / self.elapsed.as_secs_f64().max(1e-9)
+    }
+
+    /// Fraction of reads that found their data prefetched, i.e. didn't count as
+    /// [`starved_reads`](struct.Totals.html#structfield.starved_reads).
+    pub fn hit_rate(&self) -> f64 {
+        let hits = self.totals.prefetched_latency.count();
+        let total = hits + self.totals.starved_latency.count();
+        if total == 0 {
+            return 1.0
+        }
+        hits as f64 / total as f64
+    }
+}
+
+impl MultiFileReadahead<std::vec::IntoIter<PathBuf>> {
+
+    /// Drops `paths` from the page cache, then reads them all through a queue set up by `configure`
+    /// in chunks of `read_size` bytes and reports how it went.
+    ///
+    /// Each pass starts from a cold cache without root or `drop_caches`, so the results of different
+    /// settings are comparable. Eviction syncs dirty pages first but can't drop pages that are mapped
+    /// or locked by other processes. Members of directories the queue expands are not evicted.
+    pub fn benchmark<F>(paths: Vec<PathBuf>, read_size: usize, configure: F) -> Benchmark where F: FnOnce(&mut Self) {
+        let mut evicted = 0;
+        for p in &paths {
+            let f = match sys::open_nonblocking(p) {
+                Ok(f) => f,
+                Err(_) => continue
+            };
+            match f.metadata() {
+                Ok(ref m) if m.is_file() => {
+                    let _ = f.sync_data();
+                    if advise::dontneed(&f, 0, 0).is_ok() {
+                        evicted += m.len();
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let mut q = Self::new(paths.into_iter());
+        configure(&mut q);
+        let mut buf = vec![0u8; std::cmp::max(read_size, 1)];
+        let mut failed = 0;
+        let start = Instant::now();
+        while let Some(r) = q.next() {
+            let mut r = match r {
+                Ok(r) => r,
+                Err(_) => {
+                    failed += 1;
+                    continue
+                }
+            };
+            loop {
+                match r.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(_) => {}
+                    Err(_) => {
+                        failed += 1;
+                        break
+                    }
+                }
+            }
+        }
+        let elapsed = start.elapsed();
+        Benchmark {evicted, failed, elapsed, totals: q.totals()}
+    }
+}
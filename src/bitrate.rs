@@ -0,0 +1,60 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use {MultiFileReadahead, PREFETCH_BLOCK};
+
+/// Callback assigning a file its consumption rate in bytes per second given its path and length,
+/// `None` leaves it to the byte budget.
+pub type BitrateFn = Box<dyn Fn(&Path, u64) -> Option<u64> + Send>;
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Keeps `lookahead` worth of data prefetched for every file that `f` assigns a rate, e.g. the
+    /// bitrate of a media file, instead of splitting the byte budget between them.
+    ///
+    /// Such files neither count against nor draw from the budget or the rate limit. Their windows
+    /// are maintained for the files being read and the next group, and topped up once they have
+    /// fallen to half their size. Files with a prefetch plan ignore their rate.
+    pub fn bitrate(&mut self, lookahead: Duration, f: Option<BitrateFn>) {
+        self.bitrate = f.map(|f| (lookahead, f));
+    }
+
+    pub(crate) fn assign_bitrate(&self, p: &Path, len: u64) -> Option<u64> {
+        let (lookahead, ref f) = *self.bitrate.as_ref()?;
+        f(p, len).map(|rate| (rate as f64 * lookahead.as_secs_f64()) as u64)
+    }
+
+    // tops up the windows of files with a rate
+    pub(crate) fn advise_bitrate(&mut self) {
+        let ttl = self.ttl.is_some();
+        let delivered = self.delivered;
+        for (i, o) in self.open.iter_mut().take(delivered + self.group).enumerate() {
+            let p = match *o {
+                Ok(ref mut p) => p,
+                Err(_) => continue
+            };
+            let window = match p.window {
+                Some(w) if p.plan.is_none() && p.info.link_of.is_none() && !p.warm && !p.unadvisable => w,
+                _ => continue
+            };
+            if p.stale {
+                if i > delivered { continue; }
+                p.stale = false;
+            }
+            let old_pos = std::cmp::max(p.read_pos, p.prefetch_pos);
+            if old_pos >= p.length || old_pos - p.read_pos > window / 2 { continue; }
+            let mut new_pos = p.read_pos + std::cmp::max(window, PREFETCH_BLOCK);
+            new_pos = (new_pos + PREFETCH_BLOCK - 1) & !(PREFETCH_BLOCK - 1);
+            new_pos = std::cmp::min(p.length, new_pos);
+            if new_pos > old_pos {
+                p.advise(old_pos, new_pos, ttl, &mut self.hooks);
+            }
+        }
+    }
+}
@@ -0,0 +1,167 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use {sys, MultiFileReadahead};
+
+// how often an automatic budget is sized anew
+pub(crate) const RESIZE_INTERVAL: Duration = Duration::from_secs(5);
+pub(crate) const AUTO_PERCENT: u8 = 5;
+pub(crate) const AUTO_MAX: u64 = 1 << 30;
+// an automatic budget never drops below this
+const MIN_AUTO_BUDGET: u64 = 1024 * 1024;
+
+/// How many bytes a queue prefetches ahead of the consumer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Budget {
+    Fixed(u64),
+    /// A percentage of the memory available to the process, at most `max` bytes. Availability is
+    /// the lower of the system's `MemAvailable` and what the cgroup's `memory.max` leaves on Linux,
+    /// free memory elsewhere, and is checked again every few seconds.
+    Available {percent: u8, max: u64},
+}
+
+impl Budget {
+
+    /// 5% of the available memory and at most 1 GiB, deep lookahead on big machines without
+    /// evicting the working set of small VMs.
+    pub fn auto() -> Self {
+        Budget::Available {percent: AUTO_PERCENT, max: AUTO_MAX}
+    }
+}
+
+// percent of the available memory, bounded by max
+pub(crate) fn memory_share(percent: u8, max: u64) -> Option<u64> {
+    let share = sys::available_memory()? / 100 * percent as u64;
+    // a max below the floor still wins
+    Some(std::cmp::min(max, std::cmp::max(MIN_AUTO_BUDGET, share)))
+}
+
+pub(crate) struct AutoBudget {
+    percent: u8,
+    max: u64,
+    sized_at: Option<Instant>,
+}
+
+struct Pool {
+    limit: u64,
+    // sum of what the participating queues last reported
+    outstanding: AtomicU64,
+}
+
+/// A prefetch budget shared by several queues, e.g. one per core of a thread-per-core runtime.
+#[derive(Clone)]
+pub struct SharedBudget {
+    pool: Arc<Pool>,
+}
+
+impl SharedBudget {
+
+    pub fn new(bytes: u64) -> Self {
+        SharedBudget {pool: Arc::new(Pool {limit: bytes, outstanding: AtomicU64::new(0)})}
+    }
+
+    /// Bytes currently prefetched or buffered by all queues using the budget.
+    pub fn outstanding(&self) -> u64 {
+        self.pool.outstanding.load(Ordering::Relaxed)
+    }
+}
+
+pub(crate) struct BudgetShare {
+    pool: Arc<Pool>,
+    reported: u64,
+}
+
+impl BudgetShare {
+
+    // the budget of this queue, whatever the others don't use
+    pub(crate) fn limit(&self) -> u64 {
+        let others = self.pool.outstanding.load(Ordering::Relaxed).saturating_sub(self.reported);
+        self.pool.limit.saturating_sub(others)
+    }
+
+    pub(crate) fn report(&mut self, outstanding: u64) {
+        if outstanding > self.reported {
+            self.pool.outstanding.fetch_add(outstanding - self.reported, Ordering::Relaxed);
+        } else {
+            self.pool.outstanding.fetch_sub(self.reported - outstanding, Ordering::Relaxed);
+        }
+        self.reported = outstanding;
+    }
+}
+
+impl Drop for BudgetShare {
+    fn drop(&mut self) {
+        self.report(0);
+    }
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// The queue's own budget, 8 MiB by default. Not used while a
+    /// [`shared_budget`](#method.shared_budget) is set.
+    pub fn budget(&mut self, budget: Budget) {
+        self.adaptive = None;
+        match budget {
+            Budget::Fixed(bytes) => {
+                self.budget = bytes;
+                self.auto_budget = None;
+            }
+            Budget::Available {percent, max} => {
+                self.auto_budget = Some(AutoBudget {percent: std::cmp::min(percent, 100), max, sized_at: None});
+                self.resize_budget();
+            }
+        }
+    }
+
+    pub(crate) fn resize_budget(&mut self) {
+        let auto = match self.auto_budget {
+            Some(ref mut a) => a,
+            None => return
+        };
+        if auto.sized_at.is_some_and(|t| t.elapsed() < RESIZE_INTERVAL) {
+            return
+        }
+        auto.sized_at = Some(Instant::now());
+        // keep the last size if memory can't be queried
+        if let Some(bytes) = memory_share(auto.percent, auto.max) {
+            self.budget = bytes;
+        }
+    }
+
+    /// Draws prefetch from a budget shared with other queues instead of a budget of its own.
+    /// `None` goes back to the queue's own budget.
+    ///
+    /// Each queue stays within whatever the others leave over, so a busy queue can use the whole
+    /// budget while the others are idle.
+    pub fn shared_budget(&mut self, budget: Option<SharedBudget>) {
+        self.shared_budget = budget.map(|b| BudgetShare {pool: b.pool, reported: 0});
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn available_budget_is_bounded() {
+        if sys::available_memory().is_none() {
+            return
+        }
+        let mut q = MultiFileReadahead::new(Vec::<PathBuf>::new().into_iter());
+        q.budget(Budget::Available {percent: 0, max: 1 << 30});
+        assert_eq!(q.budget, MIN_AUTO_BUDGET);
+        // a max below the floor still wins
+        q.budget(Budget::Available {percent: 100, max: 4096});
+        assert_eq!(q.budget, 4096);
+        q.budget(Budget::Fixed(100));
+        assert_eq!(q.budget, 100);
+    }
+}
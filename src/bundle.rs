@@ -0,0 +1,125 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::VecDeque;
+use std::io::Error;
+use std::path::{Path, PathBuf};
+use {MultiFileReadahead, Reader};
+
+/// Callback expanding a source entry into the files that make up its bundle, in delivery order.
+pub type ExpandFn = Box<dyn FnMut(&Path) -> Vec<PathBuf> + Send>;
+
+pub(crate) struct Bundles {
+    expand: ExpandFn,
+    // number of queue entries preceding each bundle not yet delivered
+    starts: VecDeque<u64>,
+    // queue entries delivered so far
+    consumed: u64,
+}
+
+/// The files a source entry expanded to, obtained from [`next_bundle`](struct.MultiFileReadahead.html#method.next_bundle).
+pub struct Bundle<'a, T: 'a> {
+    owner: &'a mut MultiFileReadahead<T>,
+    len: usize,
+}
+
+impl<'a, T> Bundle<'a, T> where T: Iterator<Item=PathBuf> {
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The `idx`th file of the bundle, or the error it failed to open with.
+    pub fn file(&mut self, idx: usize) -> Result<Reader<'_, T>, &Error> {
+        assert!(idx < self.len, "bundle index out of bounds");
+        if self.owner.open[idx].is_err() {
+            return Err(&self.owner.open[idx].as_ref().err().unwrap().1)
+        }
+        Ok(Reader::new(self.owner, idx).expect("expect that the entry was checked to be open"))
+    }
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Expands each source entry into several files, e.g. a photo and its sidecar, which are then
+    /// delivered together by [`next_bundle`](#method.next_bundle) and share the budget while they are read.
+    ///
+    /// Expanded paths go through the same handling as source entries, so directories among them
+    /// are expanded into the bundle as well and skipped files are missing from it.
+    /// Files of later repetitions are delivered one per bundle. Must be set before reading starts.
+    pub fn expand_entries(&mut self, f: Option<ExpandFn>) {
+        if f.is_some() {
+            self.reject_paired("expanding entries");
+        }
+        self.bundles = f.map(|expand| Bundles {expand, starts: VecDeque::new(), consumed: 0});
+    }
+
+    // paths a source entry expands to, marking where its bundle begins. Hands the entry back if there's no callback
+    pub(crate) fn expand_entry(&mut self, p: PathBuf) -> Result<Vec<PathBuf>, PathBuf> {
+        let pushed = self.queued();
+        match self.bundles {
+            Some(ref mut b) => {
+                b.starts.push_back(pushed);
+                Ok((b.expand)(&p))
+            }
+            None => Err(p)
+        }
+    }
+
+    pub(crate) fn start_bundle(&mut self) {
+        let pushed = self.queued();
+        if let Some(ref mut b) = self.bundles {
+            b.starts.push_back(pushed);
+        }
+    }
+
+    fn queued(&self) -> u64 {
+        self.bundles.as_ref().map_or(0, |b| b.consumed) + self.open.len() as u64
+    }
+
+    /// Discards the current bundle and delivers the next one. Without an
+    /// [`expand_entries`](#method.expand_entries) callback every file is a bundle of its own.
+    pub fn next_bundle(&mut self) -> Option<Bundle<'_, Src>> {
+        // the discarded entries still count as queued until they are popped
+        if let Some(ref mut b) = self.bundles {
+            b.consumed += self.delivered as u64;
+        }
+        self.next_group(0);
+        let n = match self.bundles {
+            Some(_) => self.bundle_len(),
+            None => 1
+        };
+        if n == 0 || !self.next_group(n) {
+            return None
+        }
+        self.group = n;
+        Some(Bundle {owner: self, len: n})
+    }
+
+    // length of the bundle at the front of the queue, 0 at the end
+    fn bundle_len(&mut self) -> usize {
+        loop {
+            let (consumed, end) = {
+                let b = self.bundles.as_mut().unwrap();
+                // skips entries that expanded to nothing
+                while b.starts.len() >= 2 && b.starts[1] <= b.consumed {
+                    b.starts.pop_front();
+                }
+                (b.consumed, b.starts.get(1).cloned())
+            };
+            match end {
+                Some(end) => return (end - consumed) as usize,
+                None if self.add_file() => continue,
+                None => return self.open.len()
+            }
+        }
+    }
+}
@@ -0,0 +1,55 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::path::{Path, PathBuf};
+use MultiFileReadahead;
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Removes a file that hasn't been delivered yet, e.g. because its work item got invalidated.
+    ///
+    /// An opened file is closed and whatever was prefetched of it is dropped from the cache, which
+    /// frees that part of the budget for the other files. Paths that were only pulled from the source,
+    /// such as directory members, the [lifo](#method.lifo) stack or the entries of the [stat horizon](#method.stat_horizon), are
+    /// forgotten. Returns false if the path is not queued, paths still in the source can't be cancelled.
+    /// Files of [paired](#method.paired) lists can't be cancelled either.
+    pub fn cancel(&mut self, p: &Path) -> bool {
+        if self.paired {
+            return false
+        }
+        let i = match self.find_queued(p) {
+            Some(i) => i,
+            None => {
+                let removed = if let Some(i) = self.pending.iter().position(|(q, _)| q == p) {
+                    self.pending.remove(i).is_some()
+                } else if let Some(i) = self.stack.iter().position(|q| q == p) {
+                    self.stack.remove(i);
+                    true
+                } else {
+                    self.forget_ahead(p)
+                };
+                if removed {
+                    // otherwise a later duplicate of the path would pick it up
+                    if let Some(ref s) = self.user_data {
+                        s.pop(p);
+                    }
+                }
+                return removed
+            }
+        };
+        if let Some(Ok(mut fetch)) = self.open.remove(i) {
+            fetch.expire(&mut self.hooks);
+            self.hooks.closed(fetch.id);
+        }
+        let buffered = self.buffer_bytes();
+        if let Some(ref mut s) = self.shared_budget {
+            s.report(buffered);
+        }
+        self.advance();
+        true
+    }
+}
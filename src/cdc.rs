@@ -0,0 +1,206 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use MultiFileReadahead;
+
+// splitmix64, only needs to be fixed and well-mixed
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state = 0u64;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+static GEAR: [u64; 256] = gear_table();
+
+/// Chunk size bounds for content-defined chunking.
+#[derive(Clone, Copy, Debug)]
+pub struct ChunkerConfig {
+    pub min: usize,
+    pub avg: usize,
+    pub max: usize,
+}
+
+impl Default for ChunkerConfig {
+    fn default() -> Self {
+        ChunkerConfig {min: 16 * 1024, avg: 64 * 1024, max: 256 * 1024}
+    }
+}
+
+impl ChunkerConfig {
+
+    // FastCDC normalized chunking: a stricter mask below the average size, a looser one above
+    fn cut(&self, data: &[u8]) -> usize {
+        let len = data.len();
+        if len <= self.min {
+            return len
+        }
+        let bits = 63 - (self.avg as u64).leading_zeros();
+        let mask_strict = !0u64 << (63 - bits);
+        let mask_loose = (!0u64).checked_shl(65 - bits).unwrap_or(0);
+        let normal = std::cmp::min(self.avg, len);
+
+        let mut fp = 0u64;
+        for (i, &b) in data.iter().enumerate().skip(self.min) {
+            fp = (fp << 1).wrapping_add(GEAR[b as usize]);
+            let mask = if i < normal { mask_strict } else { mask_loose };
+            if fp & mask == 0 {
+                return i + 1
+            }
+        }
+        len
+    }
+}
+
+/// A content-defined chunk of a file.
+pub struct Chunk<'a> {
+    pub path: &'a Path,
+    pub offset: u64,
+    pub data: &'a [u8],
+}
+
+/// Splits each file of the queue into content-defined chunks. Chunks never span files.
+pub struct Chunks<Src> {
+    inner: MultiFileReadahead<Src>,
+    config: ChunkerConfig,
+    buf: Vec<u8>,
+    // start of the unconsumed part of buf
+    start: usize,
+    // file offset of buf[start]
+    offset: u64,
+    active: bool,
+    eof: bool,
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    pub fn chunks(self, config: ChunkerConfig) -> Chunks<Src> {
+        assert!(0 < config.min && config.min <= config.avg && config.avg <= config.max, "chunk sizes must satisfy 0 < min <= avg <= max");
+        Chunks {inner: self, config, buf: Vec::with_capacity(config.max * 2), start: 0, offset: 0, active: false, eof: false}
+    }
+}
+
+impl<Src: Iterator<Item=PathBuf>> Chunks<Src> {
+
+    pub fn next_chunk(&mut self) -> Option<Result<Chunk<'_>, Error>> {
+        let max = self.config.max;
+        loop {
+            if !self.active {
+                if let Err(e) = self.inner.next_entry()? {
+                    return Some(Err(e))
+                }
+                self.active = true;
+                self.eof = false;
+                self.buf.clear();
+                self.start = 0;
+                self.offset = 0;
+            }
+
+            if self.buf.len() - self.start < max {
+                self.buf.drain(..self.start);
+                self.start = 0;
+            }
+
+            while !self.eof && self.buf.len() - self.start < max {
+                let filled = self.buf.len();
+                self.buf.resize(self.start + max, 0);
+                self.inner.buffered = self.buf.capacity() as u64;
+                let result = self.inner.read_entry(0, &mut self.buf[filled..]);
+                self.buf.truncate(filled + *result.as_ref().unwrap_or(&0));
+                match result {
+                    Ok(0) => self.eof = true,
+                    Ok(_) => {},
+                    Err(ref e) if e.kind() == ErrorKind::Interrupted => {},
+                    Err(e) => {
+                        self.active = false;
+                        return Some(Err(e))
+                    }
+                }
+            }
+
+            if self.buf.len() == self.start {
+                self.active = false;
+                continue
+            }
+
+            let start = self.start;
+            let len = self.config.cut(&self.buf[start..]);
+            let offset = self.offset;
+            self.start += len;
+            self.offset += len as u64;
+            let path = &self.inner.open[0].as_ref().expect("expect that next_entry only leaves successfully opened files at the front").info.path;
+            return Some(Ok(Chunk {path, offset, data: &self.buf[start..start + len]}))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::Files;
+
+    const CONFIG: ChunkerConfig = ChunkerConfig {min: 64, avg: 256, max: 1024};
+
+    // xorshift, incompressible enough to hit the masks
+    fn noise(len: usize, mut state: u64) -> Vec<u8> {
+        (0..len).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        }).collect()
+    }
+
+    #[test]
+    fn cut_bounds() {
+        let data = noise(100_000, 1);
+        assert_eq!(CONFIG.cut(&data[..CONFIG.min]), CONFIG.min);
+        assert_eq!(CONFIG.cut(&data[..10]), 10);
+        let mut rest = &data[..];
+        while rest.len() > CONFIG.max {
+            let n = CONFIG.cut(&rest[..CONFIG.max]);
+            assert!(CONFIG.min < n && n <= CONFIG.max);
+            rest = &rest[n..];
+        }
+        // a run of zeroes doesn't hit the masks before the maximum
+        assert_eq!(CONFIG.cut(&[0u8; 1024]), 1024);
+    }
+
+    #[test]
+    fn chunks_cover_files_without_spanning_them() {
+        let (a, c, d) = (noise(20_000, 1), noise(3_000, 2), noise(CONFIG.min, 3));
+        let files = Files::new(&[("/a", &a), ("/b", b""), ("/c", &c), ("/d", &d)]);
+        let mut chunks = files.queue(&["/a", "/b", "/c", "/d"]).chunks(CONFIG);
+
+        let mut seen: Vec<(PathBuf, Vec<u8>)> = Vec::new();
+        while let Some(chunk) = chunks.next_chunk() {
+            let chunk = chunk.unwrap();
+            assert!(chunk.data.len() <= CONFIG.max);
+            match seen.last_mut() {
+                Some(&mut (ref p, ref mut data)) if p == chunk.path => {
+                    assert_eq!(chunk.offset, data.len() as u64);
+                    data.extend_from_slice(chunk.data);
+                }
+                _ => {
+                    assert_eq!(chunk.offset, 0);
+                    seen.push((chunk.path.to_owned(), chunk.data.to_vec()));
+                }
+            }
+        }
+        assert_eq!(seen, vec![(files.path("/a"), a), (files.path("/c"), c), (files.path("/d"), d)]);
+    }
+}
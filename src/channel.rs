@@ -0,0 +1,81 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+#[cfg(feature = "bytes")]
+use bytes::Bytes;
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::thread;
+use MultiFileReadahead;
+
+/// An owned chunk of a file, sent by [`into_channel`](struct.MultiFileReadahead.html#method.into_channel).
+#[derive(Clone, Debug)]
+pub struct FileChunk<D = Vec<u8>> {
+    pub path: PathBuf,
+    pub offset: u64,
+    pub data: D,
+}
+
+impl<Src: Iterator<Item=PathBuf> + Send + 'static> MultiFileReadahead<Src> {
+
+    /// Reads the queue on a background thread and sends the files in chunks of up to `chunk_size` bytes
+    /// over a channel holding at most `depth` chunks.
+    ///
+    /// Files are sent in order, each as a run of chunks with increasing offsets. Empty files send nothing.
+    /// Files that fail to open or read send an error and the thread continues with the next file.
+    /// The thread exits once the queue is exhausted or the receiver is dropped.
+    pub fn into_channel(self, chunk_size: usize, depth: usize) -> Receiver<Result<FileChunk, Error>> {
+        self.spawn_chunks(chunk_size, depth)
+    }
+
+    /// Like [`into_channel`](#method.into_channel), but the chunks are `Bytes` that can be cloned
+    /// cheaply to fan them out to several consumers.
+    #[cfg(feature = "bytes")]
+    pub fn into_bytes_channel(self, chunk_size: usize, depth: usize) -> Receiver<Result<FileChunk<Bytes>, Error>> {
+        self.spawn_chunks(chunk_size, depth)
+    }
+
+    fn spawn_chunks<D: From<Vec<u8>> + Send + 'static>(mut self, chunk_size: usize, depth: usize) -> Receiver<Result<FileChunk<D>, Error>> {
+        assert!(chunk_size > 0, "chunks must not be empty");
+        let (tx, rx) = sync_channel(depth);
+        thread::spawn(move || {
+            while let Some(entry) = self.next_entry() {
+                if let Err(e) = entry {
+                    if tx.send(Err(e)).is_err() {
+                        return
+                    }
+                    continue
+                }
+                let path = self.open[0].as_ref().expect("expect that next_entry only leaves successfully opened files at the front").info.path.clone();
+                let mut offset = 0;
+                loop {
+                    let mut data = vec![0; chunk_size];
+                    let msg = match self.read_entry(0, &mut data) {
+                        Ok(0) => break,
+                        Ok(n) => {
+                            data.truncate(n);
+                            let chunk = FileChunk {path: path.clone(), offset, data: D::from(data)};
+                            offset += n as u64;
+                            Ok(chunk)
+                        }
+                        Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                        Err(e) => Err(e)
+                    };
+                    let failed = msg.is_err();
+                    if tx.send(msg).is_err() {
+                        return
+                    }
+                    if failed {
+                        break
+                    }
+                }
+            }
+        });
+        rx
+    }
+}
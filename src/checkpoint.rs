@@ -0,0 +1,97 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::io::{BufReader, Error, ErrorKind, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::vec;
+use trace::{get_path, get_varint, put_varint};
+use MultiFileReadahead;
+
+const MAGIC: &[u8; 8] = b"RFCKPT01";
+
+/// The work remaining in a queue, see [`checkpoint`](struct.MultiFileReadahead.html#method.checkpoint).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// Paths in queue order with the offset to resume reading at, zero for files not started yet
+    pub entries: Vec<(PathBuf, u64)>,
+}
+
+impl Checkpoint {
+
+    pub fn write_to<W: Write>(&self, mut w: W) -> Result<(), Error> {
+        let mut buf = Vec::with_capacity(64 * self.entries.len() + 16);
+        buf.extend_from_slice(MAGIC);
+        put_varint(&mut buf, self.entries.len() as u64);
+        for &(ref p, offset) in &self.entries {
+            let bytes = p.as_os_str().as_bytes();
+            put_varint(&mut buf, bytes.len() as u64);
+            buf.extend_from_slice(bytes);
+            put_varint(&mut buf, offset);
+        }
+        w.write_all(&buf)?;
+        w.flush()
+    }
+
+    pub fn read_from<R: Read>(r: R) -> Result<Self, Error> {
+        let mut r = BufReader::new(r);
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "not a reapfrog checkpoint"))
+        }
+        let n = get_varint(&mut r)?;
+        let mut entries = Vec::new();
+        for _ in 0..n {
+            let p = get_path(&mut r)?;
+            entries.push((p, get_varint(&mut r)?));
+        }
+        Ok(Checkpoint {entries})
+    }
+}
+
+impl<Src: Iterator<Item=PathBuf> + Clone> MultiFileReadahead<Src> {
+
+    /// Describes the remaining work: the files currently handed out at the consumer's position,
+    /// the opened files behind them and a clone of the source. Periodically written out it lets
+    /// long jobs continue after a crash via [`resume`](#method.resume).
+    ///
+    /// Files behind the current ones that already failed to open are not included, their errors
+    /// are lost. Bundle members and directory contents that were already expanded are included
+    /// as plain paths.
+    pub fn checkpoint(&self) -> Checkpoint {
+        let mut entries: Vec<_> = self.open.iter().enumerate().filter_map(|(i, o)| {
+            let p = o.as_ref().ok()?;
+            let offset = if i < self.delivered { p.position() } else { 0 };
+            Some((p.info.path.clone(), offset))
+        }).collect();
+        entries.extend(self.pending.iter().map(|(p, _)| (p.clone(), 0)));
+        entries.extend(self.stack.iter().rev().map(|p| (p.clone(), 0)));
+        entries.extend(self.horizon().map(|(p, _)| (p.to_owned(), 0)));
+        entries.extend(self.source.clone().map(|p| (p, 0)));
+        Checkpoint {entries}
+    }
+}
+
+impl MultiFileReadahead<vec::IntoIter<PathBuf>> {
+
+    /// Creates a queue continuing where a checkpoint was taken, partially read files are reopened
+    /// at their recorded offsets. Configure it the same way as the original queue.
+    pub fn resume(checkpoint: Checkpoint) -> Self {
+        let mut paths = Vec::with_capacity(checkpoint.entries.len());
+        let mut q = Self::new(Vec::new().into_iter());
+        for (p, offset) in checkpoint.entries {
+            if offset > 0 {
+                q.resume_at.insert(p.clone(), offset);
+            }
+            paths.push(p);
+        }
+        q.estimate.files = Some(paths.len() as u64);
+        q.source = paths.into_iter();
+        q
+    }
+}
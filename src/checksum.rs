@@ -0,0 +1,136 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use hash::Checksum;
+#[cfg(target_os = "linux")]
+use ioprio::PriorityGuard;
+use parallel::{DoneGuard, Job, WorkerFile};
+use std::io::{Error, ErrorKind, Read};
+use std::path::PathBuf;
+use std::sync::mpsc::channel;
+use MultiFileReadahead;
+
+const CHECKSUM_BUFFER: usize = 128 * 1024;
+
+fn checksum<C: Checksum>(f: &mut WorkerFile) -> Result<C::Digest, Error> {
+    let mut buf = vec![0; CHECKSUM_BUFFER];
+    let mut c = C::default();
+    loop {
+        match f.read(&mut buf) {
+            Ok(0) => return Ok(c.finish()),
+            Ok(n) => c.update(&buf[..n]),
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e)
+        }
+    }
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Hashes all queued files on the current rayon thread pool and returns the results in source order,
+    /// each with the path of its file.
+    ///
+    /// The calling thread plans prefetch for as many files as the pool has threads under the single
+    /// budget. An I/O priority set on the queue only applies to the pool's threads while they hash.
+    ///
+    /// # Panics
+    ///
+    /// When called from a thread of a rayon pool, which would wait for its own jobs.
+    pub fn checksums<C: Checksum>(mut self) -> Vec<(PathBuf, Result<C::Digest, Error>)> {
+        assert!(rayon::current_thread_index().is_none(), "checksums must not be called on a rayon pool thread");
+        let threads = rayon::current_num_threads();
+        let (events_tx, events) = channel();
+        let (results_tx, results) = channel();
+        let dropbehind = self.dropbehind;
+        #[cfg(target_os = "linux")]
+        let prio = self.io_priority.map(|(p, _)| p);
+        let mut seq = 0;
+
+        rayon::in_place_scope(|scope| {
+            let dispatch = |job: Job| {
+                let events_tx = events_tx.clone();
+                let results_tx = results_tx.clone();
+                let idx = seq;
+                seq += 1;
+                scope.spawn(move |_| {
+                    // the pool's threads are only borrowed
+                    #[cfg(target_os = "linux")]
+                    let _prio = prio.and_then(|p| PriorityGuard::apply(p).ok());
+                    let Job {file, cursor, drop_block} = job;
+                    let _guard = DoneGuard {events: &events_tx, cursor: cursor.clone()};
+                    let result = match file {
+                        Ok((path, file, len)) => {
+                            let mut wf = WorkerFile::new(path.clone(), file, len, cursor.unwrap(), dropbehind, drop_block, events_tx.clone());
+                            let digest = checksum::<C>(&mut wf);
+                            wf.finish();
+                            (path, digest)
+                        }
+                        Err((path, e)) => (path, Err(e))
+                    };
+                    let _ = results_tx.send((idx, result));
+                });
+                true
+            };
+            self.plan_parallel(threads, dispatch, events);
+        });
+
+        drop(results_tx);
+        let mut ordered: Vec<_> = (0..seq).map(|_| None).collect();
+        for (idx, result) in results {
+            ordered[idx] = Some(result);
+        }
+        ordered.into_iter().map(|r| r.expect("expect every dispatched file to report a result")).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::hash_map::DefaultHasher;
+    use testutil::Files;
+
+    fn digest(data: &[u8]) -> u64 {
+        let mut h = DefaultHasher::default();
+        Checksum::update(&mut h, data);
+        Checksum::finish(h)
+    }
+
+    #[test]
+    fn results_in_source_order_with_paths() {
+        let files = Files::new(&[("/a", b"a"), ("/c", &[3; 300_000]), ("/d", b"")]);
+        let results = files.queue(&["/a", "/b", "/c", "/d"]).checksums::<DefaultHasher>();
+        let paths: Vec<_> = results.iter().map(|r| r.0.clone()).collect();
+        assert_eq!(paths, ["/a", "/b", "/c", "/d"].iter().map(|n| files.path(n)).collect::<Vec<_>>());
+        assert_eq!(*results[0].1.as_ref().unwrap(), digest(b"a"));
+        assert_eq!(results[1].1.as_ref().unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(*results[2].1.as_ref().unwrap(), digest(&[3; 300_000]));
+        assert_eq!(*results[3].1.as_ref().unwrap(), digest(b""));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn pool_threads_keep_their_priority() {
+        fn priority() -> i64 {
+            // IOPRIO_WHO_PROCESS of the calling thread
+            unsafe { libc::syscall(libc::SYS_ioprio_get, 1, 0) }
+        }
+        let before = rayon::broadcast(|_| priority());
+        let files = Files::new(&[("/a", b"a"), ("/b", b"b")]);
+        let mut q = files.queue(&["/a", "/b"]);
+        q.io_priority(Some(::IoPriority::Idle)).unwrap();
+        assert_eq!(q.checksums::<DefaultHasher>().len(), 2);
+        assert_eq!(rayon::broadcast(|_| priority()), before);
+    }
+
+    #[test]
+    #[should_panic(expected = "rayon pool thread")]
+    fn rejects_pool_threads() {
+        let files = Files::new(&[("/a", b"a")]);
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        pool.install(|| files.queue(&["/a"]).checksums::<DefaultHasher>());
+    }
+}
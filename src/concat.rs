@@ -0,0 +1,67 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::io::{Error, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use MultiFileReadahead;
+
+/// Callback invoked with the path of each file before its first byte is read.
+pub type BoundaryFn = Box<dyn FnMut(&Path) + Send>;
+
+/// Reads the whole queue as one continuous stream, e.g. to pipe everything into a compressor.
+///
+/// A file that fails to open or read turns into a single error from `read`, the next call
+/// continues with the following file.
+pub struct Concat<Src> {
+    inner: MultiFileReadahead<Src>,
+    boundary: Option<BoundaryFn>,
+    // whether the front of the queue is the file currently being read
+    active: bool,
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    pub fn concat(self) -> Concat<Src> {
+        Concat {inner: self, boundary: None, active: false}
+    }
+}
+
+impl<Src: Iterator<Item=PathBuf>> Concat<Src> {
+
+    pub fn on_boundary(&mut self, f: Option<BoundaryFn>) {
+        self.boundary = f;
+    }
+}
+
+impl<Src: Iterator<Item=PathBuf>> Read for Concat<Src> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.is_empty() {
+            return Ok(0)
+        }
+        loop {
+            if !self.active {
+                match self.inner.next_entry() {
+                    None => return Ok(0),
+                    Some(Err(e)) => return Err(e),
+                    Some(Ok(())) => self.active = true
+                }
+                if let Some(ref mut f) = self.boundary {
+                    f(&self.inner.open[0].as_ref().expect("expect that next_entry only leaves successfully opened files at the front").info.path);
+                }
+            }
+            match self.inner.read_entry(0, buf) {
+                Ok(0) => self.active = false,
+                Ok(n) => return Ok(n),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.active = false;
+                    return Err(e)
+                }
+            }
+        }
+    }
+}
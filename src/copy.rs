@@ -0,0 +1,335 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{File, Metadata, OpenOptions};
+use std::hash::Hasher;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use sys;
+use {MultiFileReadahead, WriteBehind, DROPBEHIND_BLOCK};
+
+const COPY_CHUNK: usize = DROPBEHIND_BLOCK as usize;
+
+/// How the data of a file was transferred.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CopyMethod {
+    /// Shares the extents of the source with `FICLONE`, no data was copied
+    Reflink,
+    /// In-kernel copy with `copy_file_range`
+    CopyFileRange,
+    /// In-kernel copy with `sendfile`
+    Sendfile,
+    /// Read into a buffer and written back out
+    Stream,
+}
+
+/// A successfully copied file.
+#[derive(Debug)]
+pub struct Copied {
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    pub bytes: u64,
+    pub method: CopyMethod,
+    /// Whether the destination was read back and matched the source
+    pub verified: bool,
+}
+
+/// A file that could not be copied. The destination may have been created or partially written.
+#[derive(Debug)]
+pub struct CopyFailed {
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    pub error: Error,
+}
+
+impl fmt::Display for CopyFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "copying {} to {} failed: {}", self.source.display(), self.destination.display(), self.error)
+    }
+}
+
+impl std::error::Error for CopyFailed {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Feeds the source side of the pairs into the queue and remembers where each one goes.
+pub(crate) struct CopySources<Src> {
+    inner: Src,
+    // every source path yields exactly one queue entry, so these line up with the open files
+    pub(crate) pairs: VecDeque<(PathBuf, PathBuf)>,
+}
+
+impl<Src> CopySources<Src> {
+    pub(crate) fn new(pairs: Src) -> Self {
+        CopySources {inner: pairs, pairs: VecDeque::new()}
+    }
+}
+
+// opens the destination for a source with the given permission bits, creating missing parents
+pub(crate) fn create_destination(destination: &Path, mode: u32, atomic: bool) -> Result<WriteBehind, Error> {
+    if let Some(parent) = destination.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    if atomic {
+        return WriteBehind::atomic(destination, mode & 0o7777)
+    }
+    Ok(WriteBehind::new(OpenOptions::new().write(true).create(true).truncate(true).mode(mode & 0o7777).open(destination)?))
+}
+
+impl<Src: Iterator<Item=(PathBuf, PathBuf)>> Iterator for CopySources<Src> {
+    type Item = PathBuf;
+
+    fn next(&mut self) -> Option<PathBuf> {
+        let (src, dst) = self.inner.next()?;
+        self.pairs.push_back((src.clone(), dst));
+        Some(src)
+    }
+}
+
+/// Copies (source, destination) pairs with readahead and dropbehind on the sources and
+/// [`WriteBehind`](struct.WriteBehind.html) on the destinations.
+///
+/// On filesystems that support it the destination is a reflink of the source. Otherwise
+/// the data is moved inside the kernel with `copy_file_range` or `sendfile` where possible,
+/// falling back to streaming through a buffer. Missing parent directories of destinations are
+/// created, existing destinations are truncated and new ones get the permission bits of their source.
+pub struct MultiFileCopy<Src> {
+    inner: MultiFileReadahead<CopySources<Src>>,
+    max_dirty: Option<u64>,
+    dropbehind: bool,
+    reflink: bool,
+    verify: bool,
+    atomic: bool,
+    preallocate: Option<u64>,
+}
+
+impl<Src: Iterator<Item=(PathBuf, PathBuf)>> MultiFileCopy<Src> {
+
+    pub fn new(pairs: Src) -> Self {
+        MultiFileCopy {
+            inner: MultiFileReadahead::new(CopySources::new(pairs)),
+            max_dirty: None,
+            dropbehind: false,
+            reflink: true,
+            verify: false,
+            atomic: false,
+            preallocate: None,
+        }
+    }
+
+    /// Drops both sources and destinations from the page cache behind the copy.
+    pub fn dropbehind(&mut self, v: bool) {
+        self.dropbehind = v;
+        self.inner.dropbehind(v);
+    }
+
+    /// See [`WriteBehind::max_dirty`](struct.WriteBehind.html#method.max_dirty).
+    pub fn max_dirty(&mut self, bytes: u64) {
+        self.max_dirty = Some(bytes);
+    }
+
+    /// Whether to try reflinking before copying, defaults to true. Disable it to get physically independent copies.
+    pub fn reflink(&mut self, v: bool) {
+        self.reflink = v;
+    }
+
+    /// Reads every destination back after copying and compares it with a hash taken while reading the source.
+    ///
+    /// The source has to pass through userspace for that, so this always streams. Destinations are
+    /// synced and dropped from the page cache before they are read back so that the comparison
+    /// reflects what is on disk. A mismatch is reported as an `InvalidData` error.
+    pub fn verify(&mut self, v: bool) {
+        self.verify = v;
+    }
+
+    /// Writes destinations with [`WriteBehind::atomic`](struct.WriteBehind.html#method.atomic)
+    /// so that an interrupted run never leaves partial files visible. Each destination is synced
+    /// before it's moved into place and always gets the permission bits of its source.
+    pub fn atomic(&mut self, v: bool) {
+        self.atomic = v;
+    }
+
+    /// Preallocates destinations of sources with at least `min_len` bytes to their full size
+    /// before copying, see [`WriteBehind::preallocate`](struct.WriteBehind.html#method.preallocate).
+    /// Small files rarely fragment, so preallocating them would only cost a syscall. Reflinked
+    /// destinations aren't preallocated and neither are those on filesystems that don't support it.
+    pub fn preallocate(&mut self, min_len: Option<u64>) {
+        self.preallocate = min_len;
+    }
+
+    /// Copies the next file. Failures are reported per file and copying continues with the next one.
+    pub fn next_copy(&mut self) -> Option<Result<Copied, CopyFailed>> {
+        let entry = self.inner.next_entry()?;
+        let (source, destination) = self.inner.source.pairs.pop_front().expect("expect one pair per queue entry");
+        let mut hasher = if self.verify { Some(DefaultHasher::new()) } else { None };
+        let result = entry.and_then(|_| self.copy_current(&destination, hasher.as_mut()));
+        let result = result.and_then(|(bytes, method)| match hasher {
+            Some(h) => verify_copy(&destination, h.finish(), bytes, &mut self.inner.scratch).map(|_| (bytes, method, true)),
+            None => Ok((bytes, method, false)),
+        });
+        Some(match result {
+            Ok((bytes, method, verified)) => Ok(Copied{source, destination, bytes, method, verified}),
+            Err(error) => Err(CopyFailed{source, destination, error}),
+        })
+    }
+
+    fn copy_current(&mut self, destination: &Path, hasher: Option<&mut DefaultHasher>) -> Result<(u64, CopyMethod), Error> {
+        let meta = self.inner.open[0].as_ref().expect("expect that next_entry only leaves successfully opened files at the front").f.metadata()?;
+        let mode = meta.mode();
+        let mut out = create_destination(destination, mode, self.atomic)?;
+        if let Some(bytes) = self.max_dirty {
+            out.max_dirty(bytes);
+        }
+        out.dropbehind(self.dropbehind);
+        let copied = self.copy_into(&mut out, &meta, hasher)?;
+        out.persist()?;
+        Ok(copied)
+    }
+
+    fn copy_into(&mut self, out: &mut WriteBehind, meta: &Metadata, mut hasher: Option<&mut DefaultHasher>) -> Result<(u64, CopyMethod), Error> {
+
+        if self.reflink && hasher.is_none() && meta.is_file() {
+            let fetch = self.inner.open[0].as_ref().expect("expect that next_entry only leaves successfully opened files at the front");
+            match sys::clone_file(&fetch.f, out.get_ref()) {
+                Ok(()) => {
+                    // nothing was read, the prefetched pages are of no further use
+                    if self.dropbehind {
+                        fetch.drop_range(0, 0, &mut self.inner.hooks);
+                    }
+                    return Ok((meta.len(), CopyMethod::Reflink))
+                }
+                Err(ref e) if unsupported(e) => {}
+                Err(e) => return Err(e)
+            }
+        }
+
+        if let Some(min) = self.preallocate {
+            if meta.is_file() && meta.len() >= min {
+                match out.preallocate(meta.len()) {
+                    Ok(()) => {}
+                    Err(ref e) if unsupported(e) => {}
+                    Err(e) => return Err(e)
+                }
+            }
+        }
+
+        let mut method = if hasher.is_some() { CopyMethod::Stream } else { CopyMethod::CopyFileRange };
+        let mut total = 0;
+        loop {
+            let n = match method {
+                CopyMethod::CopyFileRange | CopyMethod::Sendfile => match self.inner.splice_entry(0, out.get_ref(), method) {
+                    Ok(n) => {
+                        out.wrote(n as u64)?;
+                        n
+                    }
+                    Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                    // the file offsets are unchanged, so the next method simply picks up where we are
+                    Err(ref e) if unsupported(e) => {
+                        method = if method == CopyMethod::CopyFileRange { CopyMethod::Sendfile } else { CopyMethod::Stream };
+                        continue
+                    }
+                    Err(e) => return Err(e)
+                },
+                CopyMethod::Stream => self.stream_chunk(out, hasher.as_deref_mut())?,
+                CopyMethod::Reflink => unreachable!(),
+            };
+            if n == 0 {
+                if hasher.is_some() {
+                    out.get_ref().sync_data()?;
+                }
+                return Ok((total, method))
+            }
+            total += n as u64;
+        }
+    }
+
+    fn stream_chunk(&mut self, out: &mut WriteBehind, hasher: Option<&mut DefaultHasher>) -> Result<usize, Error> {
+        let mut buf = std::mem::take(&mut self.inner.scratch);
+        buf.resize(COPY_CHUNK, 0);
+        let result = loop {
+            match self.inner.read_entry(0, &mut buf) {
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Ok(n) => {
+                    if let Some(h) = hasher {
+                        h.write(&buf[..n]);
+                    }
+                    break out.write_all(&buf[..n]).map(|_| n)
+                }
+                Err(e) => break Err(e)
+            }
+        };
+        self.inner.scratch = buf;
+        result
+    }
+}
+
+fn verify_copy(destination: &Path, hash: u64, len: u64, buf: &mut Vec<u8>) -> Result<(), Error> {
+    let mut f = File::open(destination)?;
+    // the data is on disk, so this evicts all of it
+    sys::advise_dontneed(&f, 0, 0);
+    sys::advise_sequential(&f);
+    buf.resize(COPY_CHUNK, 0);
+    let mut hasher = DefaultHasher::new();
+    let mut total = 0;
+    loop {
+        let n = match f.read(buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        };
+        hasher.write(&buf[..n]);
+        sys::advise_dontneed(&f, total, n as u64);
+        total += n as u64;
+    }
+    if total != len || hasher.finish() != hash {
+        return Err(Error::new(ErrorKind::InvalidData, "destination does not match the source"))
+    }
+    Ok(())
+}
+
+fn unsupported(e: &Error) -> bool {
+    if e.kind() == ErrorKind::Unsupported {
+        return true
+    }
+    match e.raw_os_error() {
+        Some(code) => code == libc::EXDEV || code == libc::EINVAL || code == libc::ENOSYS || code == libc::EOPNOTSUPP
+            || code == libc::EBADF || code == libc::ENOTTY,
+        None => false
+    }
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    // like read_entry, but the data goes straight from the kernel into out
+    fn splice_entry(&mut self, idx: usize, out: &File, method: CopyMethod) -> Result<usize, Error> {
+        let drop = self.dropbehind;
+        let result = {
+            let fetch = self.open[idx].as_mut().expect("expect that readers are only created for successfully opened files");
+            let result = match method {
+                CopyMethod::CopyFileRange => sys::copy_file_range(&fetch.f, out, COPY_CHUNK),
+                CopyMethod::Sendfile => sys::sendfile(&fetch.f, out, COPY_CHUNK),
+                CopyMethod::Reflink | CopyMethod::Stream => unreachable!(),
+            };
+            if let Ok(bytes) = result {
+                self.hooks.read(fetch.id, bytes as u64);
+                if let Some((offset, len)) = fetch.consume(bytes as u64, drop) {
+                    fetch.drop_range(offset, len, &mut self.hooks);
+                }
+            }
+            result
+        };
+        self.advance();
+        result
+    }
+}
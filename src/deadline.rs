@@ -0,0 +1,45 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::path::PathBuf;
+use std::time::Duration;
+use {MultiFileReadahead, PREFETCH_BLOCK};
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Bounds how long an opened file waits for its first prefetch, e.g. behind a huge earlier
+    /// file that takes up the whole budget. Once a file has been open this long without any of it
+    /// being prefetched its first block is advised regardless of the budget, which caps the
+    /// first-byte latency of files deep in the window at the cost of overshooting the budget by
+    /// a block per overdue file. Files with a prefetch plan or a target bitrate are exempt.
+    pub fn prefetch_deadline(&mut self, deadline: Option<Duration>) {
+        self.deadline = deadline;
+    }
+
+    // returns the amount issued
+    pub(crate) fn advise_overdue(&mut self) -> u64 {
+        let deadline = match self.deadline {
+            Some(d) => d,
+            None => return 0
+        };
+        let ttl = self.ttl.is_some();
+        let mut issued = 0;
+        for o in self.open.iter_mut() {
+            let p = match *o {
+                Ok(ref mut p) => p,
+                Err(_) => continue
+            };
+            if p.prefetch_pos > 0 || p.read_pos > 0 || p.length == 0 { continue }
+            if p.plan.is_some() || p.window.is_some() || p.info.link_of.is_some() || p.warm || p.unadvisable { continue }
+            if p.opened_at.elapsed() < deadline { continue }
+            let end = std::cmp::min(p.length, PREFETCH_BLOCK);
+            p.advise(0, end, ttl, &mut self.hooks);
+            issued += end;
+        }
+        issued
+    }
+}
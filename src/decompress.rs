@@ -0,0 +1,53 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::io::{Error, Read};
+use std::path::{Path, PathBuf};
+use Reader;
+
+/// Compression formats recognized by [`Reader::decompressed`](struct.Reader.html#method.decompressed),
+/// each behind the feature of the crate that decodes it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// `.gz`, concatenated members are read as one stream like `zcat` does
+    #[cfg(feature = "flate2")]
+    Gzip,
+    /// `.zst`
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Compression {
+
+    /// Recognizes the format by the file extension.
+    pub fn from_path(p: &Path) -> Option<Self> {
+        match p.extension()?.to_str()? {
+            #[cfg(feature = "flate2")]
+            "gz" => Some(Compression::Gzip),
+            #[cfg(feature = "zstd")]
+            "zst" => Some(Compression::Zstd),
+            _ => None
+        }
+    }
+}
+
+impl<'a, T> Reader<'a, T> where T: Iterator<Item=PathBuf> {
+
+    /// Decompresses the file if [`Compression::from_path`](enum.Compression.html#method.from_path)
+    /// recognizes it and reads it as is otherwise, for mixed directories of e.g. rotated logs.
+    ///
+    /// The queue keeps prefetching the compressed bytes, so the budget applies to the compressed size.
+    pub fn decompressed(self) -> Result<Box<dyn Read + Send + 'a>, Error> where T: Send + 'a {
+        Ok(match Compression::from_path(self.path()) {
+            #[cfg(feature = "flate2")]
+            Some(Compression::Gzip) => Box::new(flate2::read::MultiGzDecoder::new(self)),
+            #[cfg(feature = "zstd")]
+            Some(Compression::Zstd) => Box::new(zstd::stream::read::Decoder::new(self)?),
+            None => self.boxed()
+        })
+    }
+}
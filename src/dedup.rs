@@ -0,0 +1,61 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::path::{Path, PathBuf};
+use MultiFileReadahead;
+
+/// Whether paths that occur more than once are opened again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DuplicatePaths {
+    Keep,
+    /// Drops a path if it is among the last n distinct paths
+    Window(usize),
+    /// Drops a path if it has been seen before, memory grows with the number of paths
+    All,
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Skips exact duplicates in the source. Paths are compared as given, without canonicalization.
+    /// Defaults to `DuplicatePaths::Keep`.
+    pub fn duplicate_paths(&mut self, mode: DuplicatePaths) {
+        if mode != DuplicatePaths::Keep {
+            self.reject_paired("skipping duplicate paths");
+        }
+        self.dedup = mode;
+        self.seen.clear();
+        self.seen_order.clear();
+    }
+
+    // undoes is_duplicate for a path that wasn't opened after all
+    pub(crate) fn forget_seen(&mut self, p: &Path) {
+        if self.seen.remove(p) {
+            if let Some(i) = self.seen_order.iter().rposition(|q| q == p) {
+                self.seen_order.remove(i);
+            }
+        }
+    }
+
+    pub(crate) fn is_duplicate(&mut self, p: &Path) -> bool {
+        match self.dedup {
+            DuplicatePaths::Keep => false,
+            DuplicatePaths::All => !self.seen.insert(p.to_owned()),
+            DuplicatePaths::Window(n) => {
+                if self.seen.contains(p) {
+                    return true
+                }
+                self.seen.insert(p.to_owned());
+                self.seen_order.push_back(p.to_owned());
+                if self.seen_order.len() > n {
+                    let old = self.seen_order.pop_front().unwrap();
+                    self.seen.remove(&old);
+                }
+                false
+            }
+        }
+    }
+}
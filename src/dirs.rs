@@ -0,0 +1,96 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::io::Error;
+use std::os::unix::fs::DirEntryExt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use MultiFileReadahead;
+
+/// Order in which the entries of an expanded directory are queued.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DirOrder {
+    /// As returned by the filesystem
+    Unsorted,
+    /// Sorted by file name
+    Name,
+    /// Sorted by inode number, which approximates on-disk order on many filesystems
+    Inode,
+}
+
+// lists directories ahead of their expansion so that reading them later hits warm dentry and inode caches
+pub(crate) struct DirWarmer {
+    tx: Sender<PathBuf>,
+}
+
+impl DirWarmer {
+    fn new() -> Self {
+        let (tx, rx) = channel::<PathBuf>();
+        thread::spawn(move || {
+            for dir in rx {
+                let entries = match std::fs::read_dir(&dir) {
+                    Ok(e) => e,
+                    Err(_) => continue
+                };
+                for e in entries.flatten() {
+                    // pulls in the inode, which opening the file needs later
+                    let _ = e.metadata();
+                }
+            }
+        });
+        DirWarmer {tx}
+    }
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Recursively expands directories found in the source into the files they contain.
+    ///
+    /// Subdirectories are walked depth-first, symlinks inside expanded directories are queued as-is
+    /// and not followed into directories. Disabled by default, in which case directories fail on read.
+    pub fn expand_dirs(&mut self, order: Option<DirOrder>) {
+        if order.is_some() {
+            self.reject_paired("expanding directories");
+        }
+        self.dir_order = order;
+    }
+
+    /// Lists subdirectories found during expansion on a helper thread ahead of time,
+    /// so that walking cold directory trees doesn't stall the prefetcher.
+    pub fn dir_readahead(&mut self, enabled: bool) {
+        self.dir_warmer = if enabled { Some(DirWarmer::new()) } else { None };
+    }
+
+    pub(crate) fn expand_dir(&mut self, dir: &Path) -> Result<(), Error> {
+        let mut entries = Vec::new();
+        for e in std::fs::read_dir(dir)? {
+            let e = e?;
+            let is_dir = e.file_type()?.is_dir();
+            entries.push((e.ino(), e.path(), is_dir));
+        }
+
+        match self.dir_order.unwrap_or(DirOrder::Unsorted) {
+            DirOrder::Unsorted => {},
+            DirOrder::Name => entries.sort_by(|a, b| a.1.cmp(&b.1)),
+            DirOrder::Inode => entries.sort_by_key(|e| e.0),
+        }
+
+        if let Some(ref w) = self.dir_warmer {
+            for &(_, ref p, is_dir) in entries.iter() {
+                if is_dir {
+                    let _ = w.tx.send(p.clone());
+                }
+            }
+        }
+
+        for (_, p, is_dir) in entries.into_iter().rev() {
+            self.pending.push_front((p, is_dir));
+        }
+        Ok(())
+    }
+}
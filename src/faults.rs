@@ -0,0 +1,61 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::io::Error;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+use MultiFileReadahead;
+
+/// Failures to inject into the queue so that applications can test their error handling.
+#[derive(Clone, Debug, Default)]
+pub struct Faults {
+    /// Opening fails with `ENOENT` once this many files have been opened
+    pub open_enoent_after: Option<usize>,
+    /// Reads return at most this many bytes
+    pub short_reads: Option<usize>,
+    /// Reading the byte at this offset of any file fails with `EIO`
+    pub eio_at: Option<u64>,
+    /// Delay before every read
+    pub read_delay: Option<Duration>,
+}
+
+impl Faults {
+
+    pub(crate) fn open(&self, opened: usize) -> Result<(), Error> {
+        match self.open_enoent_after {
+            Some(n) if opened >= n => Err(Error::from_raw_os_error(libc::ENOENT)),
+            _ => Ok(())
+        }
+    }
+
+    // returns how many bytes the read may return
+    pub(crate) fn read(&self, pos: u64, len: usize) -> Result<usize, Error> {
+        if let Some(d) = self.read_delay {
+            thread::sleep(d);
+        }
+        let mut len = std::cmp::min(len, self.short_reads.unwrap_or(len));
+        if let Some(bad) = self.eio_at {
+            if pos == bad && len > 0 {
+                return Err(Error::from_raw_os_error(libc::EIO))
+            }
+            if pos < bad {
+                len = std::cmp::min(len as u64, bad - pos) as usize;
+            }
+        }
+        Ok(len)
+    }
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Installs failures to inject into subsequent opens and reads, `None` removes them.
+    pub fn inject_faults(&mut self, faults: Option<Faults>) {
+        self.faults = faults;
+        self.opened = 0;
+    }
+}
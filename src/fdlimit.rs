@@ -0,0 +1,32 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::io::Error;
+use std::path::PathBuf;
+use {MultiFileReadahead, MAX_OPEN};
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    // Descriptors are shared with the rest of the process, so running out of them while opening
+    // ahead only shrinks the window. The path is retried once files were delivered and closed.
+    // Opens that are needed for the next delivery still fail.
+    pub(crate) fn defer_open(&mut self, e: &Error) -> bool {
+        let exhausted = matches!(e.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE));
+        if !exhausted || self.bundles.is_some() || self.open.len() <= self.delivered + self.group {
+            return false
+        }
+        self.open_limit = std::cmp::max(self.delivered + self.group, self.open.len() / 2);
+        true
+    }
+
+    // grows the window again after a shrink, one file for each that was closed
+    pub(crate) fn regrow_open_limit(&mut self) {
+        if self.open_limit < MAX_OPEN {
+            self.open_limit += 1;
+        }
+    }
+}
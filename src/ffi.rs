@@ -0,0 +1,179 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! C API, declared in `include/reapfrog.h`.
+//!
+//! Build it as a shared library with `cargo rustc --release --features ffi --crate-type cdylib`.
+//! Functions that can fail return a negated `errno` value.
+
+use libc::{c_char, c_int, c_void, size_t, ssize_t};
+use std::ffi::{CStr, CString, OsStr};
+use std::io::{Error, ErrorKind};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::ptr;
+use {Budget, MultiFileReadahead};
+
+type Source = Box<dyn Iterator<Item=PathBuf> + Send>;
+
+/// Opaque queue handle.
+pub struct ReapfrogQueue {
+    inner: MultiFileReadahead<Source>,
+    // NUL-terminated path of the current file
+    path: Option<CString>,
+}
+
+/// Produces the next path, sets `*len` and returns a pointer to its bytes which must stay valid
+/// until the next call, or returns NULL once exhausted.
+pub type ReapfrogNextPath = unsafe extern "C" fn(ctx: *mut c_void, len: *mut size_t) -> *const c_char;
+
+struct Callback {
+    f: ReapfrogNextPath,
+    ctx: *mut c_void,
+}
+
+// the caller promises that the callback can be invoked from whichever thread uses the queue
+unsafe impl Send for Callback {}
+
+impl Iterator for Callback {
+    type Item = PathBuf;
+
+    fn next(&mut self) -> Option<PathBuf> {
+        let mut len = 0;
+        let p = unsafe { (self.f)(self.ctx, &mut len) };
+        if p.is_null() {
+            return None
+        }
+        let bytes = unsafe { std::slice::from_raw_parts(p as *const u8, len) };
+        Some(PathBuf::from(OsStr::from_bytes(bytes)))
+    }
+}
+
+fn errno(e: &Error) -> c_int {
+    match e.raw_os_error() {
+        Some(code) => code,
+        None if e.kind() == ErrorKind::InvalidInput => libc::EINVAL,
+        None => libc::EIO
+    }
+}
+
+fn create(src: Source) -> *mut ReapfrogQueue {
+    Box::into_raw(Box::new(ReapfrogQueue {inner: MultiFileReadahead::new(src), path: None}))
+}
+
+/// Creates a queue over `n` NUL-terminated paths, which are copied.
+///
+/// # Safety
+///
+/// `paths` must point to `n` valid NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn reapfrog_new_paths(paths: *const *const c_char, n: size_t) -> *mut ReapfrogQueue {
+    let paths: Vec<PathBuf> = (0..n).map(|i| PathBuf::from(OsStr::from_bytes(CStr::from_ptr(*paths.add(i)).to_bytes()))).collect();
+    create(Box::new(paths.into_iter()))
+}
+
+/// Creates a queue pulling paths from `next` as it needs them.
+///
+/// # Safety
+///
+/// `next` must honor the contract of `ReapfrogNextPath`. It's called with `ctx` from the thread
+/// using the queue until it returns NULL or the queue is freed.
+#[no_mangle]
+pub unsafe extern "C" fn reapfrog_new_callback(next: ReapfrogNextPath, ctx: *mut c_void) -> *mut ReapfrogQueue {
+    create(Box::new(Callback {f: next, ctx}))
+}
+
+/// Sets how many bytes may be prefetched ahead of the consumer.
+///
+/// # Safety
+///
+/// `q` must be a queue created by this API and not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn reapfrog_set_budget(q: *mut ReapfrogQueue, bytes: u64) {
+    (*q).inner.budget(Budget::Fixed(bytes));
+}
+
+/// Enables dropping read data from the cache if `enabled` is non-zero.
+///
+/// # Safety
+///
+/// `q` must be a queue created by this API and not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn reapfrog_set_dropbehind(q: *mut ReapfrogQueue, enabled: c_int) {
+    (*q).inner.dropbehind(enabled != 0);
+}
+
+/// Closes the current file and moves on to the next. Returns 1 if one is available, 0 once the
+/// queue is exhausted or the negated errno if it failed to open, in which case the next call
+/// continues with the file after it.
+///
+/// # Safety
+///
+/// `q` must be a queue created by this API and not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn reapfrog_next(q: *mut ReapfrogQueue) -> c_int {
+    let q = &mut *q;
+    q.path = None;
+    match q.inner.next_entry() {
+        None => 0,
+        Some(Err(e)) => -errno(&e),
+        Some(Ok(())) => {
+            let p = &q.inner.open[0].as_ref().expect("expect that next_entry only leaves successfully opened files at the front").info.path;
+            // paths can't contain NUL bytes
+            q.path = CString::new(p.as_os_str().as_bytes()).ok();
+            1
+        }
+    }
+}
+
+/// Reads up to `len` bytes of the current file. Returns the number of bytes read, 0 at its end
+/// or the negated errno.
+///
+/// # Safety
+///
+/// `q` must be a queue created by this API and not yet freed, `buf` must be valid for writes of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn reapfrog_read(q: *mut ReapfrogQueue, buf: *mut c_void, len: size_t) -> ssize_t {
+    let q = &mut *q;
+    if q.path.is_none() {
+        return -(libc::EBADF as ssize_t)
+    }
+    let buf = std::slice::from_raw_parts_mut(buf as *mut u8, len);
+    loop {
+        match q.inner.read_entry(0, buf) {
+            Ok(n) => return n as ssize_t,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return -(errno(&e) as ssize_t)
+        }
+    }
+}
+
+/// Path of the current file, NULL if there is none. Valid until the next call to `reapfrog_next`
+/// or `reapfrog_free`.
+///
+/// # Safety
+///
+/// `q` must be a queue created by this API and not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn reapfrog_path(q: *const ReapfrogQueue) -> *const c_char {
+    match (*q).path {
+        Some(ref p) => p.as_ptr(),
+        None => ptr::null()
+    }
+}
+
+/// Frees the queue, NULL is ignored.
+///
+/// # Safety
+///
+/// `q` must be NULL or a queue created by this API and not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn reapfrog_free(q: *mut ReapfrogQueue) {
+    if !q.is_null() {
+        drop(Box::from_raw(q));
+    }
+}
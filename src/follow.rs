@@ -0,0 +1,64 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::io::{Error, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+use MultiFileReadahead;
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Treats the last file of the queue as live, like `tail -f`.
+    ///
+    /// Once a reader reaches the end of the last file, it polls for appended data every `interval`
+    /// and blocks until some arrives. The source is polled as well, a file only ends when the source
+    /// yields another path after it, e.g. after log rotation. A file that shrinks is read again from the start.
+    pub fn follow(&mut self, interval: Option<Duration>) {
+        self.follow = interval;
+    }
+
+    pub(crate) fn follow_entry(&mut self, idx: usize, buf: &mut [u8], interval: Duration) -> Result<usize, Error> {
+        loop {
+            // a newer file is queued, this one is done
+            if idx + 1 < self.open.len() || !self.pending.is_empty() {
+                return Ok(0)
+            }
+
+            let appended = {
+                let fetch = self.open[idx].as_mut().expect("expect that readers are only created for successfully opened files");
+                let meta = fetch.f.metadata()?;
+                if !meta.is_file() {
+                    return Ok(0)
+                }
+                let len = meta.len();
+                if len < fetch.read_pos {
+                    fetch.f.seek(SeekFrom::Start(0))?;
+                    fetch.read_pos = 0;
+                    fetch.prefetch_pos = 0;
+                    fetch.to_drop = 0;
+                }
+                fetch.length = len;
+                len > fetch.read_pos
+            };
+
+            if appended {
+                let result = self.read_once(idx, buf);
+                self.advance();
+                match result {
+                    Ok(0) => {},
+                    r => return r
+                }
+            }
+
+            if self.add_file() {
+                return Ok(0)
+            }
+            thread::sleep(interval);
+        }
+    }
+}
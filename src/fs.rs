@@ -0,0 +1,102 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use sys;
+use MultiFileReadahead;
+
+/// Opens the files of the queue in place of the real filesystem.
+///
+/// Implementations return a real `File` so that everything fd-based (advise, mmap, worker threads)
+/// keeps working, but its contents may come from anywhere.
+pub trait Filesystem: Send {
+    fn open(&mut self, path: &Path) -> Result<File, Error>;
+}
+
+/// An in-memory filesystem for hermetic tests.
+///
+/// Each open returns a fresh anonymous file (a memfd on Linux) holding the contents,
+/// paths that were never inserted fail with `NotFound`.
+#[derive(Default)]
+pub struct MemoryFs {
+    files: HashMap<PathBuf, Vec<u8>>,
+}
+
+impl MemoryFs {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert<P: Into<PathBuf>, C: Into<Vec<u8>>>(&mut self, path: P, contents: C) {
+        self.files.insert(path.into(), contents.into());
+    }
+
+    pub fn remove(&mut self, path: &Path) -> Option<Vec<u8>> {
+        self.files.remove(path)
+    }
+}
+
+impl Filesystem for MemoryFs {
+    fn open(&mut self, path: &Path) -> Result<File, Error> {
+        let contents = match self.files.get(path) {
+            Some(c) => c,
+            None => return Err(Error::new(ErrorKind::NotFound, format!("{} not in MemoryFs", path.display())))
+        };
+        let mut f = sys::anonymous_file()?;
+        f.write_all(contents)?;
+        f.seek(SeekFrom::Start(0))?;
+        Ok(f)
+    }
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Opens subsequent files through `fs` instead of the real filesystem, `None` restores the default.
+    ///
+    /// Directory expansion still lists real directories.
+    pub fn filesystem(&mut self, fs: Option<Box<dyn Filesystem>>) {
+        self.fs = fs;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use testutil::read_front;
+
+    #[test]
+    fn opens_return_the_contents() {
+        let mut fs = MemoryFs::new();
+        fs.insert("/a", &b"hello"[..]);
+        for _ in 0..2 {
+            let mut data = Vec::new();
+            fs.open(Path::new("/a")).unwrap().read_to_end(&mut data).unwrap();
+            assert_eq!(data, b"hello");
+        }
+        assert_eq!(fs.remove(Path::new("/a")), Some(b"hello".to_vec()));
+        assert_eq!(fs.open(Path::new("/a")).unwrap_err().kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn queue_reads_through_the_filesystem() {
+        let mut fs = MemoryFs::new();
+        fs.insert("/a", vec![7u8; 200_000]);
+        fs.insert("/c", &b"c"[..]);
+        let mut q = MultiFileReadahead::new(vec![PathBuf::from("/a"), PathBuf::from("/b"), PathBuf::from("/c")].into_iter());
+        q.filesystem(Some(Box::new(fs)));
+        let mut read = Vec::new();
+        while let Some(entry) = q.next_entry() {
+            read.push(entry.map(|()| read_front(&mut q)).map_err(|e| e.kind()));
+        }
+        assert_eq!(read, vec![Ok(vec![7u8; 200_000]), Err(ErrorKind::NotFound), Ok(b"c".to_vec())]);
+    }
+}
@@ -0,0 +1,92 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::io::{Error, Read};
+
+/// A streaming hash usable with [`checksums`](struct.MultiFileReadahead.html#method.checksums)
+/// and [`HashingReader`](struct.HashingReader.html).
+pub trait Checksum: Default {
+    type Digest: Send;
+
+    fn update(&mut self, data: &[u8]);
+    fn finish(self) -> Self::Digest;
+}
+
+impl Checksum for DefaultHasher {
+    type Digest = u64;
+
+    fn update(&mut self, data: &[u8]) {
+        self.write(data);
+    }
+
+    fn finish(self) -> u64 {
+        Hasher::finish(&self)
+    }
+}
+
+#[cfg(feature = "blake3")]
+impl Checksum for blake3::Hasher {
+    type Digest = blake3::Hash;
+
+    fn update(&mut self, data: &[u8]) {
+        // splitting only pays off for large buffers
+        #[cfg(feature = "rayon")]
+        {
+            if data.len() >= 128 * 1024 {
+                self.update_rayon(data);
+                return
+            }
+        }
+        blake3::Hasher::update(self, data);
+    }
+
+    fn finish(self) -> blake3::Hash {
+        blake3::Hasher::finalize(&self)
+    }
+}
+
+/// XXH3, 64 bits
+#[cfg(feature = "xxhash-rust")]
+impl Checksum for xxhash_rust::xxh3::Xxh3 {
+    type Digest = u64;
+
+    fn update(&mut self, data: &[u8]) {
+        xxhash_rust::xxh3::Xxh3::update(self, data);
+    }
+
+    fn finish(self) -> u64 {
+        self.digest()
+    }
+}
+
+/// Hashes whatever is read through it, e.g. to verify files while consuming them from the queue.
+pub struct HashingReader<R, C> {
+    inner: R,
+    checksum: C,
+}
+
+impl<R: Read, C: Checksum> HashingReader<R, C> {
+
+    pub fn new(inner: R) -> Self {
+        HashingReader {inner, checksum: C::default()}
+    }
+
+    /// The digest of everything read so far.
+    pub fn finish(self) -> C::Digest {
+        self.checksum.finish()
+    }
+}
+
+impl<R: Read, C: Checksum> Read for HashingReader<R, C> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let n = self.inner.read(buf)?;
+        self.checksum.update(&buf[..n]);
+        Ok(n)
+    }
+}
@@ -0,0 +1,202 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Error;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use sys;
+use progress::LatencyHistogram;
+use trace::Tracer;
+use unadvisable::{Fallback, FALLBACK_CHUNK};
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use uring::AdviseRing;
+use MultiFileReadahead;
+
+/// Advice given by the planner, see [`advise_with`](struct.MultiFileReadahead.html#method.advise_with).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Advice {
+    /// The whole file will be read front to back, given once when it's opened unless it counts as
+    /// one of the [`tiny_files`](struct.MultiFileReadahead.html#method.tiny_files).
+    /// Offset and length are zero.
+    Sequential,
+    WillNeed,
+    DontNeed,
+}
+
+/// Callback issuing advice instead of the OS, called with `(fd, offset, len, advice)`.
+/// A length of zero extends to the end of the file.
+pub type AdviseFn = Box<dyn FnMut(RawFd, u64, u64, Advice) + Send>;
+
+/// Callback invoked right after a file is opened with `(fd, path, len)`, before any of it is prefetched.
+/// The length is zero for streams.
+pub type OpenedFn = Box<dyn FnMut(RawFd, &Path, u64) + Send>;
+
+// every open, read and advise of the queue goes through here so that it can be observed
+pub(crate) struct Hooks {
+    next_id: u64,
+    pub(crate) trace: Option<Tracer>,
+    advise: Option<AdviseFn>,
+    on_open: Option<OpenedFn>,
+    // count advice but don't issue it
+    pub(crate) dry_run: bool,
+    pub(crate) willneed_calls: u64,
+    pub(crate) dontneed_calls: u64,
+    pub(crate) files_delivered: u64,
+    pub(crate) bytes_delivered: u64,
+    pub(crate) starved_reads: u64,
+    pub(crate) prefetched_latency: LatencyHistogram,
+    pub(crate) starved_latency: LatencyHistogram,
+    // storage reads of the process when the queue was created
+    pub(crate) storage_baseline: Option<u64>,
+    // devices on which WillNeed keeps failing
+    pub(crate) unadvisable_devs: HashSet<u64>,
+    // reads ahead on a helper thread for files that can't be advised
+    pub(crate) fallback: Option<Fallback>,
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    pub(crate) ring: Option<AdviseRing>,
+}
+
+impl Hooks {
+    pub(crate) fn new() -> Self {
+        Hooks {next_id: 0, trace: None, advise: None, on_open: None, dry_run: false, willneed_calls: 0, dontneed_calls: 0, files_delivered: 0, bytes_delivered: 0, starved_reads: 0,
+            prefetched_latency: LatencyHistogram::default(), starved_latency: LatencyHistogram::default(),
+            storage_baseline: sys::storage_read_bytes(),
+            unadvisable_devs: HashSet::new(),
+            fallback: Some(Fallback::new(FALLBACK_CHUNK)),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            ring: None,
+        }
+    }
+
+    pub(crate) fn opened(&mut self, p: &Path, f: &File, len: u64, sequential: bool) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        if let Some(ref mut t) = self.trace {
+            t.opened(id, p, len);
+        }
+        if sequential {
+            let _ = self.issue(id, f, 0, 0, Advice::Sequential);
+        }
+        if let Some(ref mut on_open) = self.on_open {
+            on_open(f.as_raw_fd(), p, len);
+        }
+        id
+    }
+
+    pub(crate) fn open_failed(&mut self, p: &Path) {
+        if let Some(ref mut t) = self.trace {
+            t.open_failed(p);
+        }
+    }
+
+    pub(crate) fn read(&mut self, id: u64, bytes: u64) {
+        self.bytes_delivered += bytes;
+        if let Some(ref mut t) = self.trace {
+            t.read(id, bytes);
+        }
+    }
+
+    pub(crate) fn closed(&mut self, id: u64) {
+        self.flush();
+        if let Some(ref mut t) = self.trace {
+            t.closed(id);
+        }
+    }
+
+    // None if the advice was batched, its outcome is then reported by take_completed
+    pub(crate) fn willneed(&mut self, id: u64, f: &File, offset: u64, len: u64) -> Option<Result<(), Error>> {
+        if let Some(ref mut t) = self.trace {
+            t.willneed(id, offset, len);
+        }
+        self.willneed_calls += 1;
+        self.issue(id, f, offset, len, Advice::WillNeed)
+    }
+
+    pub(crate) fn dontneed(&mut self, id: u64, f: &File, offset: u64, len: u64) {
+        if let Some(ref mut t) = self.trace {
+            t.dontneed(id, offset, len);
+        }
+        self.dontneed_calls += 1;
+        let _ = self.issue(id, f, offset, len, Advice::DontNeed);
+    }
+
+    fn issue(&mut self, id: u64, f: &File, offset: u64, len: u64, advice: Advice) -> Option<Result<(), Error>> {
+        if self.dry_run {
+            return Some(Ok(()))
+        }
+        if let Some(ref mut advise) = self.advise {
+            advise(f.as_raw_fd(), offset, len, advice);
+            return Some(Ok(()))
+        }
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        {
+            let queued = match (self.ring.as_mut(), advice) {
+                (Some(r), Advice::WillNeed) => r.push(f, offset, len, libc::POSIX_FADV_WILLNEED, Some(id)),
+                (Some(r), Advice::DontNeed) => r.push(f, offset, len, libc::POSIX_FADV_DONTNEED, None),
+                _ => false
+            };
+            if queued {
+                return None
+            }
+        }
+        #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+        let _ = id;
+        match advice {
+            Advice::Sequential => sys::advise_sequential(f),
+            Advice::WillNeed => return Some(sys::advise_willneed(f, offset, len)),
+            Advice::DontNeed => sys::advise_dontneed(f, offset, len),
+        }
+        Some(Ok(()))
+    }
+
+    // issues batched advice, called at the end of each planning step and before files are closed
+    pub(crate) fn flush(&mut self) {
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        {
+            if let Some(ref mut r) = self.ring {
+                r.flush();
+            }
+        }
+    }
+
+    // outcomes of batched WillNeed advice by file id, in completion order
+    pub(crate) fn take_completed(&mut self) -> Vec<(u64, Result<(), Error>)> {
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        {
+            if let Some(ref mut r) = self.ring {
+                return r.take_completed()
+            }
+        }
+        Vec::new()
+    }
+}
+
+// the queue drops its hooks before the files so that pending advice still refers to open descriptors
+impl Drop for Hooks {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Hands the planner's advice to `f` instead of the OS, e.g. to assert on the exact sequence
+    /// in tests. `None` restores the default.
+    ///
+    /// Only covers files read through the queue itself, worker threads and shuffled records advise directly.
+    pub fn advise_with(&mut self, f: Option<AdviseFn>) {
+        self.hooks.advise = f;
+    }
+
+    /// Lets `f` apply its own per-file advice or flags to each file the queue opens, e.g.
+    /// `POSIX_FADV_NOREUSE` for huge files. It runs after the sequential access advice, so it can override it.
+    pub fn on_open(&mut self, f: Option<OpenedFn>) {
+        self.hooks.on_open = f;
+    }
+}
@@ -0,0 +1,126 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use {MultiFileReadahead, PREFETCH_BLOCK};
+
+pub(crate) struct Horizon {
+    entries: usize,
+    // source entries pulled ahead of time with their size, if they are regular files
+    ahead: VecDeque<(PathBuf, Option<u64>)>,
+    // how many of them are smaller than a prefetch block
+    tiny: usize,
+    // the source ran out while filling
+    drained: bool,
+}
+
+impl Horizon {
+    // entries still ahead, None if the source may hold more
+    pub(crate) fn remaining(&self) -> Option<usize> {
+        if self.drained { Some(self.ahead.len()) } else { None }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.ahead.len()
+    }
+}
+
+fn is_tiny(len: Option<u64>) -> bool {
+    len.is_some_and(|l| l < PREFETCH_BLOCK)
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Stats up to `entries` source entries beyond the opened files without spending descriptors on them.
+    ///
+    /// Knowing what's coming lets the planner hold back on a file much larger than the budget
+    /// when the next files are mostly tiny, so that those are prefetched by the time the consumer reaches them.
+    /// The stats happen on the calling thread. Sizes are not known for a custom [`Filesystem`](trait.Filesystem.html).
+    pub fn stat_horizon(&mut self, entries: usize) {
+        if entries == 0 {
+            // keep what was already pulled from the source
+            if let Some(h) = self.horizon.as_mut() {
+                h.entries = 0;
+            }
+            return
+        }
+        match self.horizon {
+            Some(ref mut h) => h.entries = entries,
+            None => self.horizon = Some(Horizon {entries, ahead: VecDeque::new(), tiny: 0, drained: false})
+        }
+    }
+
+    /// The source entries statted ahead by [`stat_horizon`](#method.stat_horizon) in order, with
+    /// their length if they are regular files.
+    pub fn horizon(&self) -> impl Iterator<Item=(&Path, Option<u64>)> + '_ {
+        self.horizon.iter().flat_map(|h| h.ahead.iter().map(|&(ref p, len)| (p.as_path(), len)))
+    }
+
+    pub(crate) fn next_source(&mut self) -> Option<PathBuf> {
+        self.next_stacked()
+    }
+
+    pub(crate) fn next_pulled(&mut self) -> Option<PathBuf> {
+        if let Some(ref mut h) = self.horizon {
+            if let Some((p, len)) = h.ahead.pop_front() {
+                if is_tiny(len) {
+                    h.tiny -= 1;
+                }
+                return Some(p)
+            }
+        }
+        self.source.next()
+    }
+
+    pub(crate) fn fill_horizon(&mut self) {
+        let h = match self.horizon {
+            Some(ref mut h) => h,
+            None => return
+        };
+        while h.ahead.len() < h.entries {
+            let p = match self.source.next() {
+                Some(p) => p,
+                None => {
+                    h.drained = true;
+                    break
+                }
+            };
+            let len = match self.fs {
+                Some(_) => None,
+                None => std::fs::metadata(&p).ok().filter(|m| m.is_file()).map(|m| m.len())
+            };
+            if is_tiny(len) {
+                h.tiny += 1;
+            }
+            h.ahead.push_back((p, len));
+        }
+    }
+
+    // removes a path statted ahead, false if it isn't among them
+    pub(crate) fn forget_ahead(&mut self, p: &Path) -> bool {
+        let h = match self.horizon {
+            Some(ref mut h) => h,
+            None => return false
+        };
+        match h.ahead.iter().position(|(q, _)| q == p) {
+            Some(i) => {
+                let (_, len) = h.ahead.remove(i).unwrap();
+                if is_tiny(len) {
+                    h.tiny -= 1;
+                }
+                true
+            }
+            None => false
+        }
+    }
+
+    // whether the files ahead are mostly tiny
+    pub(crate) fn tiny_ahead(&self) -> bool {
+        self.horizon.as_ref().is_some_and(|h| !h.ahead.is_empty() && h.tiny * 2 >= h.ahead.len())
+    }
+}
@@ -0,0 +1,95 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::io::Error;
+use std::path::PathBuf;
+use std::thread;
+use MultiFileReadahead;
+
+const IOPRIO_CLASS_SHIFT: i32 = 13;
+const IOPRIO_WHO_PROCESS: i32 = 1;
+
+/// I/O scheduling class and level, lower levels are served first. Levels range from 0 to 7.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IoPriority {
+    /// Requires `CAP_SYS_ADMIN`
+    Realtime(u8),
+    BestEffort(u8),
+    /// Only served when nobody else needs the disk
+    Idle,
+}
+
+impl IoPriority {
+    fn value(self) -> i32 {
+        let (class, level) = match self {
+            IoPriority::Realtime(l) => (1, l),
+            IoPriority::BestEffort(l) => (2, l),
+            IoPriority::Idle => (3, 0),
+        };
+        class << IOPRIO_CLASS_SHIFT | i32::from(std::cmp::min(level, 7))
+    }
+}
+
+/// Sets the I/O priority of the calling thread via `ioprio_set`.
+pub fn set_thread_io_priority(prio: IoPriority) -> Result<(), Error> {
+    set_raw(prio.value())
+}
+
+fn set_raw(value: i32) -> Result<(), Error> {
+    // who = 0 targets the calling thread
+    let ret = unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, value) };
+    if ret < 0 {
+        return Err(Error::last_os_error())
+    }
+    Ok(())
+}
+
+// applies a priority to a borrowed thread, e.g. of a pool, and restores the previous one when dropped
+#[cfg(feature = "rayon")]
+pub(crate) struct PriorityGuard(i32);
+
+#[cfg(feature = "rayon")]
+impl PriorityGuard {
+    pub(crate) fn apply(prio: IoPriority) -> Result<Self, Error> {
+        let previous = unsafe { libc::syscall(libc::SYS_ioprio_get, IOPRIO_WHO_PROCESS, 0) };
+        if previous < 0 {
+            return Err(Error::last_os_error())
+        }
+        set_thread_io_priority(prio)?;
+        Ok(PriorityGuard(previous as i32))
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl Drop for PriorityGuard {
+    fn drop(&mut self) {
+        let _ = set_raw(self.0);
+    }
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Applies an I/O priority to the reads and readahead issued by the queue.
+    ///
+    /// The priority is set on the calling thread right away and on any other thread the
+    /// queue is later driven from, those threads keep it afterwards.
+    pub fn io_priority(&mut self, prio: Option<IoPriority>) -> Result<(), Error> {
+        self.io_priority = prio.map(|p| (p, None));
+        self.apply_io_priority()
+    }
+
+    pub(crate) fn apply_io_priority(&mut self) -> Result<(), Error> {
+        if let Some((prio, ref mut applied)) = self.io_priority {
+            let current = thread::current().id();
+            if *applied != Some(current) {
+                set_thread_io_priority(prio)?;
+                *applied = Some(current);
+            }
+        }
+        Ok(())
+    }
+}
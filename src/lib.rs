@@ -5,15 +5,208 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+#![cfg_attr(feature = "read_buf", feature(core_io_borrowed_buf, read_buf))]
+
 extern crate libc;
+#[cfg(feature = "memmap2")]
+extern crate memmap2;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+#[cfg(feature = "bytes")]
+extern crate bytes;
+#[cfg(feature = "futures-io")]
+extern crate futures_io;
+#[cfg(feature = "pyo3")]
+extern crate pyo3;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+extern crate io_uring;
+#[cfg(feature = "flate2")]
+extern crate flate2;
+#[cfg(feature = "zstd")]
+extern crate zstd;
+#[cfg(feature = "blake3")]
+extern crate blake3;
+#[cfg(feature = "xxhash-rust")]
+extern crate xxhash_rust;
+// the pyo3 macros expand to ::core paths, which need the crate in the root under the 2015 edition
+#[cfg(feature = "pyo3")]
+extern crate core;
+
+#[cfg(feature = "memmap2")]
+mod mmap;
+#[cfg(feature = "memmap2")]
+pub use mmap::MappedFile;
 
+#[cfg(test)]
+mod testutil;
+mod pair;
+pub use pair::{Pair, Paired};
+mod zip;
+pub use zip::{Zip, ZipPair};
+mod piece;
+pub use piece::{Piece, Pieces, Span};
+mod dirs;
+pub use dirs::DirOrder;
+use dirs::DirWarmer;
+mod source;
+pub use source::{Interleave, Interleaving, PathList, SizedPaths};
+mod links;
+pub use links::Hardlinks;
+use links::Link;
+mod rate;
+use rate::TokenBucket;
+pub mod advise;
+mod sys;
+mod special;
+pub use special::{NotRegularFile, SpecialFiles};
+mod dedup;
+pub use dedup::DuplicatePaths;
+mod follow;
+mod stat;
+pub use stat::StatAhead;
+mod parallel;
+pub use parallel::WorkerFile;
+mod owned;
+pub use owned::{OwnedReader, SharedQueue};
+mod tee;
+mod peek;
+mod schedule;
+pub use schedule::Schedule;
+mod strategy;
+pub use strategy::{Fetch, FetchStrategy, QueuedFile};
+use strategy::Strategy;
+mod plan;
+pub use plan::{PlanFn, PrefetchPlan};
+use plan::Plan;
+mod bitrate;
+pub use bitrate::BitrateFn;
+mod shuffle;
+pub use shuffle::{ReshuffleFn, ShuffledRecords};
+mod repeat;
+use repeat::Repeat;
+mod records;
+pub use records::{Framing, Record, Records};
+mod concat;
+pub use concat::{BoundaryFn, Concat};
+mod hooks;
+pub use hooks::{Advice, AdviseFn, OpenedFn};
+use hooks::Hooks;
+mod trace;
+pub use trace::{read_trace, replay_trace, TraceEvent};
+mod simulate;
+pub use simulate::{SimulatedFile, Simulation};
+mod bench;
+pub use bench::Benchmark;
+mod bundle;
+pub use bundle::{Bundle, ExpandFn};
+use bundle::Bundles;
+mod horizon;
+use horizon::Horizon;
+mod background;
+use background::Background;
+mod channel;
+pub use channel::FileChunk;
+#[cfg(feature = "futures-io")]
+mod async_read;
+#[cfg(feature = "futures-io")]
+pub use async_read::{AsyncReadahead, NextFile};
+mod budget;
+pub use budget::{Budget, SharedBudget};
+use budget::{AutoBudget, BudgetShare};
+mod planned;
+pub use planned::PlannedFile;
+mod madvise;
+pub use madvise::RegionReadahead;
+mod progress;
+pub use progress::{Estimate, FileProgress, LatencyHistogram, Totals};
+mod checkpoint;
+pub use checkpoint::Checkpoint;
+mod pause;
+mod ready;
+mod ramp;
+mod tiny;
+mod quota;
+pub use quota::{ClassifyFn, Quotas};
+mod priority;
+pub use priority::PollFn;
+mod cancel;
+mod lifo;
+mod stale;
+mod fdlimit;
+mod unadvisable;
+mod adaptive;
+mod deadline;
+mod topology;
+use topology::Topology;
+mod pressure;
+use pressure::IoPressure;
+pub use adaptive::{AdaptiveSettings, Device};
+use adaptive::Adaptive;
+mod notify;
+pub use notify::{ReadyFile, ReadyQueue};
+mod fs;
+pub use fs::{Filesystem, MemoryFs};
+mod write;
+pub use write::WriteBehind;
+mod copy;
+pub use copy::{Copied, CopyFailed, CopyMethod, MultiFileCopy};
+mod userdata;
+pub use userdata::WithUserData;
+use userdata::{Stash, UserData};
+mod transform;
+mod uninit;
+mod positional;
+pub use transform::{MultiFileTransform, Transform, TransformFn, Transformed};
+mod hash;
+pub use hash::{Checksum, HashingReader};
+#[cfg(feature = "rayon")]
+mod checksum;
+#[cfg(feature = "fault-injection")]
+mod faults;
+#[cfg(feature = "fault-injection")]
+pub use faults::Faults;
+#[cfg(target_os = "linux")]
+mod xattr;
+#[cfg(target_os = "linux")]
+pub use xattr::Xattr;
+#[cfg(target_os = "linux")]
+mod acl;
+#[cfg(target_os = "linux")]
+pub use acl::{AclEntry, AclTag, SecurityMetadata};
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+#[cfg(target_os = "linux")]
+mod ioprio;
+#[cfg(target_os = "linux")]
+pub use ioprio::{set_thread_io_priority, IoPriority};
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "pyo3")]
+pub mod python;
+#[cfg(any(feature = "flate2", feature = "zstd"))]
+mod decompress;
+#[cfg(any(feature = "flate2", feature = "zstd"))]
+pub use decompress::Compression;
+#[cfg(feature = "cdc")]
+mod cdc;
+#[cfg(feature = "cdc")]
+pub use cdc::{Chunk, ChunkerConfig, Chunks};
+
+use std::collections::HashMap;
+use std::collections::HashSet;
 use std::collections::VecDeque;
 use std::fs::File;
 use std::fs::Metadata;
-use std::io::Read;
-use std::os::unix::io::AsRawFd;
+use std::io::{Read, Seek, SeekFrom};
+use std::mem::MaybeUninit;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd};
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
 
 const DROPBEHIND_BLOCK : u64 = 512 * 1024;
 const PREFETCH_SHIFT : u8 = 16;
@@ -21,184 +214,995 @@ const PREFETCH_BLOCK : u64 = 1 << PREFETCH_SHIFT;
 const MAX_OPEN : usize = 512;
 const DEFAULT_BUDGET : u64 = 8*1024*1024;
 
+// what readers need to know about a file, shared with them so that their accessors don't depend on the queue
+struct FileInfo {
+    path: PathBuf,
+    // first path through which a hardlinked file was queued, such duplicates are not prefetched
+    link_of: Option<PathBuf>,
+    user_data: Option<UserData>,
+    #[cfg(target_os = "linux")]
+    xattrs: Option<Result<Vec<Xattr>, std::io::Error>>,
+    #[cfg(target_os = "linux")]
+    security: Option<Result<SecurityMetadata, std::io::Error>>,
+}
+
 struct Prefetch {
-    p: PathBuf,
+    // identifies the file towards hooks
+    id: u64,
+    info: Arc<FileInfo>,
     f: File,
     read_pos: u64,
     prefetch_pos: u64,
     to_drop: u64,
-    length: u64
+    length: u64,
+    // offset of the file within the concatenation of all files
+    base: u64,
+    // when the currently outstanding prefetch was first advised
+    advised_at: Option<Instant>,
+    // the prefetched pages expired before the consumer got here, don't re-advise until it's next in line
+    stale: bool,
+    // read position published by whoever reads the file outside of the queue, e.g. a worker thread
+    shared: Option<Arc<AtomicU64>>,
+    // bytes already read from the file by peek_head but not yet by the consumer
+    head: Vec<u8>,
+    head_pos: usize,
+    // ranges chosen by the plan callback, sequential prefetch if none
+    plan: Option<Plan>,
+    // bytes to keep prefetched if the file has a target rate, it's then exempt from the budget
+    window: Option<u64>,
+    // a repeated file that is expected to still be cached, don't advise it
+    warm: bool,
+    // class of its quota
+    class: Option<usize>,
+    priority: u32,
+    // consecutive WillNeed failures, once too many it isn't advised anymore
+    advise_failures: u32,
+    unadvisable: bool,
+    // read ahead by the fallback helper instead of advised, with the read position it skips past
+    emulated: Option<Arc<AtomicU64>>,
+    opened_at: Instant,
+    // multiples prefetched ranges end on and dropped ranges are made of
+    align: u64,
+    drop_block: u64,
+}
+
+// outcome of opening a single entry
+#[derive(PartialEq, Eq)]
+enum Opened {
+    // a file or an error was queued
+    Queued,
+    // nothing was queued for it, e.g. a duplicate or an expanded directory
+    Skipped,
+    // put back to be retried later
+    Deferred,
+}
+
+// the range behind read_pos to drop once `to_drop` bytes have accumulated there, it ends on a block
+// boundary and the rest is dropped along with the next block
+fn drop_behind(read_pos: u64, to_drop: &mut u64, block: u64) -> Option<(u64, u64)> {
+    if *to_drop < block {
+        return None
+    }
+    let start = read_pos - *to_drop;
+    let end = std::cmp::max(read_pos / block * block, start);
+    *to_drop = read_pos - end;
+    if end == start {
+        return None
+    }
+    Some((start, end - start))
 }
 
 impl Prefetch {
-    fn new(f: File, len: u64, p: PathBuf) -> Self {
-        unsafe {
-            libc::posix_fadvise(f.as_raw_fd(), 0, 0, libc::POSIX_FADV_SEQUENTIAL);
+    fn new(id: u64, f: File, len: u64, info: FileInfo, base: u64) -> Self {
+        Prefetch{id, f, read_pos: 0, length: len, info: Arc::new(info), to_drop: 0, prefetch_pos: 0, base, advised_at: None, stale: false, shared: None, head: Vec::new(), head_pos: 0, plan: None, window: None, warm: false, class: None, priority: 0, advise_failures: 0, unadvisable: false, emulated: None, opened_at: Instant::now(), align: PREFETCH_BLOCK, drop_block: DROPBEHIND_BLOCK}
+    }
+
+    // how far the consumer has read, excluding what peek_head buffered ahead of it
+    fn position(&self) -> u64 {
+        self.read_pos - (self.head.len() - self.head_pos) as u64
+    }
+
+    // advances the read position, returns a range behind it that should be dropped from the cache
+    fn consume(&mut self, bytes: u64, drop: bool) -> Option<(u64, u64)> {
+        self.read_pos += bytes;
+        if let Some(ref pos) = self.emulated {
+            pos.store(self.read_pos, Ordering::Relaxed);
+        }
+        if !drop {
+            return None
+        }
+        self.to_drop += bytes;
+        drop_behind(self.read_pos, &mut self.to_drop, self.drop_block)
+    }
+
+    // advises everything from old_pos up to new_pos
+    fn advise(&mut self, old_pos: u64, new_pos: u64, ttl: bool, hooks: &mut Hooks) {
+        self.willneed(old_pos, new_pos - old_pos, hooks);
+        self.prefetch_pos = new_pos;
+        if self.advised_at.is_none() && ttl {
+            self.advised_at = Some(Instant::now());
+        }
+    }
+
+    fn drop_range(&self, offset: u64, len: u64, hooks: &mut Hooks) {
+        hooks.dontneed(self.id, &self.f, offset, len);
+    }
+
+    // whether the next read hits data that should have been but wasn't prefetched
+    fn unadvised(&self) -> bool {
+        self.plan.is_none() && self.info.link_of.is_none() && !self.warm && self.read_pos < self.length && self.read_pos >= self.prefetch_pos
+    }
+
+    // drops what was advised ahead of the read position so that it gets advised again
+    fn expire(&mut self, hooks: &mut Hooks) {
+        if self.plan.is_some() {
+            self.expire_plan(hooks);
+        } else if self.prefetch_pos > self.read_pos {
+            self.drop_range(self.read_pos, self.prefetch_pos - self.read_pos, hooks);
         }
-        Prefetch{f, read_pos: 0, length: len, p, to_drop: 0, prefetch_pos: 0}
+        self.prefetch_pos = self.read_pos;
+        self.advised_at = None;
     }
 }
 
+/// Readahead and dropbehind over the files produced by a path iterator.
+///
+/// The queue and the adapters built on it are `Send` whenever the source is, so they can be moved to
+/// another thread. They aren't `Sync` since installed callbacks only need to be `Send`; wrap the
+/// queue in a `Mutex` to share it.
 pub struct MultiFileReadahead<Src> {
+    // declared before the files so that it's dropped first
+    hooks: Hooks,
     source: Src,
-    open: VecDeque<Result<Prefetch, std::io::Error>>,
+    // failed entries keep their path for the drivers reporting results per file
+    open: VecDeque<Result<Prefetch, (PathBuf, std::io::Error)>>,
     dropbehind: bool,
     budget: u64,
+    // number of entries at the front of the queue currently handed out to the consumer
+    delivered: usize,
+    // number of entries delivered together, their prefetch is interleaved
+    group: usize,
+    // entries are delivered as pairs of two lists, they must not be dropped, added or reordered
+    paired: bool,
+    // total length of all files added so far
+    stream_len: u64,
+    // if non-zero prefetch is aligned to pieces of this size in the concatenated stream
+    piece: u64,
+    // paths to be opened before pulling from the source, flagged by whether directories should be expanded
+    pending: VecDeque<(PathBuf, bool)>,
+    // source entries pulled ahead for lifo, the top is delivered next
+    stack: Vec<PathBuf>,
+    lifo: usize,
+    // how often a file is reopened after ESTALE
+    stale_reopens: u32,
+    // how far files are opened ahead, shrinks when descriptors run out
+    open_limit: usize,
+    dir_order: Option<DirOrder>,
+    dir_warmer: Option<DirWarmer>,
+    hardlinks: Hardlinks,
+    links: HashMap<(u64, u64), PathBuf>,
+    ttl: Option<Duration>,
+    rate: Option<TokenBucket>,
+    special: SpecialFiles,
+    dedup: DuplicatePaths,
+    seen: HashSet<PathBuf>,
+    // insertion order of seen paths when deduplicating within a window
+    seen_order: VecDeque<PathBuf>,
+    // poll interval for growing files at the end of the queue
+    follow: Option<Duration>,
+    // reusable buffer for copying adapters
+    scratch: Vec<u8>,
+    // bytes held by adapters wrapping the queue, e.g. the current piece
+    buffered: u64,
+    #[cfg(target_os = "linux")]
+    xattrs: bool,
+    #[cfg(target_os = "linux")]
+    security: bool,
+    user_data: Option<Arc<Stash>>,
+    // the priority and the thread it was last applied to
+    #[cfg(target_os = "linux")]
+    io_priority: Option<(IoPriority, Option<std::thread::ThreadId>)>,
+    fs: Option<Box<dyn Filesystem>>,
+    schedule: Schedule,
+    strategy: Option<Strategy>,
+    plan: Option<PlanFn>,
+    bitrate: Option<(Duration, BitrateFn)>,
+    repeat: Option<Repeat>,
+    bundles: Option<Bundles>,
+    horizon: Option<Horizon>,
+    background: Option<Background>,
+    shared_budget: Option<BudgetShare>,
+    // how much may be prefetched while zipped with a queue that is behind
+    lead: Option<u64>,
+    auto_budget: Option<AutoBudget>,
+    adaptive: Option<Adaptive>,
+    io_pressure: Option<IoPressure>,
+    topology: Option<Topology>,
+    // longest an opened file goes without prefetch
+    deadline: Option<Duration>,
+    estimate: Estimate,
+    // offsets to reopen files at after resuming from a checkpoint
+    resume_at: HashMap<PathBuf, u64>,
+    paused: bool,
+    ramp: Option<u64>,
+    // files shorter than this get simplified advice
+    tiny: u64,
+    quotas: Option<Quotas>,
+    priority_sources: Vec<(u32, PollFn)>,
+    #[cfg(feature = "fault-injection")]
+    faults: Option<Faults>,
+    // files opened since the faults were installed
+    #[cfg(feature = "fault-injection")]
+    opened: usize,
 }
 
 
 pub struct Reader<'a, T: 'a> {
-    owner: &'a mut MultiFileReadahead<T>
+    owner: &'a mut MultiFileReadahead<T>,
+    idx: usize,
+    info: Arc<FileInfo>,
 }
 
 impl<'a, T> Reader<'a, T> where T: Iterator<Item=PathBuf> {
 
-    pub fn metadata(&self) -> Metadata {
-        self.owner.open[0].as_ref().expect("expect that readers are only created for successfully opened files").f.metadata().unwrap()
+    // None if the entry failed to open
+    pub(crate) fn new(owner: &'a mut MultiFileReadahead<T>, idx: usize) -> Option<Self> {
+        let info = match owner.open[idx] {
+            Ok(ref p) => p.info.clone(),
+            Err(_) => return None
+        };
+        Some(Reader {owner, idx, info})
+    }
+
+    fn entry(&self) -> Option<&Prefetch> {
+        self.owner.open[self.idx].as_ref().ok()
+    }
+
+    /// Length of the file as of opening it, or as far as it has grown when following it.
+    /// Zero for streams such as pipes.
+    pub fn len(&self) -> u64 {
+        self.entry().map_or(0, |p| p.length)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Bytes of the file not yet read by the consumer.
+    pub fn remaining(&self) -> u64 {
+        self.entry().map_or(0, |p| p.length.saturating_sub(p.position()))
+    }
+
+    /// Type-erases the reader for APIs taking a `Box<dyn Read + Send>`, e.g. archive writers or
+    /// HTTP bodies. The box still borrows the queue.
+    pub fn boxed(self) -> Box<dyn Read + Send + 'a> where T: Send + 'a {
+        Box::new(self)
+    }
+
+    pub fn metadata(&self) -> Result<Metadata, std::io::Error> {
+        match self.owner.open[self.idx] {
+            Ok(ref p) => p.f.metadata(),
+            Err(_) => Err(std::io::Error::other("reader refers to a file that failed to open"))
+        }
     }
 
     pub fn path(&self) -> &Path {
-        &self.owner.open[0].as_ref().expect("expect that readers are only created for successfully opened files").p
+        &self.info.path
+    }
+
+    /// The path through which this file was first seen, if it was already queued through another hardlink.
+    pub fn hardlink_of(&self) -> Option<&Path> {
+        self.info.link_of.as_deref()
+    }
+
+    /// The data queued along with this file by [`MultiFileReadahead::with_user_data`](struct.MultiFileReadahead.html#method.with_user_data),
+    /// `None` if there is none or it isn't a `D`.
+    pub fn user_data<D: std::any::Any>(&self) -> Option<&D> {
+        self.info.user_data.as_ref().and_then(|d| d.downcast_ref())
+    }
+
+    /// Extended attributes read ahead of time, `None` unless enabled with
+    /// [`MultiFileReadahead::xattrs`](struct.MultiFileReadahead.html#method.xattrs).
+    #[cfg(target_os = "linux")]
+    pub fn xattrs(&self) -> Option<Result<&[Xattr], &std::io::Error>> {
+        self.info.xattrs.as_ref().map(|r| r.as_ref().map(|v| v.as_slice()))
+    }
+
+    /// ACLs and security attributes read ahead of time, `None` unless enabled with
+    /// [`MultiFileReadahead::security_metadata`](struct.MultiFileReadahead.html#method.security_metadata).
+    #[cfg(target_os = "linux")]
+    pub fn security_metadata(&self) -> Option<Result<&SecurityMetadata, &std::io::Error>> {
+        self.info.security.as_ref().map(|r| r.as_ref())
     }
 
 }
 
-impl<'a, T> Read for &'a mut Reader<'a, T>
+impl<'a, T> Read for Reader<'a, T>
     where T: Iterator<Item=PathBuf>
 {
     fn read(&mut self, buf: &mut [u8]) -> std::result::Result<usize, std::io::Error> {
-        let result = {
-            let drop = self.owner.dropbehind;
-            let ref mut fetch = self.owner.open[0].as_mut().expect("expect that readers are only created for successfully opened files");
-            let result = fetch.f.read(buf);
-            if let Ok(bytes) = result {
-                fetch.read_pos += bytes as u64;
-                if drop {
-                    fetch.to_drop += bytes as u64;
-                    if fetch.to_drop >= DROPBEHIND_BLOCK {
-                        unsafe {
-                            let drop_offset = fetch.read_pos - fetch.to_drop;
-                            libc::posix_fadvise(fetch.f.as_raw_fd(), drop_offset as i64, fetch.to_drop as i64, libc::POSIX_FADV_DONTNEED);
-                        }
-                        fetch.to_drop = 0;
+        self.owner.read_entry(self.idx, buf)
+    }
+
+    fn read_to_end(&mut self, buf: &mut Vec<u8>) -> std::result::Result<usize, std::io::Error> {
+        self.read_to_vec(buf)
+    }
+
+    #[cfg(feature = "read_buf")]
+    fn read_buf(&mut self, mut cursor: std::io::BorrowedCursor<'_>) -> std::result::Result<(), std::io::Error> {
+        let n = self.read_uninit(unsafe { cursor.as_mut() })?;
+        unsafe { cursor.advance(n) };
+        Ok(())
+    }
+
+    // plans once per block instead of after every partial read
+    fn read_exact(&mut self, mut buf: &mut [u8]) -> std::result::Result<(), std::io::Error> {
+        let mut result = Ok(());
+        let mut unplanned = 0;
+        while !buf.is_empty() {
+            let n = match self.owner.read_once(self.idx, buf) {
+                // let read_entry decide whether this is the end, e.g. when following a growing file
+                Ok(0) => self.owner.read_entry(self.idx, buf),
+                r => r
+            };
+            match n {
+                Ok(0) => {
+                    result = Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+                    break
+                }
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    unplanned += n as u64;
+                    if unplanned >= DROPBEHIND_BLOCK {
+                        self.owner.advance();
+                        unplanned = 0;
                     }
                 }
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    result = Err(e);
+                    break
+                }
             }
-
-
-            result
-        };
+        }
         self.owner.advance();
         result
     }
 }
 
+/// The descriptor of the current file for operations like `flock`, `ioctl` or `sendfile`.
+///
+/// Reading through it or moving its offset isn't seen by the queue, the reader then continues
+/// from wherever the descriptor was left and prefetch lags behind. Prefer positional reads.
+impl<'a, T> AsFd for Reader<'a, T> where T: Iterator<Item=PathBuf> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.entry().expect("expect that readers are only created for successfully opened files").f.as_fd()
+    }
+}
+
+impl<'a, T> AsRawFd for Reader<'a, T> where T: Iterator<Item=PathBuf> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.as_fd().as_raw_fd()
+    }
+}
+
 impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src>  {
 
     pub fn new(src: Src) -> Self {
-        MultiFileReadahead {source: src, open: VecDeque::new(), dropbehind: false, budget: DEFAULT_BUDGET}
+        let files = match src.size_hint() {
+            (lower, Some(upper)) if lower == upper => Some(lower as u64),
+            _ => None
+        };
+        MultiFileReadahead {
+            hooks: Hooks::new(),
+            source: src,
+            open: VecDeque::new(),
+            dropbehind: false,
+            budget: DEFAULT_BUDGET,
+            delivered: 0,
+            group: 1,
+            paired: false,
+            stream_len: 0,
+            piece: 0,
+            pending: VecDeque::new(),
+            stack: Vec::new(),
+            lifo: 0,
+            stale_reopens: 0,
+            open_limit: MAX_OPEN,
+            dir_order: None,
+            dir_warmer: None,
+            hardlinks: Hardlinks::Read,
+            links: HashMap::new(),
+            ttl: None,
+            rate: None,
+            special: SpecialFiles::Stream,
+            dedup: DuplicatePaths::Keep,
+            seen: HashSet::new(),
+            seen_order: VecDeque::new(),
+            follow: None,
+            scratch: Vec::new(),
+            buffered: 0,
+            #[cfg(target_os = "linux")]
+            xattrs: false,
+            #[cfg(target_os = "linux")]
+            security: false,
+            user_data: None,
+            #[cfg(target_os = "linux")]
+            io_priority: None,
+            fs: None,
+            schedule: Schedule::Sequential,
+            strategy: None,
+            plan: None,
+            bitrate: None,
+            repeat: None,
+            bundles: None,
+            horizon: None,
+            background: None,
+            shared_budget: None,
+            lead: None,
+            auto_budget: None,
+            adaptive: None,
+            io_pressure: None,
+            topology: None,
+            deadline: None,
+            estimate: Estimate {files, bytes: None},
+            resume_at: HashMap::new(),
+            paused: false,
+            ramp: None,
+            tiny: 0,
+            quotas: None,
+            priority_sources: Vec::new(),
+            #[cfg(feature = "fault-injection")]
+            faults: None,
+            #[cfg(feature = "fault-injection")]
+            opened: 0,
+        }
     }
 
     pub fn dropbehind(&mut self, v : bool) {
         self.dropbehind = v;
     }
 
+    /// Drops prefetched data of queued files that the consumer doesn't reach within `ttl`,
+    /// it would likely be evicted before use anyway. Such files are advised again once they are next in line.
+    pub fn prefetch_ttl(&mut self, ttl: Option<Duration>) {
+        self.ttl = ttl;
+    }
+
+    /// Caps how many bytes per second are advised, so that background jobs don't saturate a shared disk.
+    /// The budget only limits how much is outstanding at any time, not how fast it is refilled.
+    pub fn rate_limit(&mut self, bytes_per_sec: Option<u64>) {
+        self.rate = bytes_per_sec.map(|r| TokenBucket::new(std::cmp::max(r, PREFETCH_BLOCK)));
+    }
+
+    fn expire_stale(&mut self, ttl: Duration) {
+        let now = Instant::now();
+        for o in self.open.iter_mut().skip(self.delivered) {
+            let p = match *o {
+                Ok(ref mut p) => p,
+                Err(_) => continue
+            };
+            match p.advised_at {
+                Some(t) if now.duration_since(t) > ttl => {},
+                _ => continue
+            }
+            p.expire(&mut self.hooks);
+            p.stale = true;
+        }
+    }
+
+    // memory held in internal buffers, it counts against the budget like prefetched pages
+    fn buffer_bytes(&self) -> u64 {
+        let heads = self.open.iter().map(|o| match *o {
+            Ok(ref o) => o.head.capacity() as u64,
+            Err(_) => 0
+        }).sum::<u64>();
+        heads + self.scratch.capacity() as u64 + self.buffered + self.background.as_ref().map_or(0, |b| b.footprint()) + self.hooks.fallback.as_ref().map_or(0, |b| b.footprint())
+    }
+
     fn advance(&mut self) {
+        self.plan_prefetch();
+        self.hooks.flush();
+        self.advice_completed();
+    }
 
+    fn plan_prefetch(&mut self) {
+        if !self.priority_sources.is_empty() {
+            self.poll_priority();
+        }
+        for o in self.open.iter_mut().take(self.delivered) {
+            if let Ok(ref mut p) = *o {
+                if let Some(ref c) = p.shared {
+                    p.read_pos = c.load(Ordering::Relaxed);
+                }
+            }
+        }
+
+        if let Some(ttl) = self.ttl {
+            self.expire_stale(ttl);
+        }
+
+        if self.bitrate.is_some() && !self.paused {
+            self.advise_bitrate();
+        }
+
+        if !self.paused {
+            self.fill_horizon();
+        }
+        let limit = match self.shared_budget {
+            Some(ref s) => s.limit(),
+            None => {
+                self.resize_budget();
+                self.adapt();
+                self.budget
+            }
+        };
+        let limit = self.lead.map_or(limit, |lead| std::cmp::min(limit, lead));
+        let limit = self.pressure_limit(limit);
+        // files much larger than the budget only get half of it if tiny ones follow
+        let hold_back = if self.tiny_ahead() { Some(limit / 2) } else { None };
+
+        let buffers = self.buffer_bytes();
         let consumed = self.open.iter().map(|o| {
             match *o {
-                Ok(ref o) => o.prefetch_pos.saturating_sub(o.read_pos),
+                Ok(ref o) if o.window.is_some() && o.plan.is_none() => 0,
+                Ok(ref o) => o.outstanding(),
                 Err(_) => 0
             }
-        }).sum::<u64>();
+        }).sum::<u64>() + buffers;
+        if let Some(ref mut s) = self.shared_budget {
+            s.report(consumed);
+        }
+        if self.paused {
+            return
+        }
+
+        let overdue = self.advise_overdue();
+        if overdue > 0 {
+            if let Some(ref mut rate) = self.rate {
+                rate.take(overdue);
+            }
+        }
+        let consumed = consumed + overdue;
 
         // we may overshoot our budget slightly, saturate to zero
-        let mut budget = self.budget.saturating_sub(consumed);
+        let mut budget = limit.saturating_sub(consumed);
+        // members of a group share the budget so that none of them runs far ahead of the others
+        let share = limit / self.group as u64;
 
-        // hysteresis: let the loop expend the budget to ~100% if possible, then don't loop until we fall to 50%
-        if budget < consumed {
+        // hysteresis: let the loop expend the budget to ~100% if possible, then don't loop until we fall to 50%.
+        // Internal buffers only shrink the budget, large ones would otherwise hold back prefetch entirely.
+        if budget < consumed - buffers {
             return
         }
 
+        if let Some(ref mut rate) = self.rate {
+            budget = std::cmp::min(budget, rate.available());
+        }
+        let mut issued = 0;
+        // a custom strategy leaves nothing for the built-in planner below
+        if self.strategy.is_some() {
+            issued = self.advise_strategy(budget);
+            budget = 0;
+        }
+        let mut used = self.quota_usage();
+
+        if self.schedule == Schedule::HeadersFirst {
+            let headers = self.advise_headers(budget / 2);
+            budget -= headers;
+            issued += headers;
+        }
+
         for i in 0.. {
             if budget < PREFETCH_BLOCK { break; }
 
-            if i == self.open.len() && !self.add_file() {
+            if i == self.open.len() && (i > self.open_limit || !self.add_file()) {
                 break
             }
 
             if i > MAX_OPEN { break }
 
-            let ref mut p = match self.open[i] {
+            let p = match self.open[i] {
                 Ok(ref mut p) => p,
                 Err(_) => continue
             };
 
+            if p.info.link_of.is_some() || p.warm || p.unadvisable || (p.window.is_some() && p.plan.is_none()) { continue; }
+            if p.stale {
+                if i > self.delivered { continue; }
+                p.stale = false;
+            }
+            if p.plan.is_some() {
+                let outstanding = p.outstanding();
+                if outstanding >= share { continue; }
+                let allowance = (std::cmp::min(budget, share - outstanding) >> PREFETCH_SHIFT) << PREFETCH_SHIFT;
+                let n = p.advise_plan(allowance, self.ttl.is_some(), &mut self.hooks);
+                budget = budget.saturating_sub(n);
+                issued += n;
+                continue
+            }
             let old_pos = std::cmp::max(p.read_pos, p.prefetch_pos);
             if old_pos >= p.length { continue; }
+            if p.length < self.tiny {
+                if i >= self.delivered {
+                    p.advise(old_pos, p.length, self.ttl.is_some(), &mut self.hooks);
+                    budget = budget.saturating_sub(p.length - old_pos);
+                    issued += p.length - old_pos;
+                }
+                continue
+            }
+            let ahead = old_pos - p.read_pos;
+            let share = match hold_back {
+                Some(half) if p.length - p.read_pos > limit => std::cmp::min(share, half),
+                _ => share
+            };
+            let ramped = p.ramp_limit(self.ramp, share);
+            // while ramping up, top up in chunks that grow along with the window
+            if ahead >= ramped || (ramped < share && ahead > ramped / 2) { continue; }
+            let share = ramped;
+            let quota = self.quotas.as_ref().map_or(u64::MAX, |q| q.left(&used, p.class));
+            if quota < PREFETCH_BLOCK { continue; }
             // round down
-            let internal_budget = (budget >> PREFETCH_SHIFT) << PREFETCH_SHIFT;
+            let internal_budget = (std::cmp::min(std::cmp::min(budget, quota), share - ahead) >> PREFETCH_SHIFT) << PREFETCH_SHIFT;
             let mut prefetch_length = std::cmp::min(p.length - old_pos, internal_budget);
             let mut new_pos = old_pos + prefetch_length;
             // round up to multiple so that readaheads are aligned
             // allows slight overshoot of budget
-            new_pos = (new_pos + PREFETCH_BLOCK - 1) & !(PREFETCH_BLOCK - 1);
+            if self.piece > 0 {
+                let end = p.base + new_pos;
+                new_pos = end.div_ceil(self.piece) * self.piece - p.base;
+            } else {
+                new_pos = new_pos.div_ceil(p.align) * p.align;
+            }
             new_pos = std::cmp::min(p.length, new_pos);
 
             prefetch_length = new_pos - old_pos;
 
-            unsafe {
-                libc::posix_fadvise(p.f.as_raw_fd(), old_pos as i64, prefetch_length as i64, libc::POSIX_FADV_WILLNEED);
+            p.advise(old_pos, new_pos, self.ttl.is_some(), &mut self.hooks);
+            if let Some(u) = p.class.and_then(|c| used.get_mut(c)) {
+                *u += prefetch_length;
             }
 
             budget = budget.saturating_sub(prefetch_length);
-            p.prefetch_pos = new_pos;
+            issued += prefetch_length;
+        }
+
+        if let Some(ref mut rate) = self.rate {
+            rate.take(issued);
+        }
+        if let Some(ref mut s) = self.shared_budget {
+            s.report(consumed + issued);
+        }
+    }
+
+    fn read_entry(&mut self, idx: usize, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        self.read_entry_uninit(idx, uninit::as_uninit(buf), true)
+    }
+
+    fn read_entry_uninit(&mut self, idx: usize, buf: &mut [MaybeUninit<u8>], initialized: bool) -> Result<usize, std::io::Error> {
+        let result = self.read_once_uninit(idx, buf, initialized);
+        self.advance();
+        if let (Ok(0), Some(interval)) = (result.as_ref(), self.follow) {
+            if !buf.is_empty() {
+                return self.follow_entry(idx, uninit::init(buf, initialized), interval)
+            }
         }
+        result
+    }
+
+    fn read_once(&mut self, idx: usize, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        self.read_once_uninit(idx, uninit::as_uninit(buf), true)
     }
 
+    fn read_once_uninit(&mut self, idx: usize, buf: &mut [MaybeUninit<u8>], initialized: bool) -> Result<usize, std::io::Error> {
+        let mut reopens = 0;
+        loop {
+            match self.read_attempt(idx, buf, initialized) {
+                Err(ref e) if e.raw_os_error() == Some(libc::ESTALE) && reopens < self.stale_reopens => {
+                    reopens += 1;
+                    self.reopen(idx)?;
+                }
+                result => return result
+            }
+        }
+    }
+
+    fn read_attempt(&mut self, idx: usize, buf: &mut [MaybeUninit<u8>], initialized: bool) -> Result<usize, std::io::Error> {
+        let drop = self.dropbehind;
+        let fetch = self.open[idx].as_mut().expect("expect that readers are only created for successfully opened files");
+        if fetch.head_pos < fetch.head.len() {
+            let n = std::cmp::min(buf.len(), fetch.head.len() - fetch.head_pos);
+            uninit::copy(buf, &fetch.head[fetch.head_pos..][..n]);
+            fetch.head_pos += n;
+            if fetch.head_pos == fetch.head.len() {
+                fetch.head = Vec::new();
+                fetch.head_pos = 0;
+            }
+            // peek_head already reported these bytes when it read them from the file
+            return Ok(n)
+        }
+        #[cfg(feature = "fault-injection")]
+        let buf = match self.faults {
+            Some(ref faults) => match faults.read(fetch.read_pos, buf.len()) {
+                Ok(len) => &mut buf[..len],
+                Err(e) => return Err(e)
+            },
+            None => buf
+        };
+        let starved = fetch.unadvised();
+        if starved {
+            self.hooks.starved_reads += 1;
+        }
+        let start = Instant::now();
+        let result = match self.background {
+            Some(ref mut bg) if fetch.length > 0 && !buf.is_empty() => bg.read(fetch, uninit::init(buf, initialized)),
+            _ => uninit::read(&fetch.f, buf)
+        };
+        let latency = start.elapsed();
+        if starved {
+            self.hooks.starved_latency.record(latency);
+        } else {
+            self.hooks.prefetched_latency.record(latency);
+        }
+        if let Ok(bytes) = result {
+            self.hooks.read(fetch.id, bytes as u64);
+            if let Some((offset, len)) = fetch.consume(bytes as u64, drop) {
+                fetch.drop_range(offset, len, &mut self.hooks);
+            }
+        }
+
+        result
+    }
+
+    // returns false once the source is exhausted
     fn add_file(&mut self) -> bool {
-        match self.source.next() {
-            None => false,
-            Some(p) => {
-                let f = match File::open(&p) {
-                    Ok(f) => f,
-                    Err(e) => {
-                        self.open.push_back(Err(e));
-                        return false
+        loop {
+            let (p, expand) = match self.pending.pop_front() {
+                Some(e) => e,
+                None if self.replaying() => {
+                    self.start_bundle();
+                    return self.replay_file()
+                }
+                None => match self.next_source() {
+                    None => {
+                        self.start_bundle();
+                        return self.replay_file()
                     }
-                };
+                    Some(p) => match self.expand_entry(p) {
+                        Ok(members) => {
+                            let expand = self.dir_order.is_some();
+                            self.pending.extend(members.into_iter().map(|m| (m, expand)));
+                            continue
+                        }
+                        Err(p) => (p, self.dir_order.is_some())
+                    }
+                }
+            };
+
+            match self.open_entry(p, expand) {
+                Opened::Queued => return true,
+                Opened::Skipped => {}
+                Opened::Deferred => return false
+            }
+        }
+    }
+
+    pub(crate) fn open_entry(&mut self, p: PathBuf, expand: bool) -> Opened {
+        if self.is_duplicate(&p) {
+            return Opened::Skipped
+        }
+        let user_data = self.user_data.as_ref().and_then(|s| s.pop(&p));
+
+        #[cfg(feature = "fault-injection")]
+        {
+            if let Some(ref faults) = self.faults {
+                self.opened += 1;
+                if let Err(e) = faults.open(self.opened - 1) {
+                    self.hooks.open_failed(&p);
+                    self.open.push_back(Err((p, e)));
+                    return Opened::Queued
+                }
+            }
+        }
+
+        let f = match self.fs {
+            Some(ref mut fs) => fs.open(&p),
+            None => sys::open_nonblocking(&p)
+        };
+        let f = match f {
+            Ok(f) => f,
+            Err(ref e) if self.defer_open(e) => {
+                self.forget_seen(&p);
+                if let (Some(s), Some(data)) = (self.user_data.as_ref(), user_data) {
+                    s.unpop(&p, data);
+                }
+                self.pending.push_front((p, expand));
+                return Opened::Deferred
+            }
+            Err(e) => {
+                self.hooks.open_failed(&p);
+                self.open.push_back(Err((p, e)));
+                return Opened::Queued
+            }
+        };
+
+        let meta = match f.metadata() {
+            Ok(m) => m,
+            Err(e) => {
+                self.open.push_back(Err((p, e)));
+                return Opened::Queued
+            }
+        };
+
+        if expand && meta.is_dir() {
+            if let Err(e) = self.expand_dir(&p) {
+                self.open.push_back(Err((p, e)));
+                return Opened::Queued
+            }
+            return Opened::Skipped
+        }
+
+        let link_of = match self.check_link(&meta, &p) {
+            Link::First => None,
+            Link::Skip => return Opened::Skipped,
+            Link::Duplicate(first) => Some(first)
+        };
 
-                let len = match f.metadata() {
-                    Ok(m) => m.len(),
-                    Err(e) => {
-                        self.open.push_back(Err(e));
-                        return false
+        if special::is_special(meta.file_type()) {
+            let result = match self.special {
+                SpecialFiles::Reject => {
+                    let e = std::io::Error::new(std::io::ErrorKind::InvalidInput, NotRegularFile {path: p.clone(), file_type: meta.file_type()});
+                    Err((p, e))
+                }
+                SpecialFiles::Stream => match sys::clear_nonblocking(&f) {
+                    Ok(()) => {
+                        let id = self.hooks.opened(&p, &f, 0, true);
+                        let info = FileInfo {path: p, link_of: None, user_data,
+                            #[cfg(target_os = "linux")]
+                            xattrs: None,
+                            #[cfg(target_os = "linux")]
+                            security: None,
+                        };
+                        Ok(Prefetch::new(id, f, 0, info, self.stream_len))
                     }
-                };
+                    Err(e) => Err((p, e))
+                }
+            };
+            self.open.push_back(result);
+            return Opened::Queued
+        }
+
+        let len = if meta.file_type().is_block_device() {
+            match sys::block_device_size(&f) {
+                Ok(l) => l,
+                Err(e) => {
+                    self.open.push_back(Err((p, e)));
+                    return Opened::Queued
+                }
+            }
+        } else {
+            meta.len()
+        };
+        self.push_file(f, len, p, link_of, user_data, false);
+        Opened::Queued
+    }
 
-                self.open.push_back(Ok(Prefetch::new(f, len, p)));
-                true
+    fn push_file(&mut self, f: File, len: u64, p: PathBuf, link_of: Option<PathBuf>, user_data: Option<UserData>, warm: bool) {
+        if let Some(ref mut r) = self.repeat {
+            r.record(&p, len, &link_of, &user_data);
+        }
+        let id = self.hooks.opened(&p, &f, len, len >= self.tiny);
+        let plan = match self.plan {
+            Some(ref plan) => match plan(&p, len) {
+                PrefetchPlan::Ranges(ranges) => Some(Plan::new(ranges, len)),
+                PrefetchPlan::Sequential => None
+            },
+            None => None
+        };
+        let window = self.assign_bitrate(&p, len);
+        #[cfg(target_os = "linux")]
+        let xattrs = if self.xattrs { Some(xattr::read_xattrs(&f)) } else { None };
+        let info = FileInfo {path: p, link_of, user_data,
+            #[cfg(target_os = "linux")]
+            security: if self.security { Some(acl::read_security(&f, xattrs.as_ref())) } else { None },
+            #[cfg(target_os = "linux")]
+            xattrs,
+        };
+        let mut fetch = Prefetch::new(id, f, len, info, self.stream_len);
+        fetch.check_advisable(&self.hooks);
+        self.apply_topology(&mut fetch);
+        if let Some(offset) = self.resume_at.remove(&fetch.info.path) {
+            let offset = std::cmp::min(offset, len);
+            if let Err(e) = fetch.f.seek(SeekFrom::Start(offset)) {
+                self.open.push_back(Err((fetch.info.path.clone(), e)));
+                return
             }
+            fetch.read_pos = offset;
+            fetch.prefetch_pos = offset;
         }
+        fetch.warm = warm;
+        fetch.class = self.quotas.as_ref().and_then(|q| q.classify(&fetch.info.path));
+        fetch.plan = plan;
+        fetch.window = window;
+        self.open.push_back(Ok(fetch));
+        self.stream_len += len;
     }
 
-    pub fn next(&mut self) -> Option<Result<Reader<Src>, std::io::Error>> {
-        // discard most recent file
-        if let Some(Ok(p)) = self.open.pop_front() {
-            if p.to_drop > 0 {
-                unsafe {
-                    libc::posix_fadvise(p.f.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED);
+    // discards the current group and makes the next n entries the current one
+    fn next_group(&mut self, n: usize) -> bool {
+        for _ in 0..self.delivered {
+            if let Some(Ok(p)) = self.open.pop_front() {
+                if p.to_drop > 0 {
+                    p.drop_range(0, 0, &mut self.hooks);
                 }
+                self.hooks.closed(p.id);
+                self.regrow_open_limit();
             }
         }
+        self.delivered = 0;
+        #[cfg(target_os = "linux")]
+        {
+            // best effort, the initial call in io_priority already surfaced any error
+            let _ = self.apply_io_priority();
+        }
         self.advance();
 
-        if self.open.is_empty() && !self.add_file() {
-            return None;
-        };
+        while self.open.len() < n {
+            if !self.add_file() {
+                return false
+            }
+        }
+        self.delivered = n;
+        self.hooks.files_delivered += self.open.iter().take(n).filter(|o| o.is_ok()).count() as u64;
+        true
+    }
+
+    // discards the current file and makes the next one the front of the queue
+    fn next_entry(&mut self) -> Option<Result<(), std::io::Error>> {
+        if !self.next_group(1) {
+            return None
+        }
         if self.open[0].is_err() {
-            return Some(Err(self.open.pop_front().unwrap().err().unwrap()))
+            self.delivered = 0;
+            return Some(Err(self.open.pop_front().unwrap().err().unwrap().1))
+        }
+        Some(Ok(()))
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<Result<Reader<'_, Src>, std::io::Error>> {
+        match self.next_entry()? {
+            Ok(()) => Some(Ok(Reader::new(self, 0).expect("expect that next_entry only leaves successfully opened files at the front"))),
+            Err(e) => Some(Err(e))
         }
-        Some(Ok(Reader{owner: self}))
     }
 }
+
+// compile-time check of the thread-safety promised above
+#[allow(dead_code)]
+fn assert_send() {
+    fn send<T: Send>() {}
+    fn sync<T: Sync>() {}
+    type Src = std::vec::IntoIter<PathBuf>;
+    send::<MultiFileReadahead<Src>>();
+    send::<Reader<'static, Src>>();
+    send::<Pair<'static, Src>>();
+    send::<Bundle<'static, Src>>();
+    send::<Pieces<Src>>();
+    send::<Records<Src>>();
+    send::<Concat<Src>>();
+    send::<MultiFileCopy<Src>>();
+    #[cfg(feature = "cdc")]
+    send::<Chunks<Src>>();
+    send::<ShuffledRecords>();
+    send::<StatAhead<Src>>();
+    send::<WriteBehind>();
+    send::<WorkerFile>();
+    send::<PlannedFile>();
+    send::<RegionReadahead<'static>>();
+    sync::<PlannedFile>();
+    sync::<SharedBudget>();
+    sync::<MemoryFs>();
+    #[cfg(feature = "futures-io")]
+    send::<AsyncReadahead>();
+}
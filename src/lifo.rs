@@ -0,0 +1,39 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::path::PathBuf;
+use MultiFileReadahead;
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Delivers the most recently produced source entries first, e.g. for depth-first producers
+    /// whose latest discoveries are hottest in the dentry cache.
+    ///
+    /// Up to `depth` entries are pulled from the source onto a stack and the next file is opened
+    /// from its top. Opened files keep their order, so the prefetch planner, which follows the
+    /// opened files, also works in delivery order. `None` goes back to the source order once the
+    /// stack is drained.
+    pub fn lifo(&mut self, depth: Option<usize>) {
+        if depth.is_some_and(|d| d > 0) {
+            self.reject_paired("lifo order");
+        }
+        self.lifo = depth.unwrap_or(0);
+    }
+
+    pub(crate) fn next_stacked(&mut self) -> Option<PathBuf> {
+        while self.stack.len() < self.lifo {
+            match self.next_pulled() {
+                Some(p) => self.stack.push(p),
+                None => break
+            }
+        }
+        match self.stack.pop() {
+            Some(p) => Some(p),
+            None => self.next_pulled()
+        }
+    }
+}
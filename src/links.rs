@@ -0,0 +1,57 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::fs::Metadata;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use MultiFileReadahead;
+
+/// How files reached through more than one hardlink are handled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hardlinks {
+    /// Every path is read, no tracking
+    Read,
+    /// Later links to an already queued file are dropped from the queue
+    Skip,
+    /// Later links are still delivered but not prefetched,
+    /// [`Reader::hardlink_of`](struct.Reader.html#method.hardlink_of) names the first link
+    Flag,
+}
+
+pub(crate) enum Link {
+    First,
+    Skip,
+    Duplicate(PathBuf),
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Tracks `(device, inode)` of files with multiple links. Defaults to `Hardlinks::Read`.
+    pub fn hardlinks(&mut self, mode: Hardlinks) {
+        if mode == Hardlinks::Skip {
+            self.reject_paired("skipping hardlinks");
+        }
+        self.hardlinks = mode;
+        if mode == Hardlinks::Read {
+            self.links.clear();
+        }
+    }
+
+    pub(crate) fn check_link(&mut self, meta: &Metadata, p: &Path) -> Link {
+        if self.hardlinks == Hardlinks::Read || meta.nlink() < 2 {
+            return Link::First
+        }
+        let first = self.links.entry((meta.dev(), meta.ino())).or_insert_with(|| p.to_owned());
+        if first == p {
+            return Link::First
+        }
+        match self.hardlinks {
+            Hardlinks::Skip => Link::Skip,
+            _ => Link::Duplicate(first.clone())
+        }
+    }
+}
@@ -0,0 +1,106 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use {DEFAULT_BUDGET, DROPBEHIND_BLOCK};
+
+fn page_size() -> usize {
+    match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        n if n > 0 => n as usize,
+        _ => 4096
+    }
+}
+
+/// Windowed readahead for a memory mapping owned by the caller, e.g. a parser that already maps its input.
+///
+/// As for files mapped by the queue the window is kept ahead of a consumption cursor which the
+/// caller moves forward with [`advance_to`](#method.advance_to), but through `madvise` on the region.
+pub struct RegionReadahead<'a> {
+    region: &'a [u8],
+    window: u64,
+    cursor: u64,
+    advised: u64,
+    dropped: u64,
+    dropbehind: bool,
+}
+
+impl<'a> RegionReadahead<'a> {
+
+    pub fn new(region: &'a [u8]) -> Self {
+        let mut r = RegionReadahead {region, window: DEFAULT_BUDGET, cursor: 0, advised: 0, dropped: 0, dropbehind: false};
+        r.advise(0, region.len() as u64, libc::MADV_SEQUENTIAL, false);
+        r.top_up();
+        r
+    }
+
+    /// How far ahead of the cursor to advise, defaults to 8MiB.
+    pub fn window(&mut self, bytes: u64) {
+        self.window = std::cmp::max(bytes, DROPBEHIND_BLOCK);
+        self.top_up();
+    }
+
+    /// Discards the pages behind the cursor from the mapping with `MADV_DONTNEED`.
+    ///
+    /// # Safety
+    ///
+    /// The region must be a shared or never modified mapping of a file. Discarded pages of private
+    /// mappings revert to the file contents or zeroes, which changes memory behind the shared borrow.
+    pub unsafe fn dropbehind(&mut self, v: bool) {
+        self.dropbehind = v;
+    }
+
+    pub fn cursor(&self) -> u64 {
+        self.cursor
+    }
+
+    /// Marks everything up to `pos` as consumed. Moving the cursor backwards has no effect.
+    pub fn advance_to(&mut self, pos: u64) {
+        let pos = std::cmp::min(pos, self.region.len() as u64);
+        if pos <= self.cursor {
+            return
+        }
+        self.cursor = pos;
+        if self.dropbehind && self.cursor - self.dropped >= DROPBEHIND_BLOCK {
+            let (from, to) = (self.dropped, self.cursor);
+            self.dropped = self.advise(from, to - from, libc::MADV_DONTNEED, true);
+        }
+        self.top_up();
+    }
+
+    // advises the next part of the window once less than half of it is left
+    fn top_up(&mut self) {
+        let len = self.region.len() as u64;
+        let start = std::cmp::max(self.advised, self.cursor);
+        if start >= len || start - self.cursor > self.window / 2 {
+            return
+        }
+        let end = std::cmp::min(self.cursor + self.window, len);
+        self.advise(start, end - start, libc::MADV_WILLNEED, false);
+        self.advised = end;
+    }
+
+    // madvise wants page aligned ranges. Destructive advice is narrowed to whole pages within the
+    // range so that it can't touch memory outside of it. Returns the end of the advised range
+    fn advise(&self, offset: u64, len: u64, advice: libc::c_int, inward: bool) -> u64 {
+        let page = page_size();
+        let base = self.region.as_ptr() as usize;
+        let (mut start, mut end) = (base + offset as usize, base + (offset + len) as usize);
+        if inward {
+            start = start.div_ceil(page) * page;
+            end &= !(page - 1);
+        } else {
+            start &= !(page - 1);
+            end = end.div_ceil(page) * page;
+        }
+        if start >= end {
+            return offset
+        }
+        unsafe {
+            libc::madvise(start as *mut libc::c_void, end - start, advice);
+        }
+        std::cmp::min((end - base) as u64, self.region.len() as u64)
+    }
+}
@@ -0,0 +1,85 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use memmap2::{Advice, Mmap, UncheckedAdvice};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use MultiFileReadahead;
+
+/// A file of the queue mapped into memory.
+///
+/// Readahead is scheduled relative to a consumption cursor which the caller moves forward
+/// with [`advance_to`](#method.advance_to). With dropbehind enabled the pages behind the cursor
+/// are unmapped and dropped from the page cache.
+pub struct MappedFile<'a, T: 'a> {
+    owner: &'a mut MultiFileReadahead<T>,
+    map: Mmap,
+}
+
+impl<'a, T> MappedFile<'a, T> where T: Iterator<Item=PathBuf> {
+
+    pub fn path(&self) -> &Path {
+        &self.owner.open[0].as_ref().expect("expect that mappings are only created for successfully opened files").info.path
+    }
+
+    pub fn map(&self) -> &Mmap {
+        &self.map
+    }
+
+    pub fn cursor(&self) -> u64 {
+        self.owner.open[0].as_ref().expect("expect that mappings are only created for successfully opened files").read_pos
+    }
+
+    /// Marks everything up to `pos` as consumed. Moving the cursor backwards has no effect.
+    pub fn advance_to(&mut self, pos: u64) {
+        {
+            let drop = self.owner.dropbehind;
+            let fetch = self.owner.open[0].as_mut().expect("expect that mappings are only created for successfully opened files");
+            let pos = std::cmp::min(pos, self.map.len() as u64);
+            if pos <= fetch.read_pos {
+                return
+            }
+            let bytes = pos - fetch.read_pos;
+            self.owner.hooks.read(fetch.id, bytes);
+            if let Some((offset, len)) = fetch.consume(bytes, drop) {
+                // pages still mapped by us would survive the fadvise
+                unsafe {
+                    let _ = self.map.unchecked_advise_range(UncheckedAdvice::DontNeed, offset as usize, len as usize);
+                }
+                fetch.drop_range(offset, len, &mut self.owner.hooks);
+            }
+        }
+        self.owner.advance();
+    }
+}
+
+impl<'a, T> Deref for MappedFile<'a, T> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.map
+    }
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Like [`next`](#method.next) but maps the file into memory instead of returning a `Read`er.
+    pub fn next_mmap(&mut self) -> Option<Result<MappedFile<'_, Src>, std::io::Error>> {
+        if let Err(e) = self.next_entry()? {
+            return Some(Err(e))
+        }
+        let map = {
+            let fetch = self.open[0].as_ref().expect("expect that next_entry only leaves successfully opened files at the front");
+            match unsafe { Mmap::map(&fetch.f) } {
+                Ok(m) => m,
+                Err(e) => return Some(Err(e))
+            }
+        };
+        let _ = map.advise(Advice::Sequential);
+        Some(Ok(MappedFile{owner: self, map}))
+    }
+}
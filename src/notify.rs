@@ -0,0 +1,240 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use parallel::{Event, Job, WorkerFile};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{Error, Read, Write};
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use {sys, MultiFileReadahead, PREFETCH_BLOCK};
+
+struct State {
+    ready: VecDeque<Result<ReadyFile, Error>>,
+    done: bool,
+    dropped: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    // write end of the notification descriptor
+    signal: File,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // only called with the lock held so that it's ordered with clearing
+    fn signal(&self) {
+        let _ = (&self.signal).write(&1u64.to_ne_bytes());
+    }
+}
+
+// marks the planner as done even if it panics so that the consumer doesn't wait forever
+struct DoneGuard(Arc<Shared>);
+
+impl Drop for DoneGuard {
+    fn drop(&mut self) {
+        let mut s = self.0.lock();
+        s.done = true;
+        self.0.signal();
+    }
+}
+
+/// A file handed out by a [`ReadyQueue`](struct.ReadyQueue.html) whose first block was found in the page cache.
+pub struct ReadyFile {
+    inner: WorkerFile,
+}
+
+impl ReadyFile {
+
+    pub fn path(&self) -> &Path {
+        self.inner.path()
+    }
+
+    pub fn len(&self) -> u64 {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+}
+
+impl Read for ReadyFile {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.inner.read(buf)
+    }
+}
+
+impl Drop for ReadyFile {
+    fn drop(&mut self) {
+        self.inner.finish();
+        self.inner.done();
+    }
+}
+
+/// Readiness-based access to the queue for event loops, obtained from
+/// [`into_ready_queue`](struct.MultiFileReadahead.html#method.into_ready_queue).
+///
+/// The descriptor returned by `as_raw_fd` polls readable while files are ready or once the queue
+/// is exhausted, register it with epoll, kqueue or the event loop's equivalent and call
+/// [`try_next`](#method.try_next) when it fires. It's an eventfd on Linux and a pipe elsewhere.
+pub struct ReadyQueue {
+    shared: Arc<Shared>,
+    fd: File,
+    events: Sender<Event>,
+}
+
+impl ReadyQueue {
+
+    /// The next ready file or open error, `None` if nothing is ready yet or the queue is exhausted.
+    pub fn try_next(&mut self) -> Option<Result<ReadyFile, Error>> {
+        let mut s = self.shared.lock();
+        let next = s.ready.pop_front();
+        if s.ready.is_empty() && !s.done {
+            // clear the signal, the planner sets it again with the next file
+            let mut buf = [0u8; 64];
+            while let Ok(n) = (&self.fd).read(&mut buf) {
+                if n == 0 {
+                    break
+                }
+            }
+        }
+        if next.is_some() {
+            let _ = self.events.send(Event::Progress);
+        }
+        next
+    }
+
+    /// Whether the queue is exhausted and all files have been handed out.
+    pub fn is_finished(&self) -> bool {
+        let s = self.shared.lock();
+        s.done && s.ready.is_empty()
+    }
+}
+
+impl AsRawFd for ReadyQueue {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsFd for ReadyQueue {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+impl Drop for ReadyQueue {
+    fn drop(&mut self) {
+        self.shared.lock().dropped = true;
+        let _ = self.events.send(Event::Progress);
+    }
+}
+
+impl<Src: Iterator<Item=PathBuf> + Send + 'static> MultiFileReadahead<Src> {
+
+    /// Moves the queue to a planner thread that hands out files through a pollable
+    /// [`ReadyQueue`](struct.ReadyQueue.html) once their first block is cached, so that event loops
+    /// can use it without blocking on the disk or dedicating a thread to it.
+    ///
+    /// At most `depth` files are handed out or waiting for their first block at a time, a file
+    /// counts until its `ReadyFile` is dropped. Files whose first block isn't cached after `max_wait`
+    /// are handed out anyway, e.g. those not advised because of a prefetch plan.
+    pub fn into_ready_queue(mut self, depth: usize, max_wait: Duration) -> Result<ReadyQueue, Error> {
+        let depth = std::cmp::max(depth, 1);
+        let (fd, signal) = sys::notification_fd()?;
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {ready: VecDeque::new(), done: false, dropped: false}),
+            signal,
+        });
+        let (events_tx, events) = channel();
+        let queue = ReadyQueue {shared: shared.clone(), fd, events: events_tx.clone()};
+        let dropbehind = self.dropbehind;
+
+        thread::spawn(move || {
+            let _guard = DoneGuard(shared.clone());
+            let mut waiting: VecDeque<(Job, Instant)> = VecDeque::new();
+            let mut handed_out = 0;
+            let mut exhausted = false;
+            loop {
+                while !exhausted && handed_out + waiting.len() < depth {
+                    match self.next_job() {
+                        Some(job) => waiting.push_back((job, Instant::now())),
+                        None => exhausted = true
+                    }
+                }
+                self.advance();
+
+                // files are handed out in order, the first one holds back the others
+                while let Some(&(ref job, since)) = waiting.front() {
+                    let ready = match job.file {
+                        Ok((_, ref f, len)) => since.elapsed() >= max_wait || sys::is_resident(f, std::cmp::min(len, PREFETCH_BLOCK)).unwrap_or(true),
+                        Err(_) => true
+                    };
+                    if !ready {
+                        break
+                    }
+                    let (job, _) = waiting.pop_front().unwrap();
+                    let file = match job.file {
+                        Ok((path, f, len)) => {
+                            handed_out += 1;
+                            Ok(ReadyFile {inner: WorkerFile::new(path, f, len, job.cursor.unwrap(), dropbehind, job.drop_block, events_tx.clone())})
+                        }
+                        Err((_, e)) => Err(e)
+                    };
+                    let mut s = shared.lock();
+                    if s.dropped {
+                        return
+                    }
+                    s.ready.push_back(file);
+                    shared.signal();
+                }
+
+                // nothing left to prefetch, the handed out files don't need to be retired
+                if exhausted && waiting.is_empty() {
+                    return
+                }
+                if shared.lock().dropped {
+                    return
+                }
+
+                // poll the residency of the next file while waiting for the consumer
+                let event = if waiting.is_empty() {
+                    events.recv().map_err(|_| RecvTimeoutError::Disconnected)
+                } else {
+                    events.recv_timeout(Duration::from_millis(1))
+                };
+                let mut event = match event {
+                    Ok(e) => e,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => return
+                };
+                loop {
+                    if let Event::Done(cursor) = event {
+                        handed_out -= 1;
+                        if let Some(c) = cursor {
+                            self.retire(&c);
+                        }
+                    }
+                    event = match events.try_recv() {
+                        Ok(e) => e,
+                        Err(_) => break
+                    };
+                }
+            }
+        });
+        Ok(queue)
+    }
+}
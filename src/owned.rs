@@ -0,0 +1,130 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use parallel::{Event, WorkerFile};
+use std::io::{Error, Read};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use MultiFileReadahead;
+
+struct State<Src> {
+    queue: MultiFileReadahead<Src>,
+    events: Receiver<Event>,
+}
+
+impl<Src: Iterator<Item=PathBuf>> State<Src> {
+    // retires finished files and tops up the prefetch
+    fn pump(&mut self) {
+        while let Ok(event) = self.events.try_recv() {
+            if let Event::Done(Some(c)) = event {
+                self.queue.retire(&c);
+            }
+        }
+        self.queue.advance();
+    }
+}
+
+struct Shared<Src> {
+    state: Mutex<State<Src>>,
+    events: Sender<Event>,
+}
+
+impl<Src> Shared<Src> {
+    fn lock(&self) -> MutexGuard<'_, State<Src>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A queue whose files can be held and read by several threads at once, obtained from
+/// [`into_shared`](struct.MultiFileReadahead.html#method.into_shared).
+///
+/// Clones refer to the same queue. Each call to `next` hands out the following file as an
+/// [`OwnedReader`](struct.OwnedReader.html) that doesn't borrow the queue, while the planner keeps
+/// prefetching the files behind it whenever a reader makes progress or the next file is taken.
+pub struct SharedQueue<Src> {
+    shared: Arc<Shared<Src>>,
+}
+
+impl<Src> Clone for SharedQueue<Src> {
+    fn clone(&self) -> Self {
+        SharedQueue {shared: self.shared.clone()}
+    }
+}
+
+impl<Src: Iterator<Item=PathBuf>> SharedQueue<Src> {
+
+    /// The next file, or its open error. Files are handed out in queue order, also across threads.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&self) -> Option<Result<OwnedReader<Src>, Error>> {
+        let mut s = self.shared.lock();
+        s.pump();
+        let job = s.queue.next_job()?;
+        let dropbehind = s.queue.dropbehind;
+        let result = match job.file {
+            Ok((path, f, len)) => {
+                let cursor = job.cursor.expect("expect that jobs for opened files carry a cursor");
+                Ok(OwnedReader {inner: WorkerFile::new(path, f, len, cursor, dropbehind, job.drop_block, self.shared.events.clone()), shared: self.shared.clone()})
+            }
+            Err((_, e)) => Err(e)
+        };
+        s.queue.advance();
+        Some(result)
+    }
+}
+
+/// A file handed out by a [`SharedQueue`](struct.SharedQueue.html), can be sent to another thread.
+///
+/// Dropping it retires the file from the queue, which frees its part of the budget.
+pub struct OwnedReader<Src: Iterator<Item=PathBuf>> {
+    inner: WorkerFile,
+    shared: Arc<Shared<Src>>,
+}
+
+impl<Src: Iterator<Item=PathBuf>> OwnedReader<Src> {
+
+    pub fn path(&self) -> &Path {
+        self.inner.path()
+    }
+
+    pub fn len(&self) -> u64 {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+}
+
+impl<Src: Iterator<Item=PathBuf>> Read for OwnedReader<Src> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let n = self.inner.read(buf)?;
+        // if another thread holds the queue it plans for this read as well
+        if let Ok(mut s) = self.shared.state.try_lock() {
+            s.pump();
+        }
+        Ok(n)
+    }
+}
+
+impl<Src: Iterator<Item=PathBuf>> Drop for OwnedReader<Src> {
+    fn drop(&mut self) {
+        self.inner.finish();
+        self.inner.done();
+        self.shared.lock().pump();
+    }
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Turns the queue into a [`SharedQueue`](struct.SharedQueue.html) whose files can be read
+    /// concurrently by different threads, all under the single budget of this queue.
+    pub fn into_shared(self) -> SharedQueue<Src> {
+        let (tx, rx) = channel();
+        SharedQueue {shared: Arc::new(Shared {state: Mutex::new(State {queue: self, events: rx}), events: tx})}
+    }
+}
@@ -0,0 +1,86 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::io::Error;
+use std::path::PathBuf;
+use {MultiFileReadahead, Reader};
+
+/// Source alternating between two path lists, ends as soon as either of them does.
+pub struct Paired<A, B> {
+    left: A,
+    right: B,
+    pending: Option<PathBuf>,
+}
+
+impl<A, B> Iterator for Paired<A, B> where A: Iterator<Item=PathBuf>, B: Iterator<Item=PathBuf> {
+    type Item = PathBuf;
+
+    fn next(&mut self) -> Option<PathBuf> {
+        if let Some(p) = self.pending.take() {
+            return Some(p)
+        }
+        match (self.left.next(), self.right.next()) {
+            (Some(l), Some(r)) => {
+                self.pending = Some(r);
+                Some(l)
+            }
+            _ => None
+        }
+    }
+}
+
+/// Two files read in lockstep, obtained from [`next_pair`](struct.MultiFileReadahead.html#method.next_pair).
+pub struct Pair<'a, T: 'a> {
+    owner: &'a mut MultiFileReadahead<T>
+}
+
+impl<'a, T> Pair<'a, T> where T: Iterator<Item=PathBuf> {
+
+    pub fn left(&mut self) -> Result<Reader<'_, T>, &Error> {
+        self.side(0)
+    }
+
+    pub fn right(&mut self) -> Result<Reader<'_, T>, &Error> {
+        self.side(1)
+    }
+
+    fn side(&mut self, idx: usize) -> Result<Reader<'_, T>, &Error> {
+        if self.owner.open[idx].is_err() {
+            return Err(&self.owner.open[idx].as_ref().err().unwrap().1)
+        }
+        Ok(Reader::new(self.owner, idx).expect("expect that the entry was checked to be open"))
+    }
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+    pub(crate) fn reject_paired(&self, option: &str) {
+        assert!(!self.paired, "{} would misalign the files of paired lists", option);
+    }
+}
+
+impl<A, B> MultiFileReadahead<Paired<A, B>> where A: Iterator<Item=PathBuf>, B: Iterator<Item=PathBuf> {
+
+    /// Reads two path lists in lockstep, e.g. for comparing two directory trees.
+    ///
+    /// Both sides share a single budget. Prefetch alternates between them so that neither side
+    /// can starve the other.
+    ///
+    /// Pairs are formed by position, so options that would drop, add or reorder entries panic when set.
+    pub fn paired(left: A, right: B) -> Self {
+        let mut q = MultiFileReadahead::new(Paired{left, right, pending: None});
+        q.group = 2;
+        q.paired = true;
+        q
+    }
+
+    pub fn next_pair(&mut self) -> Option<Pair<'_, Paired<A, B>>> {
+        if !self.next_group(2) {
+            return None
+        }
+        Some(Pair{owner: self})
+    }
+}
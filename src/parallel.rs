@@ -0,0 +1,294 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::fs::File;
+use std::io::{Error, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use sys;
+use {drop_behind, MultiFileReadahead, DROPBEHIND_BLOCK};
+
+pub(crate) enum Event {
+    // a worker read enough that the planner should top up the prefetch
+    Progress,
+    Done(Option<Arc<AtomicU64>>),
+    // the user callback panicked, the worker won't take further files
+    Panicked,
+}
+
+pub(crate) struct Job {
+    pub(crate) file: Result<(PathBuf, File, u64), (PathBuf, Error)>,
+    pub(crate) cursor: Option<Arc<AtomicU64>>,
+    // size of the blocks dropped behind the reader
+    pub(crate) drop_block: u64,
+}
+
+/// A queued file being read on a worker thread.
+pub struct WorkerFile {
+    path: PathBuf,
+    f: File,
+    length: u64,
+    read_pos: u64,
+    cursor: Arc<AtomicU64>,
+    dropbehind: bool,
+    drop_block: u64,
+    to_drop: u64,
+    unreported: u64,
+    events: Sender<Event>,
+}
+
+impl WorkerFile {
+
+    pub(crate) fn new(path: PathBuf, f: File, length: u64, cursor: Arc<AtomicU64>, dropbehind: bool, drop_block: u64, events: Sender<Event>) -> Self {
+        WorkerFile {path, f, length, read_pos: 0, cursor, dropbehind, drop_block, to_drop: 0, unreported: 0, events}
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    // tells the planner that the file may be retired, for files outliving the worker loop
+    pub(crate) fn done(&self) {
+        let _ = self.events.send(Event::Done(Some(self.cursor.clone())));
+    }
+
+    pub(crate) fn finish(&self) {
+        if self.dropbehind && self.read_pos > 0 {
+            sys::advise_dontneed(&self.f, 0, 0);
+        }
+    }
+}
+
+impl Read for WorkerFile {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let bytes = self.f.read(buf)? as u64;
+        self.read_pos += bytes;
+        self.cursor.store(self.read_pos, Ordering::Relaxed);
+        if self.dropbehind {
+            self.to_drop += bytes;
+            if let Some((offset, len)) = drop_behind(self.read_pos, &mut self.to_drop, self.drop_block) {
+                sys::advise_dontneed(&self.f, offset, len);
+            }
+        }
+        self.unreported += bytes;
+        if self.unreported >= DROPBEHIND_BLOCK {
+            self.unreported = 0;
+            let _ = self.events.send(Event::Progress);
+        }
+        Ok(bytes as usize)
+    }
+}
+
+// reports completion, or the panic of the user callback so that the planner stops handing out files
+// instead of waiting for the worker forever
+pub(crate) struct DoneGuard<'a> {
+    pub(crate) events: &'a Sender<Event>,
+    pub(crate) cursor: Option<Arc<AtomicU64>>,
+}
+
+impl<'a> Drop for DoneGuard<'a> {
+    fn drop(&mut self) {
+        let event = if thread::panicking() {
+            Event::Panicked
+        } else {
+            Event::Done(self.cursor.take())
+        };
+        let _ = self.events.send(event);
+    }
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Reads the queued files concurrently on `threads` worker threads.
+    ///
+    /// The calling thread keeps planning prefetch for all files under the single budget while each
+    /// worker calls `f` with one file at a time. Files are handed out in source order but may complete in any order.
+    pub fn for_each_parallel<F>(mut self, threads: usize, f: F) where F: Fn(Result<&mut WorkerFile, Error>) + Sync {
+        let threads = std::cmp::max(threads, 1);
+        let (events_tx, events) = channel();
+        // rendezvous channel, a file is only handed out once a worker is ready for it
+        let (jobs_tx, jobs) = sync_channel::<Job>(0);
+        let jobs = Mutex::new(jobs);
+        let dropbehind = self.dropbehind;
+        #[cfg(target_os = "linux")]
+        let prio = self.io_priority.map(|(p, _)| p);
+
+        thread::scope(|scope| {
+            for _ in 0..threads {
+                let events_tx = events_tx.clone();
+                let jobs = &jobs;
+                let f = &f;
+                scope.spawn(move || {
+                    #[cfg(target_os = "linux")]
+                    {
+                        if let Some(p) = prio {
+                            let _ = ::set_thread_io_priority(p);
+                        }
+                    }
+                    loop {
+                        let job = match jobs.lock().map(|rx| rx.recv()) {
+                            Ok(Ok(job)) => job,
+                            _ => return
+                        };
+                        let _guard = DoneGuard {events: &events_tx, cursor: job.cursor.clone()};
+                        match job.file {
+                            Ok((path, file, len)) => {
+                                let mut wf = WorkerFile::new(path, file, len, job.cursor.unwrap(), dropbehind, job.drop_block, events_tx.clone());
+                                f(Ok(&mut wf));
+                                wf.finish();
+                            }
+                            Err((_, e)) => f(Err(e))
+                        }
+                    }
+                });
+            }
+            drop(events_tx);
+            self.plan_parallel(threads, |job| jobs_tx.send(job).is_ok(), events);
+            // lets the idle workers exit, the scope waits for them
+            drop(jobs_tx);
+        });
+    }
+
+    // hands out up to `threads` files at a time to `dispatch` until the queue runs dry or dispatch fails
+    pub(crate) fn plan_parallel<D: FnMut(Job) -> bool>(&mut self, threads: usize, mut dispatch: D, events: Receiver<Event>) {
+        let mut in_flight = 0;
+        loop {
+            while in_flight < threads {
+                let job = match self.next_job() {
+                    Some(j) => j,
+                    None => break
+                };
+                if !dispatch(job) {
+                    return
+                }
+                in_flight += 1;
+            }
+            if in_flight == 0 {
+                return
+            }
+            self.advance();
+
+            let mut event = match events.recv() {
+                Ok(e) => e,
+                Err(_) => return
+            };
+            loop {
+                match event {
+                    Event::Done(cursor) => {
+                        in_flight -= 1;
+                        if let Some(c) = cursor {
+                            self.retire(&c);
+                        }
+                    }
+                    // returning lets the remaining workers exit so that the panic propagates
+                    Event::Panicked => return,
+                    Event::Progress => {}
+                }
+                event = match events.try_recv() {
+                    Ok(e) => e,
+                    Err(_) => break
+                };
+            }
+        }
+    }
+
+    // hands out the next file to be read outside of the queue
+    pub(crate) fn next_job(&mut self) -> Option<Job> {
+        if self.open.len() <= self.delivered && !self.add_file() {
+            return None
+        }
+        let idx = self.delivered;
+        if self.open[idx].is_err() {
+            let failed = self.open.remove(idx).unwrap().err().unwrap();
+            return Some(Job {file: Err(failed), cursor: None, drop_block: DROPBEHIND_BLOCK})
+        }
+        let cursor = Arc::new(AtomicU64::new(0));
+        let p = self.open[idx].as_mut().unwrap();
+        match p.f.try_clone() {
+            Ok(f) => {
+                p.shared = Some(cursor.clone());
+                let file = (p.info.path.clone(), f, p.length);
+                self.delivered += 1;
+                self.hooks.files_delivered += 1;
+                Some(Job {file: Ok(file), cursor: Some(cursor), drop_block: p.drop_block})
+            }
+            Err(e) => {
+                let path = p.info.path.clone();
+                self.hooks.closed(p.id);
+                self.open.remove(idx);
+                self.regrow_open_limit();
+                Some(Job {file: Err((path, e)), cursor: None, drop_block: DROPBEHIND_BLOCK})
+            }
+        }
+    }
+
+    // removes a file handed out by next_job once it has been read
+    pub(crate) fn retire(&mut self, cursor: &Arc<AtomicU64>) {
+        let pos = self.open.iter().take(self.delivered).position(|o| match *o {
+            Ok(ref p) => p.shared.as_ref().is_some_and(|c| Arc::ptr_eq(c, cursor)),
+            Err(_) => false
+        });
+        if let Some(pos) = pos {
+            if let Some(Ok(p)) = self.open.remove(pos) {
+                self.hooks.closed(p.id);
+            }
+            self.delivered -= 1;
+            self.regrow_open_limit();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use super::*;
+    use testutil::Files;
+
+    #[test]
+    fn every_file_is_read_once() {
+        let files = Files::new(&[("/a", &[1; 100_000]), ("/b", b""), ("/c", b"c"), ("/d", &[4; 3000])]);
+        let read = Mutex::new(Vec::new());
+        files.queue(&["/a", "/b", "/missing", "/c", "/d"]).for_each_parallel(2, |f| {
+            let entry = match f {
+                Ok(f) => {
+                    let mut data = Vec::new();
+                    f.read_to_end(&mut data).unwrap();
+                    Ok((f.path().to_owned(), data))
+                }
+                Err(e) => Err(e.kind())
+            };
+            read.lock().unwrap().push(entry);
+        });
+        let mut read = read.into_inner().unwrap();
+        read.sort_by_key(|r| r.as_ref().ok().cloned());
+        assert_eq!(read, vec![
+            Err(std::io::ErrorKind::NotFound),
+            Ok((files.path("/a"), vec![1; 100_000])),
+            Ok((files.path("/b"), Vec::new())),
+            Ok((files.path("/c"), b"c".to_vec())),
+            Ok((files.path("/d"), vec![4; 3000])),
+        ]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn panicking_callback_propagates() {
+        let files = Files::new(&[("/a", b"a"), ("/b", b"b"), ("/c", b"c")]);
+        files.queue(&["/a", "/b", "/c"]).for_each_parallel(1, |_| panic!("callback failed"));
+    }
+}
@@ -0,0 +1,43 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::path::PathBuf;
+use MultiFileReadahead;
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Stops issuing new advice until [`unpause`](#method.unpause), e.g. to yield I/O to
+    /// higher-priority work. The queue can still be read, it just no longer prefetches.
+    ///
+    /// With `drop` the prefetched but not yet read data of all open files is dropped from the
+    /// cache as well, to also yield memory. It is advised again once unpaused.
+    pub fn pause(&mut self, drop: bool) {
+        self.paused = true;
+        if !drop {
+            return
+        }
+        for o in self.open.iter_mut() {
+            if let Ok(ref mut p) = *o {
+                p.expire(&mut self.hooks);
+            }
+        }
+        self.hooks.flush();
+        let buffered = self.buffer_bytes();
+        if let Some(ref mut s) = self.shared_budget {
+            s.report(buffered);
+        }
+    }
+
+    pub fn unpause(&mut self) {
+        self.paused = false;
+        self.advance();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+}
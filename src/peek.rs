@@ -0,0 +1,74 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use Reader;
+
+impl<'a, T> Reader<'a, T> where T: Iterator<Item=PathBuf> {
+
+    /// Returns up to `n` bytes following the current position without consuming them, i.e. the head
+    /// of the file if nothing was read yet. Fewer bytes are only returned at the end of the file.
+    ///
+    /// The bytes are buffered and handed out again by subsequent reads, so format sniffing
+    /// doesn't take anything away from the actual consumer. Until then they count against the prefetch budget.
+    pub fn peek_head(&mut self, n: usize) -> Result<&[u8], Error> {
+        let idx = self.idx;
+        let mut head = {
+            let fetch = self.owner.open[idx].as_mut().expect("expect that readers are only created for successfully opened files");
+            fetch.head.drain(..fetch.head_pos);
+            fetch.head_pos = 0;
+            std::mem::take(&mut fetch.head)
+        };
+        // with the buffer taken out read_once goes straight to the file
+        let mut result = Ok(());
+        while head.len() < n {
+            let filled = head.len();
+            head.resize(n, 0);
+            match self.owner.read_once(idx, &mut head[filled..]) {
+                Ok(bytes) => {
+                    head.truncate(filled + bytes);
+                    if bytes == 0 {
+                        break
+                    }
+                }
+                Err(e) => {
+                    head.truncate(filled);
+                    if e.kind() != ErrorKind::Interrupted {
+                        result = Err(e);
+                        break
+                    }
+                }
+            }
+        }
+        self.owner.advance();
+        let fetch = self.owner.open[idx].as_mut().expect("expect that readers are only created for successfully opened files");
+        fetch.head = head;
+        result?;
+        let len = std::cmp::min(n, fetch.head.len());
+        Ok(&fetch.head[..len])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use testutil::{read_front, Files};
+
+    #[test]
+    fn peeked_bytes_are_read_again() {
+        let files = Files::new(&[("/a", b"hello world")]);
+        let mut q = files.queue(&["/a"]);
+        {
+            let mut r = q.next().unwrap().unwrap();
+            assert_eq!(r.peek_head(5).unwrap(), b"hello");
+            assert_eq!(r.peek_head(2).unwrap(), b"he");
+            // fewer bytes at the end of the file
+            assert_eq!(r.peek_head(100).unwrap(), b"hello world");
+        }
+        assert_eq!(read_front(&mut q), b"hello world");
+    }
+}
@@ -0,0 +1,208 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use MultiFileReadahead;
+
+/// A byte range of a file, e.g. the part that contributed to a piece.
+#[derive(Debug, Clone)]
+pub struct Span {
+    pub path: PathBuf,
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// A fixed-size piece of the concatenation of all files. Only the last piece may be shorter.
+pub struct Piece<'a> {
+    pub index: u64,
+    pub data: &'a [u8],
+    pub spans: &'a [Span],
+}
+
+/// Reads the queue as one logical stream cut into fixed-size pieces, e.g. for torrent-style hashing.
+///
+/// Prefetch windows are aligned to piece boundaries of the stream rather than to file offsets
+/// and the piece buffer counts against the prefetch budget.
+/// Files that fail to open or read are reported as errors and the stream continues after them,
+/// which shifts all subsequent piece boundaries.
+pub struct Pieces<Src> {
+    inner: MultiFileReadahead<Src>,
+    piece: usize,
+    index: u64,
+    buf: Vec<u8>,
+    spans: Vec<Span>,
+    // whether the front of the queue is the file currently being read
+    active: bool,
+    // whether the current file already has a span in the current piece
+    spanned: bool,
+    // whether the piece in buf has been handed out
+    returned: bool,
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Cuts the queue into pieces of `piece_len` bytes.
+    ///
+    /// The filled part of the current piece counts against the budget, so pieces close to the
+    /// budget in size leave little room for prefetch towards their end.
+    pub fn pieces(mut self, piece_len: usize) -> Pieces<Src> {
+        assert!(piece_len > 0, "pieces must not be empty");
+        self.piece = piece_len as u64;
+        Pieces {inner: self, piece: piece_len, index: 0, buf: Vec::with_capacity(piece_len), spans: Vec::new(), active: false, spanned: false, returned: false}
+    }
+}
+
+impl<Src: Iterator<Item=PathBuf>> Pieces<Src> {
+
+    pub fn next_piece(&mut self) -> Option<Result<Piece<'_>, Error>> {
+        if self.returned {
+            self.buf.clear();
+            self.spans.clear();
+            self.index += 1;
+            self.returned = false;
+            self.spanned = false;
+        }
+
+        while self.buf.len() < self.piece {
+            if !self.active {
+                match self.inner.next_entry() {
+                    None => break,
+                    Some(Err(e)) => return Some(Err(e)),
+                    Some(Ok(())) => {
+                        self.active = true;
+                        self.spanned = false;
+                    }
+                }
+            }
+            if !self.spanned {
+                let fetch = self.inner.open[0].as_ref().expect("expect that next_entry only leaves successfully opened files at the front");
+                self.spans.push(Span {path: fetch.info.path.clone(), offset: fetch.read_pos, len: 0});
+                self.spanned = true;
+            }
+
+            let filled = self.buf.len();
+            // only the part of the piece already read is held back from the consumer
+            self.inner.buffered = filled as u64;
+            self.buf.resize(self.piece, 0);
+            let result = self.inner.read_entry(0, &mut self.buf[filled..]);
+            let bytes = match result {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {
+                    self.buf.truncate(filled);
+                    continue
+                }
+                Err(_) => 0
+            };
+            self.buf.truncate(filled + bytes);
+            self.spans.last_mut().unwrap().len += bytes as u64;
+            if bytes == 0 {
+                self.active = false;
+                // files that end or fail right at a piece boundary don't contribute to it
+                if self.spans.last().unwrap().len == 0 {
+                    self.spans.pop();
+                }
+            }
+            if let Err(e) = result {
+                self.active = false;
+                return Some(Err(e))
+            }
+        }
+
+        if self.buf.is_empty() {
+            return None
+        }
+
+        self.returned = true;
+        Some(Ok(Piece {index: self.index, data: &self.buf, spans: &self.spans}))
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use testutil::Files;
+    use {Advice, DEFAULT_BUDGET};
+
+    fn spans(p: &Piece) -> Vec<(PathBuf, u64, u64)> {
+        p.spans.iter().map(|s| (s.path.clone(), s.offset, s.len)).collect()
+    }
+
+    #[test]
+    fn pieces_span_file_boundaries() {
+        let files = Files::new(&[("/a", b"01234"), ("/b", b""), ("/c", b"5678901")]);
+        let mut it = files.queue(&["/a", "/b", "/c"]).pieces(4);
+        let (a, c) = (files.path("/a"), files.path("/c"));
+        let mut expected = vec![
+            vec![(a.clone(), 0, 4)],
+            vec![(a, 4, 1), (c.clone(), 0, 3)],
+            vec![(c, 3, 4)],
+        ].into_iter();
+        let mut all = Vec::new();
+        let mut idx = 0;
+        while let Some(p) = it.next_piece() {
+            let p = p.unwrap();
+            assert_eq!(p.index, idx);
+            assert_eq!(spans(&p), expected.next().unwrap());
+            assert_eq!(p.data.len() as u64, p.spans.iter().map(|s| s.len).sum::<u64>());
+            all.extend_from_slice(p.data);
+            idx += 1;
+        }
+        assert!(expected.next().is_none());
+        assert_eq!(all, b"012345678901");
+    }
+
+    #[test]
+    fn file_ending_at_piece_boundary() {
+        let files = Files::new(&[("/a", b"0123"), ("/b", b"45")]);
+        let mut it = files.queue(&["/a", "/b"]).pieces(4);
+        let p = it.next_piece().unwrap().unwrap();
+        assert_eq!(spans(&p), vec![(files.path("/a"), 0, 4)]);
+        let p = it.next_piece().unwrap().unwrap();
+        assert_eq!(spans(&p), vec![(files.path("/b"), 0, 2)]);
+        assert_eq!(p.data, b"45");
+        assert!(it.next_piece().is_none());
+    }
+
+    #[test]
+    fn open_errors_shift_the_stream() {
+        let files = Files::new(&[("/a", b"012"), ("/c", b"345")]);
+        let mut it = files.queue(&["/a", "/b", "/c"]).pieces(4);
+        match it.next_piece() {
+            Some(Err(e)) => assert_eq!(e.kind(), ErrorKind::NotFound),
+            _ => panic!("expected the missing file's error first")
+        }
+        let p = it.next_piece().unwrap().unwrap();
+        assert_eq!(p.data, b"0123");
+        assert_eq!(spans(&p), vec![(files.path("/a"), 0, 3), (files.path("/c"), 0, 1)]);
+        let p = it.next_piece().unwrap().unwrap();
+        assert_eq!(p.data, b"45");
+        assert!(it.next_piece().is_none());
+    }
+
+    #[test]
+    fn large_pieces_leave_room_for_prefetch() {
+        let data = vec![5u8; 4 * DEFAULT_BUDGET as usize];
+        let files = Files::new(&[("/a", &data)]);
+        let mut q = files.queue(&["/a"]);
+        let advised = Arc::new(AtomicU64::new(0));
+        let log = advised.clone();
+        q.advise_with(Some(Box::new(move |_, _, len, advice| if advice == Advice::WillNeed {
+            log.fetch_add(len, Ordering::Relaxed);
+        })));
+        let mut it = q.pieces(DEFAULT_BUDGET as usize * 3 / 4);
+        let mut read = 0;
+        while let Some(p) = it.next_piece() {
+            read += p.unwrap().data.len();
+        }
+        assert_eq!(read, data.len());
+        assert_eq!(advised.load(Ordering::Relaxed), data.len() as u64);
+    }
+}
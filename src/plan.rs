@@ -0,0 +1,106 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use hooks::Hooks;
+use {MultiFileReadahead, Prefetch};
+
+/// What to prefetch of a file, returned by the callback installed with
+/// [`prefetch_plan`](struct.MultiFileReadahead.html#method.prefetch_plan).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PrefetchPlan {
+    /// The whole file from front to back
+    Sequential,
+    /// Only these `(offset, length)` ranges, advised in the given order
+    Ranges(Vec<(u64, u64)>),
+}
+
+/// Callback choosing the [`PrefetchPlan`](enum.PrefetchPlan.html) for a file given its path and length.
+pub type PlanFn = Box<dyn Fn(&Path, u64) -> PrefetchPlan + Send>;
+
+pub(crate) struct Plan {
+    // not yet advised, in order
+    pending: VecDeque<(u64, u64)>,
+    // advised and not yet passed by the read position
+    advised: Vec<(u64, u64)>,
+}
+
+impl Plan {
+    pub(crate) fn new(ranges: Vec<(u64, u64)>, length: u64) -> Self {
+        let pending = ranges.into_iter().filter_map(|(offset, len)| {
+            let end = std::cmp::min(offset.saturating_add(len), length);
+            if offset < end { Some((offset, end - offset)) } else { None }
+        }).collect();
+        Plan {pending, advised: Vec::new()}
+    }
+}
+
+impl Prefetch {
+
+    // advised bytes the consumer hasn't read yet
+    pub(crate) fn outstanding(&self) -> u64 {
+        match self.plan {
+            Some(ref plan) => plan.advised.iter().map(|&(offset, len)| (offset + len).saturating_sub(std::cmp::max(offset, self.read_pos))).sum(),
+            None => self.prefetch_pos.saturating_sub(self.read_pos)
+        }
+    }
+
+    // advises up to `allowance` bytes of the pending ranges, returns the amount issued
+    pub(crate) fn advise_plan(&mut self, allowance: u64, ttl: bool, hooks: &mut Hooks) -> u64 {
+        let read_pos = self.read_pos;
+        // moved out while advising, which records failures on self
+        let mut plan = self.plan.take().expect("expect advise_plan to be called for planned files only");
+        plan.advised.retain(|&(offset, len)| offset + len > read_pos);
+        let mut issued = 0;
+        while issued < allowance {
+            let (offset, len) = match plan.pending.pop_front() {
+                Some(r) => r,
+                None => break
+            };
+            if offset + len <= read_pos {
+                continue
+            }
+            let take = std::cmp::min(len, allowance - issued);
+            if take < len {
+                plan.pending.push_front((offset + take, len - take));
+            }
+            self.willneed(offset, take, hooks);
+            plan.advised.push((offset, take));
+            issued += take;
+        }
+        self.plan = Some(plan);
+        if issued > 0 && self.advised_at.is_none() && ttl {
+            self.advised_at = Some(Instant::now());
+        }
+        issued
+    }
+
+    // drops what was advised but not yet read and queues it for advising again
+    pub(crate) fn expire_plan(&mut self, hooks: &mut Hooks) {
+        let read_pos = self.read_pos;
+        let plan = self.plan.as_mut().expect("expect expire_plan to be called for planned files only");
+        while let Some((offset, len)) = plan.advised.pop() {
+            let start = std::cmp::max(offset, read_pos);
+            if start < offset + len {
+                hooks.dontneed(self.id, &self.f, start, offset + len - start);
+                plan.pending.push_front((start, offset + len - start));
+            }
+        }
+    }
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Lets `f` decide what to prefetch of each file as it is opened, given its path and length.
+    ///
+    /// Files with ranges count only their advised and not yet read ranges against the budget.
+    pub fn prefetch_plan(&mut self, f: Option<PlanFn>) {
+        self.plan = f;
+    }
+}
@@ -0,0 +1,81 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use parallel::Job;
+use std::fs::File;
+use std::io::Error;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use MultiFileReadahead;
+
+/// A queued file that the caller reads itself, obtained from
+/// [`next_planned`](struct.MultiFileReadahead.html#method.next_planned).
+pub struct PlannedFile {
+    path: PathBuf,
+    file: File,
+    length: u64,
+    cursor: Arc<AtomicU64>,
+}
+
+impl PlannedFile {
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// A descriptor of its own for the file, e.g. to hand to the async I/O of a runtime.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Tells the planner how far the file has been read.
+    pub fn set_position(&self, pos: u64) {
+        self.cursor.store(pos, Ordering::Relaxed);
+    }
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Hands out the next file for the caller to read with its own I/O, e.g. native async reads of
+    /// a thread-per-core runtime such as glommio or monoio, while the queue keeps planning prefetch.
+    ///
+    /// Several files may be handed out at once. Report progress with
+    /// [`PlannedFile::set_position`](struct.PlannedFile.html#method.set_position), call
+    /// [`plan_ahead`](#method.plan_ahead) regularly and [`finish_planned`](#method.finish_planned) once done.
+    /// Dropbehind only applies once a file is finished.
+    pub fn next_planned(&mut self) -> Option<Result<PlannedFile, Error>> {
+        let Job {file, cursor, ..} = self.next_job()?;
+        self.advance();
+        Some(file.map(|(path, file, length)| PlannedFile {path, file, length, cursor: cursor.expect("expect that opened jobs carry a cursor")}).map_err(|(_, e)| e))
+    }
+
+    /// Tops up prefetch according to the positions reported for the files handed out.
+    pub fn plan_ahead(&mut self) {
+        self.advance();
+    }
+
+    pub fn finish_planned(&mut self, f: PlannedFile) {
+        if self.dropbehind {
+            for p in self.open.iter().take(self.delivered).filter_map(|o| o.as_ref().ok()) {
+                if p.shared.as_ref().is_some_and(|c| Arc::ptr_eq(c, &f.cursor)) {
+                    p.drop_range(0, 0, &mut self.hooks);
+                }
+            }
+        }
+        self.retire(&f.cursor);
+        self.advance();
+    }
+}
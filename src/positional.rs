@@ -0,0 +1,50 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::io::{Error, ErrorKind};
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use Reader;
+
+impl<'a, T> Reader<'a, T> where T: Iterator<Item=PathBuf> {
+
+    /// Reads from `offset` without moving the position of sequential reads, e.g. to grab the footer
+    /// or index of a file while its body is streamed.
+    ///
+    /// The prefetch window stays where it is and the bytes don't count as delivered. With dropbehind,
+    /// pages read behind the current position are dropped again right away, those ahead are left in
+    /// the cache for the sequential reads and dropped once those pass them. Fails with `ESPIPE` for streams.
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        let drop = self.owner.dropbehind;
+        let fetch = self.owner.open[self.idx].as_mut().expect("expect that readers are only created for successfully opened files");
+        let n = loop {
+            match fetch.f.read_at(buf, offset) {
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                r => break r?
+            }
+        };
+        if drop && n > 0 && offset < fetch.read_pos {
+            let end = std::cmp::min(offset + n as u64, fetch.read_pos);
+            fetch.drop_range(offset, end - offset, &mut self.owner.hooks);
+        }
+        Ok(n)
+    }
+
+    /// Like [`read_at`](#method.read_at), but fills all of `buf` or fails with `UnexpectedEof`.
+    pub fn read_exact_at(&mut self, mut offset: u64, mut buf: &mut [u8]) -> Result<(), Error> {
+        while !buf.is_empty() {
+            match self.read_at(offset, buf)? {
+                0 => return Err(Error::from(ErrorKind::UnexpectedEof)),
+                n => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+            }
+        }
+        Ok(())
+    }
+}
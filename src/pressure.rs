@@ -0,0 +1,68 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use {sys, MultiFileReadahead, PREFETCH_BLOCK};
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+// the budget shrinks to at most 1/2^MAX_BACKOFF of its size
+const MAX_BACKOFF: u32 = 4;
+
+pub(crate) struct IoPressure {
+    // share of wall time with tasks stalled on I/O above which the queue backs off
+    threshold: f64,
+    // previous sample of the cumulative stall time in microseconds
+    sampled: Option<(Instant, u64)>,
+    backoff: u32,
+}
+
+impl IoPressure {
+    fn sample(&mut self) {
+        let now = Instant::now();
+        if self.sampled.is_some_and(|(t, _)| now - t < SAMPLE_INTERVAL) {
+            return
+        }
+        let total = match sys::io_stall_micros() {
+            Some(t) => t,
+            None => return
+        };
+        if let Some((t, last)) = self.sampled {
+            let stalled = total.saturating_sub(last) as f64 / (now - t).as_micros() as f64;
+            if stalled > self.threshold {
+                self.backoff = std::cmp::min(self.backoff + 1, MAX_BACKOFF);
+            } else if stalled < self.threshold / 2.0 {
+                self.backoff = self.backoff.saturating_sub(1);
+            }
+        }
+        self.sampled = Some((now, total));
+    }
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Backs off while other tasks stall on I/O, according to the `some` line of `/proc/pressure/io`.
+    ///
+    /// Once more than `threshold` of the wall time, e.g. `0.2`, is spent stalled the prefetch budget
+    /// is halved each second down to a sixteenth, which also throttles how fast new prefetch is issued.
+    /// It grows back the same way once stalls fall below half the threshold. Stalls are counted
+    /// system-wide and include those caused by the queue itself. Does nothing where PSI isn't available.
+    pub fn io_pressure_backoff(&mut self, threshold: Option<f64>) {
+        self.io_pressure = threshold.map(|threshold| IoPressure {threshold, sampled: None, backoff: 0});
+    }
+
+    // the budget after backing off
+    pub(crate) fn pressure_limit(&mut self, limit: u64) -> u64 {
+        match self.io_pressure {
+            Some(ref mut p) => {
+                p.sample();
+                std::cmp::max(limit >> p.backoff, std::cmp::min(limit, PREFETCH_BLOCK))
+            }
+            None => limit
+        }
+    }
+}
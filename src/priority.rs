@@ -0,0 +1,115 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::path::{Path, PathBuf};
+use {MultiFileReadahead, Opened};
+
+/// Polled for paths that should be read before everything of a lower priority, `None` if there
+/// are none right now, e.g. `move || rx.try_recv().ok()` for requests arriving over a channel.
+pub type PollFn = Box<dyn FnMut() -> Option<PathBuf> + Send>;
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Merges sources with priorities above that of the queue's own source, which is 0, e.g. files
+    /// a user requested ahead of a background list.
+    ///
+    /// They are polled at every planning step. Their paths are opened right away and queued behind
+    /// the current files but ahead of all files of a lower priority, so they are delivered and
+    /// prefetched first while lower priorities get the remaining budget. The members of a directory
+    /// are queued like those of the queue's own source. Not meant to be combined
+    /// with pairs, groups, bundles or pieces, whose files have to stay adjacent.
+    pub fn priority_sources(&mut self, mut sources: Vec<(u32, PollFn)>) {
+        if !sources.is_empty() {
+            self.reject_paired("priority sources");
+        }
+        sources.sort_by_key(|&(prio, _)| std::cmp::Reverse(prio));
+        self.priority_sources = sources;
+    }
+
+    /// Changes the priority of a queued file that hasn't been delivered yet and moves it
+    /// behind the files of the same or a higher priority and ahead of the others. Prefetching is
+    /// re-planned right away.
+    ///
+    /// Returns false if the path is not among the opened files, e.g. because it hasn't been pulled
+    /// from the source yet. A [priority source](#method.priority_sources) can queue it instead.
+    /// Files of [paired](#method.paired) lists can't be moved.
+    pub fn reprioritize(&mut self, p: &Path, prio: u32) -> bool {
+        self.requeue(p, Some(prio))
+    }
+
+    /// Moves a queued file that hasn't been delivered yet to the front so that it is delivered and
+    /// prefetched next. It takes on the highest priority among the queued files.
+    pub fn promote(&mut self, p: &Path) -> bool {
+        self.requeue(p, None)
+    }
+
+    // moves to the front if no priority is given
+    fn requeue(&mut self, p: &Path, prio: Option<u32>) -> bool {
+        if self.paired {
+            return false
+        }
+        let i = match self.find_queued(p) {
+            Some(i) => i,
+            None => return false
+        };
+        let mut entry = self.open.remove(i).unwrap();
+        let target = match prio {
+            Some(prio) => (self.delivered..self.open.len()).find(|&i| match self.open[i] {
+                Ok(ref f) => f.priority < prio,
+                Err(_) => false
+            }).unwrap_or(self.open.len()),
+            None => self.delivered
+        };
+        let highest = self.open.iter().skip(self.delivered).filter_map(|o| o.as_ref().ok()).map(|f| f.priority).max();
+        if let Ok(ref mut fetch) = entry {
+            fetch.priority = prio.unwrap_or_else(|| std::cmp::max(highest.unwrap_or(0), fetch.priority));
+        }
+        self.open.insert(target, entry);
+        self.advance();
+        true
+    }
+
+    pub(crate) fn find_queued(&self, p: &Path) -> Option<usize> {
+        (self.delivered..self.open.len()).find(|&i| match self.open[i] {
+            Ok(ref f) => f.info.path == p,
+            Err(_) => false
+        })
+    }
+
+    pub(crate) fn poll_priority(&mut self) {
+        for s in 0..self.priority_sources.len() {
+            let prio = self.priority_sources[s].0;
+            while self.open.len() <= self.open_limit {
+                let p = match (self.priority_sources[s].1)() {
+                    Some(p) => p,
+                    None => break
+                };
+                self.enqueue_priority(p, prio);
+            }
+        }
+    }
+
+    // opens p and moves it ahead of the queued files with a lower priority
+    pub(crate) fn enqueue_priority(&mut self, p: PathBuf, prio: u32) {
+        let before = self.open.len();
+        let expand = self.dir_order.is_some();
+        if self.open_entry(p, expand) != Opened::Queued {
+            return
+        }
+        if let Ok(ref mut fetch) = self.open[before] {
+            fetch.priority = prio;
+        }
+        let target = (self.delivered..before).find(|&i| match self.open[i] {
+            Ok(ref f) => f.priority < prio,
+            Err(_) => false
+        });
+        if let Some(target) = target {
+            let entry = self.open.remove(before).unwrap();
+            self.open.insert(target, entry);
+        }
+    }
+}
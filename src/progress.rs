@@ -0,0 +1,203 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use {sys, DuplicatePaths, Hardlinks, MultiFileReadahead, Prefetch, Reader, SizedPaths};
+
+/// How far a file has been read and prefetched.
+#[derive(Clone, Copy, Debug)]
+pub struct FileProgress<'a> {
+    pub path: &'a Path,
+    pub len: u64,
+    /// Bytes read by the consumer
+    pub read_pos: u64,
+    /// End of the advised window. For files with a prefetch plan the read position plus
+    /// whatever of the plan is advised ahead of it
+    pub prefetch_pos: u64,
+}
+
+impl<'a> FileProgress<'a> {
+    /// How far the prefetcher is ahead of the consumer, close to zero means the consumer waits for I/O.
+    pub fn ahead(&self) -> u64 {
+        self.prefetch_pos.saturating_sub(self.read_pos)
+    }
+}
+
+/// Run-wide counters, see [`MultiFileReadahead::totals`](struct.MultiFileReadahead.html#method.totals).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Totals {
+    /// Successfully opened files handed to the consumer, including the current ones
+    pub files_delivered: u64,
+    /// Bytes handed to the consumer
+    pub bytes_delivered: u64,
+    /// Bytes advised but not yet read
+    pub bytes_in_flight: u64,
+    /// Files opened or failed to open behind the current ones
+    pub files_queued: usize,
+    /// Reads that started beyond the prefetched data, i.e. the consumer had to wait for the disk
+    /// because the budget, the rate limit, a pause or the open file limit held the planner back.
+    /// A steadily growing count means the budget is too small for the consumer. Files with a
+    /// prefetch plan don't count.
+    pub starved_reads: u64,
+    /// Latency of reads within the prefetched data
+    pub prefetched_latency: LatencyHistogram,
+    /// Latency of the reads counted by `starved_reads`, the difference to `prefetched_latency`
+    /// is what the prefetching hides
+    pub starved_latency: LatencyHistogram,
+    /// Bytes actually read from storage since the queue was created, from `read_bytes` of `/proc/self/io`.
+    /// Compared with `bytes_delivered` it tells how much was served from the cache. The counter covers the whole process
+    /// including other threads and readahead done by the kernel, so it's only meaningful while the queue
+    /// is the main reader. `None` if it isn't available, e.g. outside of Linux.
+    pub storage_read_bytes: Option<u64>,
+}
+
+const LATENCY_BUCKETS: usize = 24;
+
+/// Read latencies in power-of-two buckets of microseconds.
+///
+/// Only reads that go to the file count, not those served from buffers like the head read by `peek`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    buckets: [u64; LATENCY_BUCKETS],
+}
+
+impl LatencyHistogram {
+
+    pub(crate) fn record(&mut self, latency: Duration) {
+        let micros = latency.as_micros();
+        let bucket = (128 - micros.leading_zeros()) as usize;
+        self.buckets[std::cmp::min(bucket, LATENCY_BUCKETS - 1)] += 1;
+    }
+
+    /// Bucket `i` counts reads that took less than 2<sup>i</sup> µs and at least half of that,
+    /// the last one everything slower.
+    pub fn buckets(&self) -> &[u64] {
+        &self.buckets
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// Upper bound of the bucket containing quantile `q` in `0.0..=1.0`, `None` if nothing was recorded.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None
+        }
+        let rank = std::cmp::max(1, (q.clamp(0.0, 1.0) * count as f64).ceil() as u64);
+        let mut seen = 0;
+        for (i, &n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return Some(Duration::from_micros(1 << i))
+            }
+        }
+        None
+    }
+}
+
+/// What the run is expected to deliver, to report [`Totals`](struct.Totals.html) as a percentage.
+///
+/// Files that fail to open, are skipped as duplicates or expanded into bundles make the actual
+/// numbers deviate from it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Estimate {
+    /// Known if the source reports an exact size hint when the queue is created
+    pub files: Option<u64>,
+    /// Known if provided via [`SizedPaths`](struct.SizedPaths.html) or
+    /// [`expected_bytes`](struct.MultiFileReadahead.html#method.expected_bytes)
+    pub bytes: Option<u64>,
+}
+
+impl Prefetch {
+    pub(crate) fn progress(&self) -> FileProgress<'_> {
+        let read_pos = self.position();
+        let prefetch_pos = match self.plan {
+            Some(_) => self.read_pos + self.outstanding(),
+            None => std::cmp::max(self.prefetch_pos, self.read_pos)
+        };
+        FileProgress {path: &self.info.path, len: self.length, read_pos, prefetch_pos}
+    }
+}
+
+impl<'a, T> Reader<'a, T> where T: Iterator<Item=PathBuf> {
+
+    pub fn progress(&self) -> FileProgress<'_> {
+        self.owner.open[self.idx].as_ref().map(|p| p.progress()).unwrap_or(FileProgress {path: &self.info.path, len: 0, read_pos: 0, prefetch_pos: 0})
+    }
+
+    /// Progress of the files queued behind this one, see
+    /// [`MultiFileReadahead::progress`](struct.MultiFileReadahead.html#method.progress).
+    pub fn queued_progress(&self) -> impl Iterator<Item=FileProgress<'_>> + '_ {
+        self.owner.open.iter().skip(self.owner.delivered).filter_map(|o| o.as_ref().ok()).map(|p| p.progress())
+    }
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Progress of the opened files in queue order, starting with those currently handed out.
+    pub fn progress(&self) -> impl Iterator<Item=FileProgress<'_>> + '_ {
+        self.open.iter().filter_map(|o| o.as_ref().ok()).map(|p| p.progress())
+    }
+
+    pub fn estimate(&self) -> Estimate {
+        self.estimate
+    }
+
+    /// How many files are left to be delivered, including failed opens but not the current files.
+    ///
+    /// Known if the source reports an exact size hint or the [stat horizon](#method.stat_horizon) has
+    /// pulled all of it, and nothing changes the count along the way: no expanded directories or
+    /// bundles, repetitions, priority sources or dropped duplicates and hardlinks.
+    pub fn remaining_files(&self) -> Option<usize> {
+        if self.dir_order.is_some() || self.bundles.is_some() || self.repeat.is_some() || !self.priority_sources.is_empty()
+            || self.dedup != DuplicatePaths::Keep || self.hardlinks == Hardlinks::Skip {
+            return None
+        }
+        let source = match self.horizon.as_ref().and_then(|h| h.remaining()) {
+            Some(ahead) => ahead,
+            None => match self.source.size_hint() {
+                (lower, Some(upper)) if lower == upper => lower + self.horizon.as_ref().map_or(0, |h| h.len()),
+                _ => return None
+            }
+        };
+        Some(self.open.len() - self.delivered + self.pending.len() + self.stack.len() + source)
+    }
+
+    /// Overrides the expected byte count, e.g. from a du-style pre-scan.
+    pub fn expected_bytes(&mut self, bytes: Option<u64>) {
+        self.estimate.bytes = bytes;
+    }
+
+    /// Cheap enough to call for periodic logging. Bytes read outside the queue, i.e. by worker
+    /// threads, through planned files or as shuffled records, are not counted as delivered.
+    pub fn totals(&self) -> Totals {
+        Totals {
+            files_delivered: self.hooks.files_delivered,
+            bytes_delivered: self.hooks.bytes_delivered,
+            bytes_in_flight: self.progress().map(|p| p.ahead()).sum(),
+            files_queued: self.open.len() - self.delivered,
+            starved_reads: self.hooks.starved_reads,
+            prefetched_latency: self.hooks.prefetched_latency,
+            starved_latency: self.hooks.starved_latency,
+            storage_read_bytes: self.hooks.storage_baseline.and_then(|base| Some(sys::storage_read_bytes()?.saturating_sub(base))),
+        }
+    }
+}
+
+impl MultiFileReadahead<SizedPaths> {
+
+    /// Creates a queue whose [`estimate`](#method.estimate) knows both the file and the byte count up front.
+    pub fn sized(src: SizedPaths) -> Self {
+        let bytes = src.total_bytes();
+        let mut q = Self::new(src);
+        q.expected_bytes(Some(bytes));
+        q
+    }
+}
@@ -0,0 +1,161 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Python module `reapfrog`.
+//!
+//! Build it with `cargo rustc --release --features pyo3,pyo3/extension-module --crate-type cdylib`
+//! and install `libreapfrog.so` as `reapfrog.so` on the module path, or use maturin.
+//!
+//! ```python
+//! import reapfrog
+//! for f in reapfrog.Queue(paths, budget=64 << 20):
+//!     data = f.read()
+//! ```
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard};
+use std::vec;
+use {Budget, MultiFileReadahead};
+
+/// Iterator over the files behind a list of paths, prefetching ahead of the one being read.
+///
+/// A file that fails to open raises `OSError` from `__next__`, the next call continues with the file after it.
+#[pyclass(module = "reapfrog", frozen)]
+struct Queue {
+    // pyclasses have to be Sync
+    state: Mutex<State>,
+}
+
+struct State {
+    inner: MultiFileReadahead<vec::IntoIter<PathBuf>>,
+    // bumped whenever the current file changes, invalidates older File objects
+    generation: u64,
+}
+
+impl Queue {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The file currently read from a `Queue`. It becomes unusable once the next one is taken.
+#[pyclass(module = "reapfrog")]
+struct File {
+    queue: Py<Queue>,
+    generation: u64,
+    #[pyo3(get)]
+    name: PathBuf,
+    #[pyo3(get)]
+    closed: bool,
+}
+
+#[pymethods]
+impl Queue {
+
+    #[new]
+    #[pyo3(signature = (paths, budget=None, dropbehind=false))]
+    fn new(paths: Vec<PathBuf>, budget: Option<u64>, dropbehind: bool) -> Self {
+        let mut inner = MultiFileReadahead::new(paths.into_iter());
+        if let Some(b) = budget {
+            inner.budget(Budget::Fixed(b));
+        }
+        inner.dropbehind(dropbehind);
+        Queue {state: Mutex::new(State {inner, generation: 0})}
+    }
+
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(slf: PyRef<'_, Self>) -> PyResult<Option<File>> {
+        let py = slf.py();
+        let q = &*slf;
+        let entry = py.allow_threads(|| {
+            let mut s = q.lock();
+            s.generation += 1;
+            let generation = s.generation;
+            s.inner.next_entry().map(|r| r.map(|()| {
+                (generation, s.inner.open[0].as_ref().expect("expect that next_entry only leaves successfully opened files at the front").info.path.clone())
+            }))
+        });
+        match entry {
+            None => Ok(None),
+            Some(Err(e)) => Err(e.into()),
+            Some(Ok((generation, name))) => Ok(Some(File {queue: slf.into(), generation, name, closed: false}))
+        }
+    }
+}
+
+#[pymethods]
+impl File {
+
+    /// Reads up to `size` bytes, everything up to the end of the file if negative.
+    #[pyo3(signature = (size=-1))]
+    fn read<'py>(&self, py: Python<'py>, size: i64) -> PyResult<Bound<'py, PyBytes>> {
+        if self.closed {
+            return Err(PyValueError::new_err("I/O operation on closed file"))
+        }
+        let queue = self.queue.get();
+        let generation = self.generation;
+        let data = py.allow_threads(|| {
+            let mut q = queue.lock();
+            if q.generation != generation {
+                return Ok(None)
+            }
+            let mut data = Vec::new();
+            let limit = if size < 0 { u64::MAX } else { size as u64 };
+            while (data.len() as u64) < limit {
+                let start = data.len();
+                let want = std::cmp::min(limit - start as u64, 256 * 1024) as usize;
+                data.resize(start + want, 0);
+                match q.inner.read_entry(0, &mut data[start..]) {
+                    Ok(0) => {
+                        data.truncate(start);
+                        break
+                    }
+                    Ok(n) => data.truncate(start + n),
+                    Err(ref e) if e.kind() == ErrorKind::Interrupted => data.truncate(start),
+                    Err(e) => return Err(e)
+                }
+            }
+            Ok(Some(data))
+        })?;
+        match data {
+            Some(d) => Ok(PyBytes::new(py, &d)),
+            None => Err(PyValueError::new_err("I/O operation on a file the queue has moved past"))
+        }
+    }
+
+    fn readable(&self) -> bool {
+        true
+    }
+
+    /// Marks the file as closed, the queue moves past it with its next file.
+    fn close(&mut self) {
+        self.closed = true;
+    }
+
+    fn __enter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    #[pyo3(signature = (*_args))]
+    fn __exit__(&mut self, _args: &Bound<'_, pyo3::types::PyTuple>) {
+        self.close();
+    }
+}
+
+#[pymodule]
+fn reapfrog(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<Queue>()?;
+    m.add_class::<File>()?;
+    Ok(())
+}
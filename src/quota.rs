@@ -0,0 +1,75 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::path::{Path, PathBuf};
+use MultiFileReadahead;
+
+/// Callback assigning a path to one of the classes of [`Quotas`](struct.Quotas.html), `None` leaves it unrestricted.
+pub type ClassifyFn = Box<dyn Fn(&Path) -> Option<usize> + Send>;
+
+/// Caps on how much of the budget files of each class may hold, e.g. to give a hot dataset more
+/// lookahead than a cold archive interleaved with it in the same queue.
+pub struct Quotas {
+    limits: Vec<u64>,
+    classify: ClassifyFn,
+}
+
+impl Quotas {
+
+    /// Class `i` may have `limits[i]` bytes prefetched ahead of the consumer. Classes returned by
+    /// `classify` beyond the limits are unrestricted.
+    pub fn new(limits: Vec<u64>, classify: ClassifyFn) -> Self {
+        Quotas {limits, classify}
+    }
+
+    /// Quotas for the files below each prefix, the first matching prefix applies.
+    pub fn by_prefix(prefixes: Vec<(PathBuf, u64)>) -> Self {
+        let limits = prefixes.iter().map(|&(_, l)| l).collect();
+        let prefixes: Vec<PathBuf> = prefixes.into_iter().map(|(p, _)| p).collect();
+        Quotas::new(limits, Box::new(move |p| prefixes.iter().position(|prefix| p.starts_with(prefix))))
+    }
+
+    pub(crate) fn classify(&self, p: &Path) -> Option<usize> {
+        (self.classify)(p).filter(|&c| c < self.limits.len())
+    }
+
+    // how much more a file of the class may prefetch
+    pub(crate) fn left(&self, used: &[u64], class: Option<usize>) -> u64 {
+        // classes assigned under earlier quotas may be out of range
+        match class.and_then(|c| Some((self.limits.get(c)?, used.get(c)?))) {
+            Some((&limit, &used)) => limit.saturating_sub(used),
+            None => u64::MAX
+        }
+    }
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Limits the prefetch of classes of files on top of the overall budget. Only applies to files
+    /// opened afterwards. Files with a prefetch plan or a rate and the headers scheduled by
+    /// `Schedule::HeadersFirst` are not limited.
+    pub fn quotas(&mut self, quotas: Option<Quotas>) {
+        self.quotas = quotas;
+    }
+
+    // prefetched bytes per class
+    pub(crate) fn quota_usage(&self) -> Vec<u64> {
+        let q = match self.quotas {
+            Some(ref q) => q,
+            None => return Vec::new()
+        };
+        let mut used = vec![0; q.limits.len()];
+        for p in self.open.iter().filter_map(|o| o.as_ref().ok()) {
+            if let Some(c) = p.class {
+                if c < used.len() && p.plan.is_none() && p.window.is_none() {
+                    used[c] += p.outstanding();
+                }
+            }
+        }
+        used
+    }
+}
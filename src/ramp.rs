@@ -0,0 +1,30 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::path::PathBuf;
+use {MultiFileReadahead, Prefetch, PREFETCH_BLOCK};
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Starts each file with a window of `initial` bytes and lets it grow with the amount the
+    /// consumer has read, so it doubles with every window consumed, like the kernel's own readahead.
+    /// Consumers that abandon files after their headers then don't waste the budget on the rest,
+    /// while big files still reach large windows. `None` gives every file its full share right away.
+    pub fn ramp_up(&mut self, initial: Option<u64>) {
+        self.ramp = initial.map(|i| std::cmp::max(i, PREFETCH_BLOCK));
+    }
+}
+
+impl Prefetch {
+    // how far ahead of the consumer the file may be prefetched
+    pub(crate) fn ramp_limit(&self, ramp: Option<u64>, share: u64) -> u64 {
+        match ramp {
+            Some(initial) => std::cmp::min(share, std::cmp::max(initial, self.position())),
+            None => share
+        }
+    }
+}
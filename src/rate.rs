@@ -0,0 +1,38 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::time::Instant;
+
+// token bucket holding up to one second worth of bytes
+pub(crate) struct TokenBucket {
+    rate: u64,
+    tokens: u64,
+    last: Instant,
+}
+
+impl TokenBucket {
+
+    pub(crate) fn new(rate: u64) -> Self {
+        TokenBucket {rate, tokens: rate, last: Instant::now()}
+    }
+
+    pub(crate) fn available(&mut self) -> u64 {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last);
+        let refill = (elapsed.as_secs_f64() * self.rate as f64) as u64;
+        // only move the clock when something is refilled so that frequent polling doesn't lose fractions
+        if refill > 0 {
+            self.tokens = std::cmp::min(self.rate, self.tokens.saturating_add(refill));
+            self.last = now;
+        }
+        self.tokens
+    }
+
+    pub(crate) fn take(&mut self, bytes: u64) {
+        self.tokens = self.tokens.saturating_sub(bytes);
+    }
+}
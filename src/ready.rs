@@ -0,0 +1,43 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::io::Error;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use {sys, MultiFileReadahead, Reader, PREFETCH_BLOCK};
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Like [`next`](#method.next) but waits up to `timeout` for the first block of the file to be
+    /// in the page cache, so that the first read doesn't block on the disk. For consumers that care
+    /// more about consistent latency than throughput, e.g. viewers stepping through files.
+    ///
+    /// Doesn't wait for files whose first block wasn't advised, e.g. because a prefetch plan skips it,
+    /// or if residency can't be determined, e.g. for special files.
+    pub fn next_when_ready(&mut self, timeout: Duration) -> Option<Result<Reader<'_, Src>, Error>> {
+        if let Err(e) = self.next_entry()? {
+            return Some(Err(e))
+        }
+        let start = Instant::now();
+        let mut delay = Duration::from_micros(100);
+        {
+            let p = self.open[0].as_ref().expect("expect that next_entry only leaves successfully opened files at the front");
+            let head = std::cmp::min(p.length, PREFETCH_BLOCK);
+            if p.read_pos < head && p.read_pos < p.prefetch_pos {
+                while let Ok(false) = sys::is_resident(&p.f, head) {
+                    let elapsed = start.elapsed();
+                    if elapsed >= timeout {
+                        break
+                    }
+                    std::thread::sleep(std::cmp::min(delay, timeout - elapsed));
+                    delay = std::cmp::min(delay * 2, Duration::from_millis(10));
+                }
+            }
+        }
+        Some(Ok(Reader::new(self, 0).expect("expect that next_entry only leaves successfully opened files at the front")))
+    }
+}
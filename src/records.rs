@@ -0,0 +1,245 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::convert::TryInto;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use MultiFileReadahead;
+
+const RECORD_READ: usize = 64 * 1024;
+const DEFAULT_MAX_RECORD: usize = 16 * 1024 * 1024;
+
+/// How records are delimited within each file. Records never span files.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Framing {
+    /// Records end with this byte, e.g. `b'\n'` for lines. The delimiter is not part of the record
+    /// and the last record of a file may lack it.
+    Delimited(u8),
+    /// Each record is preceded by its length as a 32-bit little-endian integer
+    U32Le,
+    /// Each record is preceded by its length as a 32-bit big-endian integer
+    U32Be,
+}
+
+/// A record and where it came from.
+pub struct Record<'a> {
+    pub path: &'a Path,
+    /// Offset of the record, including any length prefix, within its file
+    pub offset: u64,
+    pub data: &'a [u8],
+}
+
+/// Reads the queue as a sequence of records, e.g. the lines of many rotated log files.
+///
+/// Files that fail to open or read are reported as errors and iteration continues with the next file.
+/// A truncated length-prefixed record at the end of a file is reported as an `UnexpectedEof` error,
+/// one longer than [`max_record_len`](#method.max_record_len) as `InvalidData`.
+pub struct Records<Src> {
+    inner: MultiFileReadahead<Src>,
+    framing: Framing,
+    max_len: usize,
+    buf: Vec<u8>,
+    // start of the unconsumed data in buf
+    start: usize,
+    // bytes after start already searched for a delimiter
+    scanned: usize,
+    // file offset of buf[start]
+    offset: u64,
+    // whether the front of the queue is the file currently being read
+    active: bool,
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    pub fn records(self, framing: Framing) -> Records<Src> {
+        Records {inner: self, framing, max_len: DEFAULT_MAX_RECORD, buf: Vec::new(), start: 0, scanned: 0, offset: 0, active: false}
+    }
+}
+
+impl<Src: Iterator<Item=PathBuf>> Records<Src> {
+
+    /// Fails records longer than `bytes` instead of buffering them, which guards against corrupt
+    /// length prefixes and files lacking delimiters. Iteration continues with the next file. Defaults to 16 MiB.
+    pub fn max_record_len(&mut self, bytes: usize) {
+        self.max_len = bytes;
+    }
+
+    pub fn next_record(&mut self) -> Option<Result<Record<'_>, Error>> {
+        loop {
+            if !self.active {
+                if let Err(e) = self.inner.next_entry()? {
+                    return Some(Err(e))
+                }
+                self.active = true;
+                self.buf.clear();
+                self.start = 0;
+                self.scanned = 0;
+                self.offset = 0;
+            }
+
+            // (record start, record end, bytes consumed) relative to start
+            let data = &self.buf[self.start..];
+            let framed = frame(self.framing, data, self.scanned);
+            let (from, to, consumed) = match framed {
+                Some(r) => r,
+                None if pending_len(self.framing, data) > self.max_len => {
+                    self.active = false;
+                    return Some(Err(Error::new(ErrorKind::InvalidData, "record exceeds the maximum length")))
+                }
+                None => {
+                    self.scanned = data.len();
+                    match self.fill() {
+                        Ok(true) => continue,
+                        Ok(false) => {
+                            self.active = false;
+                            let rest = self.buf.len() - self.start;
+                            if rest == 0 {
+                                continue
+                            }
+                            if let Framing::Delimited(_) = self.framing {
+                                (0, rest, rest)
+                            } else {
+                                return Some(Err(Error::new(ErrorKind::UnexpectedEof, "truncated record at the end of the file")))
+                            }
+                        }
+                        Err(e) => {
+                            self.active = false;
+                            return Some(Err(e))
+                        }
+                    }
+                }
+            };
+
+            let base = self.start;
+            let offset = self.offset;
+            self.start += consumed;
+            self.scanned = 0;
+            self.offset += consumed as u64;
+            let path = &self.inner.open[0].as_ref().expect("expect that next_entry only leaves successfully opened files at the front").info.path;
+            return Some(Ok(Record {path, offset, data: &self.buf[base + from..base + to]}))
+        }
+    }
+
+    // reads more of the current file, false at its end
+    fn fill(&mut self) -> Result<bool, Error> {
+        // compact once the consumed records make up half of the buffer, not on every read
+        if self.start > 0 && self.start >= self.buf.len() / 2 {
+            self.buf.drain(..self.start);
+            self.start = 0;
+        }
+        let filled = self.buf.len();
+        self.buf.resize(filled + RECORD_READ, 0);
+        self.inner.buffered = self.buf.capacity() as u64;
+        loop {
+            match self.inner.read_entry(0, &mut self.buf[filled..]) {
+                Ok(n) => {
+                    self.buf.truncate(filled + n);
+                    return Ok(n > 0)
+                }
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.buf.truncate(filled);
+                    return Err(e)
+                }
+            }
+        }
+    }
+}
+
+// the first `scanned` bytes of data are known to hold no delimiter
+fn frame(framing: Framing, data: &[u8], scanned: usize) -> Option<(usize, usize, usize)> {
+    if let Framing::Delimited(d) = framing {
+        let end = scanned + data[scanned..].iter().position(|&b| b == d)?;
+        return Some((0, end, end + 1))
+    }
+    let len = prefix(framing, data)?;
+    if data.len() - 4 < len {
+        return None
+    }
+    Some((4, 4 + len, 4 + len))
+}
+
+fn prefix(framing: Framing, data: &[u8]) -> Option<usize> {
+    let len = match framing {
+        Framing::Delimited(_) => return None,
+        Framing::U32Le => u32::from_le_bytes(data.get(..4)?.try_into().unwrap()),
+        Framing::U32Be => u32::from_be_bytes(data.get(..4)?.try_into().unwrap()),
+    };
+    Some(len as usize)
+}
+
+// the length of the incomplete record at the start of data as far as it is known
+fn pending_len(framing: Framing, data: &[u8]) -> usize {
+    match framing {
+        Framing::Delimited(_) => data.len(),
+        _ => prefix(framing, data).unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::Files;
+
+    fn collect<Src: Iterator<Item=PathBuf>>(records: &mut Records<Src>) -> Vec<Result<(u64, Vec<u8>), ErrorKind>> {
+        let mut all = Vec::new();
+        while let Some(r) = records.next_record() {
+            all.push(r.map(|r| (r.offset, r.data.to_vec())).map_err(|e| e.kind()));
+        }
+        all
+    }
+
+    #[test]
+    fn lines_across_reads() {
+        let long = vec![b'x'; 3 * RECORD_READ];
+        let mut data = b"a\n\nbc\n".to_vec();
+        data.extend_from_slice(&long);
+        data.extend_from_slice(b"\nend");
+        let files = Files::new(&[("/a", &data)]);
+        let mut records = files.queue(&["/a"]).records(Framing::Delimited(b'\n'));
+        assert_eq!(collect(&mut records), vec![
+            Ok((0, b"a".to_vec())),
+            Ok((2, Vec::new())),
+            Ok((3, b"bc".to_vec())),
+            Ok((6, long.clone())),
+            Ok((7 + long.len() as u64, b"end".to_vec())),
+        ]);
+    }
+
+    #[test]
+    fn length_prefixed() {
+        let mut data = Vec::new();
+        for r in [&b"abc"[..], b"", b"de"] {
+            data.extend_from_slice(&(r.len() as u32).to_be_bytes());
+            data.extend_from_slice(r);
+        }
+        let truncated = [0, 0, 0, 9, 1, 2];
+        let files = Files::new(&[("/a", &data), ("/b", &truncated)]);
+        let mut records = files.queue(&["/a", "/b"]).records(Framing::U32Be);
+        assert_eq!(collect(&mut records), vec![
+            Ok((0, b"abc".to_vec())),
+            Ok((7, Vec::new())),
+            Ok((11, b"de".to_vec())),
+            Err(ErrorKind::UnexpectedEof),
+        ]);
+    }
+
+    #[test]
+    fn oversized_records_fail_their_file() {
+        let mut prefixed = u32::MAX.to_le_bytes().to_vec();
+        prefixed.extend_from_slice(&[0; 100]);
+        let files = Files::new(&[("/a", &prefixed), ("/b", &[1u8, 0, 0, 0, 7])]);
+        let mut records = files.queue(&["/a", "/b"]).records(Framing::U32Le);
+        records.max_record_len(1000);
+        assert_eq!(collect(&mut records), vec![Err(ErrorKind::InvalidData), Ok((0, vec![7]))]);
+
+        let files = Files::new(&[("/a", &[b'x'; 5000]), ("/b", b"ok\n")]);
+        let mut records = files.queue(&["/a", "/b"]).records(Framing::Delimited(b'\n'));
+        records.max_record_len(1000);
+        assert_eq!(collect(&mut records), vec![Err(ErrorKind::InvalidData), Ok((0, b"ok".to_vec()))]);
+    }
+}
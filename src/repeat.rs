@@ -0,0 +1,125 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::path::{Path, PathBuf};
+use userdata::UserData;
+use {sys, MultiFileReadahead};
+
+pub(crate) struct Repeat {
+    // total number of passes, None for no end
+    passes: Option<u64>,
+    pass: u64,
+    // the files queued during the first pass with their length, hardlink origin and user data
+    history: Vec<(PathBuf, u64, Option<PathBuf>, Option<UserData>)>,
+    next: usize,
+    advise: bool,
+}
+
+impl Repeat {
+    pub(crate) fn record(&mut self, p: &Path, len: u64, link_of: &Option<PathBuf>, user_data: &Option<UserData>) {
+        if self.pass == 0 {
+            self.history.push((p.to_owned(), len, link_of.clone(), user_data.clone()));
+        }
+    }
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Reads the source `passes` times in total, `None` repeats it forever. With `readvise` unset
+    /// repeated files aren't prefetched, for data sets that are expected to still be cached from the previous pass.
+    ///
+    /// Repetitions replay the files queued by the first pass with their cached sizes instead of
+    /// consuming the source again, so the source isn't touched after its first pass and
+    /// directories aren't expanded twice. Files that failed to open, were skipped or were special
+    /// files during the first pass are not repeated. Must be set before reading starts.
+    pub fn repeat(&mut self, passes: Option<u64>, readvise: bool) {
+        self.repeat = match passes {
+            Some(n) if n <= 1 => None,
+            _ => Some(Repeat {passes, pass: 0, history: Vec::new(), next: 0, advise: readvise})
+        };
+    }
+
+    pub(crate) fn replaying(&self) -> bool {
+        self.repeat.as_ref().is_some_and(|r| r.pass > 0)
+    }
+
+    // queues the next file of a repetition, false once all passes are done
+    pub(crate) fn replay_file(&mut self) -> bool {
+        let (p, len, link_of, user_data, advise) = {
+            let r = match self.repeat {
+                Some(ref mut r) => r,
+                None => return false
+            };
+            // the source just ran dry or the previous repetition is done
+            if r.pass == 0 || r.next == r.history.len() {
+                if r.history.is_empty() || r.passes.is_some_and(|n| r.pass + 1 >= n) {
+                    return false
+                }
+                r.pass += 1;
+                r.next = 0;
+            }
+            let (ref p, len, ref link_of, ref user_data) = r.history[r.next];
+            r.next += 1;
+            (p.clone(), len, link_of.clone(), user_data.clone(), r.advise)
+        };
+
+        let f = match self.fs {
+            Some(ref mut fs) => fs.open(&p),
+            None => sys::open_nonblocking(&p)
+        };
+        match f {
+            Ok(f) => self.push_file(f, len, p, link_of, user_data, !advise),
+            Err(e) => self.open.push_back(Err((p, e)))
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use testutil::{read_front, Files};
+    use MultiFileReadahead;
+
+    #[test]
+    fn passes_replay_opened_files() {
+        let files = Files::new(&[("/a", &[1; 100_000]), ("/b", b"hello")]);
+        let mut q = files.queue(&["/a", "/missing", "/b"]);
+        q.repeat(Some(3), false);
+        let mut read = Vec::new();
+        let mut failed = 0;
+        while let Some(r) = q.next() {
+            match r {
+                Ok(r) => read.push(r.path().to_owned()),
+                Err(_) => {
+                    failed += 1;
+                    continue
+                }
+            }
+            assert_eq!(read_front(&mut q).len(), if read.len() % 2 == 1 { 100_000 } else { 5 });
+        }
+        let pass = [files.path("/a"), files.path("/b")];
+        assert_eq!(read, [&pass[..], &pass[..], &pass[..]].concat());
+        // only the first pass opens the missing file
+        assert_eq!(failed, 1);
+    }
+
+    #[test]
+    fn passes_keep_user_data() {
+        let files = Files::new(&[("/a", &[1; 1000]), ("/b", b"hello")]);
+        let mut q = MultiFileReadahead::with_user_data(vec![(files.path("/a"), 1u32), (files.path("/missing"), 2), (files.path("/b"), 3)].into_iter());
+        q.filesystem(Some(Box::new(files.fs())));
+        q.repeat(Some(3), false);
+        let mut seen = Vec::new();
+        while let Some(r) = q.next() {
+            if let Ok(r) = r {
+                seen.push((r.path().to_owned(), *r.user_data::<u32>().unwrap()));
+            }
+        }
+        let pass = [(files.path("/a"), 1), (files.path("/b"), 3)];
+        assert_eq!(seen, [&pass[..], &pass[..], &pass[..]].concat());
+    }
+}
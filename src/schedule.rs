@@ -0,0 +1,55 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::path::PathBuf;
+use {MultiFileReadahead, MAX_OPEN, PREFETCH_BLOCK};
+
+/// Order in which the lookahead window is prefetched.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Schedule {
+    /// Each file in turn, as deep as the budget allows
+    Sequential,
+    /// The first block of every file in the window before the bodies, for consumers that reject
+    /// many files after looking at their headers. Headers take at most half of the budget.
+    HeadersFirst,
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Defaults to `Schedule::Sequential`.
+    pub fn schedule(&mut self, schedule: Schedule) {
+        self.schedule = schedule;
+    }
+
+    // advises the first block of as many files as the budget allows, returns the amount issued
+    pub(crate) fn advise_headers(&mut self, budget: u64) -> u64 {
+        let ttl = self.ttl.is_some();
+        let mut issued = 0;
+        for i in 0.. {
+            if budget - issued < PREFETCH_BLOCK { break; }
+
+            if i == self.open.len() && (i > self.open_limit || !self.add_file()) {
+                break
+            }
+
+            if i > MAX_OPEN { break }
+
+            let p = match self.open[i] {
+                Ok(ref mut p) => p,
+                Err(_) => continue
+            };
+
+            if p.info.link_of.is_some() || p.warm || p.unadvisable || p.plan.is_some() || p.window.is_some() || (p.stale && i > self.delivered) { continue; }
+            let old_pos = std::cmp::max(p.read_pos, p.prefetch_pos);
+            let end = std::cmp::min(p.length, PREFETCH_BLOCK);
+            if old_pos >= end { continue; }
+            p.advise(old_pos, end, ttl, &mut self.hooks);
+            issued += end - old_pos;
+        }
+        issued
+    }
+}
@@ -0,0 +1,176 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Error;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use {sys, Span, DEFAULT_BUDGET, MAX_OPEN};
+
+/// Callback reordering the records for the given epoch, returning false ends the iteration instead.
+pub type ReshuffleFn = Box<dyn FnMut(u64, &mut Vec<Span>) -> bool + Send>;
+
+/// Reads records, i.e. byte ranges of files, in a caller-chosen random order such as the
+/// pre-shuffled index of a training data loader.
+///
+/// Kernel readahead and the sequential planner are useless for this access pattern, so the
+/// records themselves are advised in the order they will be read, up to the lookahead budget.
+/// With a reshuffle callback iteration continues through further epochs and the plan extends
+/// seamlessly into the next one.
+pub struct ShuffledRecords {
+    records: Vec<Span>,
+    // the order of the next epoch, once the plan has reached it
+    next: Option<Vec<Span>>,
+    reshuffle: Option<ReshuffleFn>,
+    epoch: u64,
+    finished: bool,
+    pos: usize,
+    // all records before this one (counting on into the next epoch) have been advised
+    advised: usize,
+    outstanding: u64,
+    budget: u64,
+    files: HashMap<PathBuf, (File, u64)>,
+    uses: u64,
+    buf: Vec<u8>,
+}
+
+impl ShuffledRecords {
+
+    pub fn new(records: Vec<Span>) -> Self {
+        ShuffledRecords {
+            records,
+            next: None,
+            reshuffle: None,
+            epoch: 0,
+            finished: false,
+            pos: 0,
+            advised: 0,
+            outstanding: 0,
+            budget: DEFAULT_BUDGET,
+            files: HashMap::new(),
+            uses: 0,
+            buf: Vec::new(),
+        }
+    }
+
+    /// How many bytes of upcoming records to keep advised.
+    pub fn lookahead(&mut self, bytes: u64) {
+        self.budget = bytes;
+    }
+
+    /// Continues with another epoch after the last record, reordered by `f`.
+    pub fn reshuffle(&mut self, f: Option<ReshuffleFn>) {
+        self.reshuffle = f;
+    }
+
+    /// The zero-based epoch of the record returned last.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Reads the next record. Failures are reported per record and iteration continues after them.
+    pub fn next_record(&mut self) -> Option<Result<(&Span, &[u8]), Error>> {
+        if self.pos == self.records.len() {
+            let next = self.next.take().or_else(|| self.next_order())?;
+            self.records = next;
+            self.epoch += 1;
+            self.advised -= self.pos;
+            self.pos = 0;
+            if self.records.is_empty() {
+                return None
+            }
+        }
+        self.plan();
+
+        let idx = self.pos;
+        self.pos += 1;
+        if idx < self.advised {
+            self.outstanding -= self.records[idx].len;
+        } else {
+            self.advised = self.pos;
+        }
+        if let Err(e) = self.read(idx) {
+            return Some(Err(e))
+        }
+        Some(Ok((&self.records[idx], &self.buf)))
+    }
+
+    fn next_order(&mut self) -> Option<Vec<Span>> {
+        if self.finished {
+            return None
+        }
+        let mut order = self.records.clone();
+        let more = match self.reshuffle {
+            Some(ref mut f) => f(self.epoch + 1, &mut order),
+            None => false
+        };
+        if !more {
+            self.finished = true;
+            return None
+        }
+        Some(order)
+    }
+
+    fn read(&mut self, idx: usize) -> Result<(), Error> {
+        let r = &self.records[idx];
+        self.buf.resize(r.len as usize, 0);
+        let f = cached_file(&mut self.files, &mut self.uses, &r.path)?;
+        f.read_exact_at(&mut self.buf, r.offset)
+    }
+
+    // keeps advising upcoming records until the budget is used up, once it has fallen to half
+    fn plan(&mut self) {
+        // the record buffer counts against the budget as well
+        let budget = self.budget.saturating_sub(self.buf.capacity() as u64);
+        if self.outstanding > budget / 2 {
+            return
+        }
+        loop {
+            let (path, offset, len) = {
+                let r = if self.advised < self.records.len() {
+                    &self.records[self.advised]
+                } else {
+                    if self.next.is_none() {
+                        self.next = self.next_order();
+                    }
+                    match self.next.as_ref().and_then(|n| n.get(self.advised - self.records.len())) {
+                        Some(r) => r,
+                        None => return
+                    }
+                };
+                if self.outstanding > 0 && self.outstanding + r.len > budget {
+                    return
+                }
+                (r.path.clone(), r.offset, r.len)
+            };
+            if let Ok(f) = cached_file(&mut self.files, &mut self.uses, &path) {
+                let _ = sys::advise_willneed(f, offset, len);
+            }
+            self.advised += 1;
+            self.outstanding += len;
+        }
+    }
+
+}
+
+// descriptors are cached so that the same files aren't reopened for every record
+fn cached_file<'a>(files: &'a mut HashMap<PathBuf, (File, u64)>, uses: &mut u64, path: &Path) -> Result<&'a File, Error> {
+    *uses += 1;
+    if !files.contains_key(path) {
+        if files.len() >= MAX_OPEN {
+            let lru = files.iter().min_by_key(|e| (e.1).1).map(|e| e.0.clone()).unwrap();
+            files.remove(&lru);
+        }
+        let f = File::open(path)?;
+        sys::advise_random(&f);
+        files.insert(path.to_owned(), (f, *uses));
+    }
+    let entry = files.get_mut(path).unwrap();
+    entry.1 = *uses;
+    Ok(&entry.0)
+}
@@ -0,0 +1,98 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use MultiFileReadahead;
+
+/// What the planner did over a [`simulate`](struct.MultiFileReadahead.html#method.simulate)d run.
+#[derive(Clone, Debug, Default)]
+pub struct Simulation {
+    /// Most bytes advised but not yet consumed at any time
+    pub peak_in_flight: u64,
+    pub willneed_calls: u64,
+    pub dontneed_calls: u64,
+    /// Most files held open at any time
+    pub max_open_files: usize,
+    /// Files that failed to open
+    pub failed: u64,
+    /// Successfully opened files in queue order
+    pub files: Vec<SimulatedFile>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SimulatedFile {
+    pub path: PathBuf,
+    pub len: u64,
+    /// Most bytes of the file advised ahead of the consumer at any time
+    pub max_window: u64,
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Walks the source as if every file was read front to back in chunks of `read_size` bytes
+    /// and reports what the planner would have done.
+    ///
+    /// Files are opened and stat'ed as usual but neither read nor advised, so the page cache is left alone.
+    /// Never returns for endless sources, e.g. repeating forever.
+    pub fn simulate(mut self, read_size: u64) -> Simulation {
+        let read_size = std::cmp::max(read_size, 1);
+        self.hooks.dry_run = true;
+        let (willneed, dontneed) = (self.hooks.willneed_calls, self.hooks.dontneed_calls);
+        let mut sim = Simulation::default();
+        let mut files = HashMap::new();
+
+        loop {
+            match self.next_entry() {
+                None => break,
+                Some(Err(_)) => {
+                    sim.failed += 1;
+                    continue
+                }
+                Some(Ok(())) => {}
+            }
+            loop {
+                self.observe(&mut sim, &mut files);
+                let drop = self.dropbehind;
+                let fetch = self.open[0].as_mut().expect("expect that next_entry only leaves successfully opened files at the front");
+                if fetch.read_pos >= fetch.length {
+                    break
+                }
+                let bytes = std::cmp::min(read_size, fetch.length - fetch.read_pos);
+                self.hooks.read(fetch.id, bytes);
+                if let Some((offset, len)) = fetch.consume(bytes, drop) {
+                    fetch.drop_range(offset, len, &mut self.hooks);
+                }
+                self.advance();
+            }
+        }
+        // dropbehind of the last file
+        self.next_group(0);
+
+        sim.willneed_calls = self.hooks.willneed_calls - willneed;
+        sim.dontneed_calls = self.hooks.dontneed_calls - dontneed;
+        sim
+    }
+
+    fn observe(&self, sim: &mut Simulation, files: &mut HashMap<u64, usize>) {
+        let mut in_flight = 0;
+        let mut open = 0;
+        for p in self.open.iter().filter_map(|o| o.as_ref().ok()) {
+            let window = p.outstanding();
+            in_flight += window;
+            open += 1;
+            let idx = *files.entry(p.id).or_insert_with(|| {
+                sim.files.push(SimulatedFile {path: p.info.path.clone(), len: p.length, max_window: 0});
+                sim.files.len() - 1
+            });
+            let f = &mut sim.files[idx];
+            f.max_window = std::cmp::max(f.max_window, window);
+        }
+        sim.peak_in_flight = std::cmp::max(sim.peak_in_flight, in_flight);
+        sim.max_open_files = std::cmp::max(sim.max_open_files, open);
+    }
+}
@@ -0,0 +1,180 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::{BufRead, BufReader, Error, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use Quotas;
+
+/// Source streaming delimited paths from a reader, e.g. the output of `find -print0` on stdin.
+///
+/// Paths are taken as raw bytes, so they don't have to be valid UTF-8. Empty entries are skipped.
+/// Iteration ends at the first read error, which can be retrieved with [`take_error`](#method.take_error).
+pub struct PathList<R> {
+    reader: BufReader<R>,
+    delim: u8,
+    buf: Vec<u8>,
+    error: Option<Error>,
+}
+
+impl<R: Read> PathList<R> {
+
+    pub fn new(reader: R, delim: u8) -> Self {
+        PathList {reader: BufReader::new(reader), delim, buf: Vec::new(), error: None}
+    }
+
+    /// NUL-delimited paths
+    pub fn nul(reader: R) -> Self {
+        Self::new(reader, b'\0')
+    }
+
+    /// Newline-delimited paths
+    pub fn lines(reader: R) -> Self {
+        Self::new(reader, b'\n')
+    }
+
+    pub fn take_error(&mut self) -> Option<Error> {
+        self.error.take()
+    }
+}
+
+impl<R: Read> Iterator for PathList<R> {
+    type Item = PathBuf;
+
+    fn next(&mut self) -> Option<PathBuf> {
+        if self.error.is_some() {
+            return None
+        }
+        loop {
+            self.buf.clear();
+            match self.reader.read_until(self.delim, &mut self.buf) {
+                Ok(0) => return None,
+                Ok(_) => {},
+                Err(e) => {
+                    self.error = Some(e);
+                    return None
+                }
+            }
+            if self.buf.last() == Some(&self.delim) {
+                self.buf.pop();
+            }
+            if !self.buf.is_empty() {
+                return Some(PathBuf::from(OsStr::from_bytes(&self.buf)))
+            }
+        }
+    }
+}
+
+/// Source of paths whose sizes are already known, e.g. from a manifest or an earlier scan.
+///
+/// The sizes are only used for [`Estimate`](struct.Estimate.html)s, the files are still opened and measured as usual.
+pub struct SizedPaths {
+    paths: std::vec::IntoIter<PathBuf>,
+    bytes: u64,
+}
+
+impl SizedPaths {
+
+    pub fn new<I: IntoIterator<Item=(PathBuf, u64)>>(entries: I) -> Self {
+        let mut bytes = 0;
+        let paths: Vec<_> = entries.into_iter().map(|(p, len)| {
+            bytes += len;
+            p
+        }).collect();
+        SizedPaths {paths: paths.into_iter(), bytes}
+    }
+
+    /// Sum of all sizes, including those of paths already taken from the source
+    pub fn total_bytes(&self) -> u64 {
+        self.bytes
+    }
+}
+
+impl Iterator for SizedPaths {
+    type Item = PathBuf;
+
+    fn next(&mut self) -> Option<PathBuf> {
+        self.paths.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.paths.size_hint()
+    }
+}
+
+impl ExactSizeIterator for SizedPaths {}
+
+/// How [`Interleave`](struct.Interleave.html) takes turns between its sources.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Interleaving {
+    /// One path from each source in turn
+    Alternate,
+    /// `n[i]` paths from source `i` in turn, e.g. `[1, 4]` for one index file per four data files
+    Ratio(Vec<usize>),
+}
+
+/// Source taking turns between several sources so that they share the budget of one queue, e.g.
+/// data files and the index files consumed in lockstep with them. Exhausted sources drop out of the rotation.
+pub struct Interleave {
+    sources: Vec<Box<dyn Iterator<Item=PathBuf> + Send>>,
+    weights: Vec<usize>,
+    exhausted: Vec<bool>,
+    current: usize,
+    taken: usize,
+    // which source each path not yet classified came from
+    origins: Option<Arc<Mutex<HashMap<PathBuf, usize>>>>,
+}
+
+impl Interleave {
+
+    pub fn new(sources: Vec<Box<dyn Iterator<Item=PathBuf> + Send>>, policy: Interleaving) -> Self {
+        let weights = match policy {
+            Interleaving::Alternate => vec![1; sources.len()],
+            Interleaving::Ratio(mut w) => {
+                w.resize(sources.len(), 1);
+                w
+            }
+        };
+        let exhausted = weights.iter().map(|&w| w == 0).collect();
+        Interleave {sources, weights, exhausted, current: 0, taken: 0, origins: None}
+    }
+
+    /// [`Quotas`](struct.Quotas.html) that give the files of source `i` at most `limits[i]` bytes of
+    /// prefetch, to be installed on the queue built from this source.
+    pub fn quotas(&mut self, limits: Vec<u64>) -> Quotas {
+        let origins = self.origins.get_or_insert_with(Default::default).clone();
+        Quotas::new(limits, Box::new(move |p| origins.lock().unwrap_or_else(|e| e.into_inner()).remove(p)))
+    }
+}
+
+impl Iterator for Interleave {
+    type Item = PathBuf;
+
+    fn next(&mut self) -> Option<PathBuf> {
+        while self.exhausted.iter().any(|&e| !e) {
+            if self.exhausted[self.current] || self.taken >= self.weights[self.current] {
+                self.current = (self.current + 1) % self.sources.len();
+                self.taken = 0;
+                continue
+            }
+            match self.sources[self.current].next() {
+                Some(p) => {
+                    self.taken += 1;
+                    if let Some(ref origins) = self.origins {
+                        origins.lock().unwrap_or_else(|e| e.into_inner()).insert(p.clone(), self.current);
+                    }
+                    return Some(p)
+                }
+                None => self.exhausted[self.current] = true
+            }
+        }
+        None
+    }
+}
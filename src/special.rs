@@ -0,0 +1,57 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::fmt;
+use std::fs::FileType;
+use std::os::unix::fs::FileTypeExt;
+use std::path::PathBuf;
+use MultiFileReadahead;
+
+/// How FIFOs, character devices and sockets are handled. Their size is meaningless, so they are never prefetched.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpecialFiles {
+    /// Deliver a [`NotRegularFile`](struct.NotRegularFile.html) error in their place
+    Reject,
+    /// Read them until EOF without prefetching
+    Stream,
+}
+
+/// Error payload for rejected special files, obtainable via `io::Error::get_ref` and downcasting.
+#[derive(Debug)]
+pub struct NotRegularFile {
+    pub path: PathBuf,
+    pub file_type: FileType,
+}
+
+impl fmt::Display for NotRegularFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = if self.file_type.is_fifo() {
+            "fifo"
+        } else if self.file_type.is_char_device() {
+            "character device"
+        } else if self.file_type.is_socket() {
+            "socket"
+        } else {
+            "special file"
+        };
+        write!(f, "{} is a {}, not a regular file", self.path.display(), kind)
+    }
+}
+
+impl std::error::Error for NotRegularFile {}
+
+pub(crate) fn is_special(t: FileType) -> bool {
+    t.is_fifo() || t.is_char_device() || t.is_socket()
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Defaults to `SpecialFiles::Stream`.
+    pub fn special_files(&mut self, mode: SpecialFiles) {
+        self.special = mode;
+    }
+}
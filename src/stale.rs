@@ -0,0 +1,42 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::fs::File;
+use std::io::{Error, Seek, SeekFrom};
+use std::path::PathBuf;
+use MultiFileReadahead;
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Reopens a file up to `attempts` times when a read fails with `ESTALE`, which NFS reports for
+    /// descriptors that were held while the file changed on the server, and continues where the
+    /// previous descriptor left off. The error surfaces once the attempts are used up or if
+    /// reopening fails. Off by default.
+    pub fn reopen_stale(&mut self, attempts: Option<u32>) {
+        self.stale_reopens = attempts.unwrap_or(0);
+    }
+
+    // replaces the descriptor of a stale file with a new one at the read position
+    pub(crate) fn reopen(&mut self, idx: usize) -> Result<(), Error> {
+        // advice may still be queued for the old descriptor
+        self.hooks.flush();
+        let fetch = self.open[idx].as_mut().expect("expect that readers are only created for successfully opened files");
+        let mut f = match self.fs {
+            Some(ref mut fs) => fs.open(&fetch.info.path)?,
+            None => File::open(&fetch.info.path)?
+        };
+        f.seek(SeekFrom::Start(fetch.read_pos))?;
+        self.hooks.closed(fetch.id);
+        let sequential = fetch.plan.is_none() && fetch.length >= self.tiny;
+        fetch.id = self.hooks.opened(&fetch.info.path, &f, fetch.length, sequential);
+        fetch.f = f;
+        // whatever was prefetched went with the old descriptor
+        fetch.prefetch_pos = fetch.read_pos;
+        fetch.advised_at = None;
+        Ok(())
+    }
+}
@@ -0,0 +1,166 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::BTreeMap;
+use std::fs::Metadata;
+use std::io::Error;
+use std::path::PathBuf;
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+
+type Stat = (PathBuf, Result<Metadata, Error>);
+
+// hands out source entries to the helpers, at most `depth` past the one the consumer waits for
+struct Dispatch<Src> {
+    src: Src,
+    next_idx: usize,
+    limit: usize,
+    // the source is exhausted or the scan was dropped
+    closed: bool,
+}
+
+struct Shared<Src> {
+    dispatch: Mutex<Dispatch<Src>>,
+    // signaled when the limit moves or the scan is dropped
+    advanced: Condvar,
+}
+
+impl<Src> Shared<Src> {
+    fn lock(&self) -> MutexGuard<'_, Dispatch<Src>> {
+        self.dispatch.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Metadata-only scan that stats paths ahead of the consumer on helper threads, for du/find-style tools.
+///
+/// Yields `(path, metadata)` in source order. Symlinks are not followed unless enabled.
+/// The helper threads are started on the first call to `next`.
+pub struct StatAhead<Src> {
+    source: Option<Src>,
+    threads: usize,
+    depth: usize,
+    follow_links: bool,
+    rx: Option<Receiver<(usize, Stat)>>,
+    shared: Option<Arc<Shared<Src>>>,
+    // results that arrived ahead of their turn
+    reorder: BTreeMap<usize, Stat>,
+    next_idx: usize,
+}
+
+impl<Src> StatAhead<Src> where Src: Iterator<Item=PathBuf> + Send + 'static {
+
+    pub fn new(src: Src) -> Self {
+        StatAhead {source: Some(src), threads: 4, depth: 256, follow_links: false, rx: None, shared: None, reorder: BTreeMap::new(), next_idx: 0}
+    }
+
+    /// Number of helper threads, defaults to 4.
+    pub fn threads(mut self, n: usize) -> Self {
+        self.threads = std::cmp::max(n, 1);
+        self
+    }
+
+    /// How many paths may be stat'ed ahead of the consumer, defaults to 256. That includes those still
+    /// in progress, so a single slow stat, e.g. on a hung network filesystem, stalls the scan after that many.
+    pub fn depth(mut self, n: usize) -> Self {
+        self.depth = std::cmp::max(n, 1);
+        self
+    }
+
+    pub fn follow_links(mut self, v: bool) -> Self {
+        self.follow_links = v;
+        self
+    }
+
+    fn start(&mut self) {
+        let src = match self.source.take() {
+            Some(s) => s,
+            None => return
+        };
+        let shared = Arc::new(Shared {
+            dispatch: Mutex::new(Dispatch {src, next_idx: 0, limit: self.depth, closed: false}),
+            advanced: Condvar::new(),
+        });
+        let (tx, rx) = sync_channel(self.depth);
+        for _ in 0..self.threads {
+            let shared = shared.clone();
+            let tx = tx.clone();
+            let follow = self.follow_links;
+            thread::spawn(move || {
+                loop {
+                    let (idx, p) = {
+                        let mut d = shared.lock();
+                        while d.next_idx >= d.limit && !d.closed {
+                            d = shared.advanced.wait(d).unwrap_or_else(|e| e.into_inner());
+                        }
+                        if d.closed {
+                            return
+                        }
+                        match d.src.next() {
+                            Some(p) => {
+                                d.next_idx += 1;
+                                (d.next_idx - 1, p)
+                            }
+                            None => {
+                                // let the others exit as well so that the consumer sees the end
+                                d.closed = true;
+                                shared.advanced.notify_all();
+                                return
+                            }
+                        }
+                    };
+                    let meta = if follow { std::fs::metadata(&p) } else { std::fs::symlink_metadata(&p) };
+                    if tx.send((idx, (p, meta))).is_err() {
+                        return
+                    }
+                }
+            });
+        }
+        self.rx = Some(rx);
+        self.shared = Some(shared);
+    }
+
+    // lets the helpers stat one more path
+    fn advance(&mut self) {
+        self.next_idx += 1;
+        if let Some(ref shared) = self.shared {
+            shared.lock().limit = self.next_idx + self.depth;
+            shared.advanced.notify_one();
+        }
+    }
+}
+
+impl<Src> Drop for StatAhead<Src> {
+    fn drop(&mut self) {
+        // wake up helpers waiting for the consumer so that they exit
+        if let Some(ref shared) = self.shared {
+            shared.lock().closed = true;
+            shared.advanced.notify_all();
+        }
+    }
+}
+
+impl<Src> Iterator for StatAhead<Src> where Src: Iterator<Item=PathBuf> + Send + 'static {
+    type Item = Stat;
+
+    fn next(&mut self) -> Option<Stat> {
+        self.start();
+        loop {
+            if let Some(s) = self.reorder.remove(&self.next_idx) {
+                self.advance();
+                return Some(s)
+            }
+            match self.rx.as_ref()?.recv() {
+                Ok((idx, s)) => {
+                    self.reorder.insert(idx, s);
+                }
+                // all helpers are done and everything they sent has been received
+                Err(_) => return None
+            }
+        }
+    }
+}
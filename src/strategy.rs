@@ -0,0 +1,253 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::path::{Path, PathBuf};
+use {MultiFileReadahead, Schedule, MAX_OPEN, PREFETCH_BLOCK};
+
+/// A file in the lookahead window as seen by a [`FetchStrategy`](trait.FetchStrategy.html).
+#[derive(Debug, Clone, Copy)]
+pub struct QueuedFile<'a> {
+    pub path: &'a Path,
+    pub length: u64,
+    /// Where the consumer currently reads.
+    pub read_pos: u64,
+    /// Everything up to here is already advised, never behind `read_pos`.
+    pub prefetched: u64,
+    /// Whether the file was already handed out as a reader.
+    pub delivered: bool,
+}
+
+/// Asks the queue to advise the file at index `file` of the window up to offset `until`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fetch {
+    pub file: usize,
+    pub until: u64,
+}
+
+/// Decides which parts of the lookahead window get prefetched next, see
+/// [`MultiFileReadahead::fetch_strategy`](struct.MultiFileReadahead.html#method.fetch_strategy).
+pub trait FetchStrategy {
+    /// Called with the files that can be advised in queue order and the number of bytes that may be advised.
+    ///
+    /// Each fetch extends the prefetched part of a file, so fetches behind what's already prefetched are ignored.
+    /// The queue applies them in order until the budget is spent, the last one gets cut short.
+    fn plan(&mut self, files: &[QueuedFile], budget: u64, fetches: &mut Vec<Fetch>);
+}
+
+// extends files front to back until the budget runs out
+fn sequential(files: &[QueuedFile], pos: &mut [u64], mut budget: u64, fetches: &mut Vec<Fetch>) {
+    for (i, f) in files.iter().enumerate() {
+        if budget < PREFETCH_BLOCK { break }
+        if pos[i] >= f.length { continue }
+        // round up so that readaheads stay aligned
+        let until = std::cmp::min(f.length, (pos[i] + budget).div_ceil(PREFETCH_BLOCK) * PREFETCH_BLOCK);
+        budget = budget.saturating_sub(until - pos[i]);
+        pos[i] = until;
+        fetches.push(Fetch {file: i, until});
+    }
+}
+
+/// The schedules in their basic form, without the quotas, ramping, plans and group shares of the built-in planner.
+impl FetchStrategy for Schedule {
+    fn plan(&mut self, files: &[QueuedFile], budget: u64, fetches: &mut Vec<Fetch>) {
+        let mut pos: Vec<u64> = files.iter().map(|f| f.prefetched).collect();
+        let mut budget = budget;
+        if *self == Schedule::HeadersFirst {
+            let mut headers = budget / 2;
+            for (i, f) in files.iter().enumerate() {
+                let until = std::cmp::min(f.length, PREFETCH_BLOCK);
+                if pos[i] >= until { continue }
+                if headers < until - pos[i] { break }
+                headers -= until - pos[i];
+                budget -= until - pos[i];
+                pos[i] = until;
+                fetches.push(Fetch {file: i, until});
+            }
+        }
+        sequential(files, &mut pos, budget, fetches);
+    }
+}
+
+// the strategy along with buffers reused across planning steps
+pub(crate) struct Strategy {
+    strategy: Box<dyn FetchStrategy + Send>,
+    fetches: Vec<Fetch>,
+    indices: Vec<usize>,
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Replaces the built-in planner with `strategy`, which then decides on its own which files are
+    /// prefetched how far. Passing `Box::new(Schedule::Sequential)` is a starting point for experiments.
+    ///
+    /// The queue still opens files and enforces the budget and rate limit. Overdue files of the
+    /// [`prefetch_deadline`](#method.prefetch_deadline) and bitrate-limited ones are still advised by the queue itself.
+    /// Hardlinked duplicates, warm and unadvisable files and those with a prefetch plan or bitrate
+    /// window aren't offered to the strategy. `None` restores the built-in planner.
+    pub fn fetch_strategy(&mut self, strategy: Option<Box<dyn FetchStrategy + Send>>) {
+        self.strategy = strategy.map(|strategy| Strategy {strategy, fetches: Vec::new(), indices: Vec::new()});
+    }
+
+    // lets the strategy spend the budget, returns the amount issued
+    pub(crate) fn advise_strategy(&mut self, budget: u64) -> u64 {
+        // open files until what's left of them covers the budget
+        let mut ahead = self.open.iter().filter_map(|o| o.as_ref().ok()).map(|p| p.length.saturating_sub(std::cmp::max(p.read_pos, p.prefetch_pos))).sum::<u64>();
+        while ahead < budget && self.open.len() < std::cmp::min(self.open_limit, MAX_OPEN) {
+            let before = self.open.len();
+            if !self.add_file() {
+                break
+            }
+            ahead += self.open.iter().skip(before).filter_map(|o| o.as_ref().ok()).map(|p| p.length).sum::<u64>();
+        }
+
+        let s = self.strategy.as_mut().expect("expect that strategies are only consulted when set");
+        s.fetches.clear();
+        s.indices.clear();
+        let delivered = self.delivered;
+        let files = self.open.iter().enumerate().filter_map(|(i, o)| match *o {
+            Ok(ref p) if p.info.link_of.is_none() && !p.warm && !p.unadvisable && p.plan.is_none() && p.window.is_none() && !(p.stale && i > delivered) => {
+                s.indices.push(i);
+                Some(QueuedFile {
+                    path: &p.info.path,
+                    length: p.length,
+                    read_pos: p.read_pos,
+                    prefetched: std::cmp::max(p.read_pos, p.prefetch_pos),
+                    delivered: i < delivered,
+                })
+            }
+            _ => None
+        }).collect::<Vec<_>>();
+        s.strategy.plan(&files, budget, &mut s.fetches);
+
+        let ttl = self.ttl.is_some();
+        let mut issued = 0;
+        for f in &s.fetches {
+            let i = match s.indices.get(f.file) {
+                Some(&i) => i,
+                None => continue
+            };
+            let p = match self.open[i] {
+                Ok(ref mut p) => p,
+                Err(_) => continue
+            };
+            let start = std::cmp::max(p.read_pos, p.prefetch_pos);
+            let end = std::cmp::min(std::cmp::min(f.until, p.length), start + (budget - issued));
+            if end <= start { continue }
+            p.stale = false;
+            p.advise(start, end, ttl, &mut self.hooks);
+            issued += end - start;
+            if issued >= budget { break }
+        }
+        issued
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fs::Filesystem;
+    use std::fs::File;
+    use std::io::{Error, Read, Write};
+    use std::os::unix::io::FromRawFd;
+    use std::sync::{Arc, Mutex};
+    use testutil::{Files, Queue};
+    use {Advice, MemoryFs, SpecialFiles};
+
+    // serves "/pipe" as a FIFO holding `piped`, everything else from memory
+    struct PipeFs {
+        files: MemoryFs,
+        piped: Vec<u8>,
+    }
+
+    impl Filesystem for PipeFs {
+        fn open(&mut self, path: &Path) -> Result<File, Error> {
+            if path != Path::new("/pipe") {
+                return self.files.open(path)
+            }
+            let mut fds = [0; 2];
+            if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+                return Err(Error::last_os_error())
+            }
+            let (rx, mut tx) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+            tx.write_all(&self.piped)?;
+            Ok(rx)
+        }
+    }
+
+    type Advised = Arc<Mutex<Vec<(u64, u64, Advice)>>>;
+
+    fn queue(files: &Files, order: &[&str], piped: usize) -> (Queue, Advised) {
+        let mut q = files.queue(order);
+        q.filesystem(Some(Box::new(PipeFs {files: files.fs(), piped: vec![2u8; piped]})));
+        q.special_files(SpecialFiles::Stream);
+        q.fetch_strategy(Some(Box::new(Schedule::Sequential)));
+        let advised = Advised::default();
+        let log = advised.clone();
+        q.advise_with(Some(Box::new(move |_, offset, len, advice| log.lock().unwrap().push((offset, len, advice)))));
+        (q, advised)
+    }
+
+    fn will_need(advised: &Advised) -> Vec<(u64, u64)> {
+        advised.lock().unwrap().iter().filter(|a| a.2 == Advice::WillNeed).map(|a| (a.0, a.1)).collect()
+    }
+
+    #[test]
+    fn stream_read_past_its_length() {
+        let files = Files::new(&[("/a", &[1; 3 * PREFETCH_BLOCK as usize])]);
+        let (mut q, advised) = queue(&files, &["/pipe", "/a"], 1000);
+        {
+            let mut r = q.next().unwrap().unwrap();
+            let mut buf = [0u8; 100];
+            let mut total = 0;
+            // every read plans with the stream's position ahead of its zero length
+            loop {
+                match r.read(&mut buf).unwrap() {
+                    0 => break,
+                    n => total += n
+                }
+            }
+            assert_eq!(total, 1000);
+        }
+        let mut r = q.next().unwrap().unwrap();
+        let mut data = Vec::new();
+        r.read_to_end(&mut data).unwrap();
+        assert_eq!(data.len(), 3 * PREFETCH_BLOCK as usize);
+        assert!(q.next().is_none());
+        // only the regular file is advised, in full and exactly once
+        let ranges = will_need(&advised);
+        assert_eq!(ranges.iter().map(|r| r.1).sum::<u64>(), 3 * PREFETCH_BLOCK);
+        assert!(ranges.windows(2).all(|w| w[0].0 + w[0].1 == w[1].0));
+    }
+
+    #[test]
+    fn sequential_fetches_stay_within_files() {
+        let mut files = Vec::new();
+        let mut pos = vec![0; 3];
+        let lengths = [PREFETCH_BLOCK / 2, 3 * PREFETCH_BLOCK, 2 * PREFETCH_BLOCK];
+        for (i, &length) in lengths.iter().enumerate() {
+            files.push(QueuedFile {path: Path::new("/x"), length, read_pos: 0, prefetched: 0, delivered: i == 0});
+        }
+        let mut fetches = Vec::new();
+        sequential(&files, &mut pos, 2 * PREFETCH_BLOCK, &mut fetches);
+        assert_eq!(fetches, vec![Fetch {file: 0, until: PREFETCH_BLOCK / 2}, Fetch {file: 1, until: 2 * PREFETCH_BLOCK}]);
+        assert_eq!(pos, vec![PREFETCH_BLOCK / 2, 2 * PREFETCH_BLOCK, 0]);
+    }
+
+    #[test]
+    fn headers_first() {
+        let files: Vec<_> = [4 * PREFETCH_BLOCK, 4 * PREFETCH_BLOCK].iter().map(|&length| {
+            QueuedFile {path: Path::new("/x"), length, read_pos: 0, prefetched: 0, delivered: false}
+        }).collect();
+        let mut fetches = Vec::new();
+        Schedule::HeadersFirst.plan(&files, 4 * PREFETCH_BLOCK, &mut fetches);
+        assert_eq!(fetches, vec![
+            Fetch {file: 0, until: PREFETCH_BLOCK},
+            Fetch {file: 1, until: PREFETCH_BLOCK},
+            Fetch {file: 0, until: 3 * PREFETCH_BLOCK},
+        ]);
+    }
+}
@@ -0,0 +1,400 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::fs::{File, OpenOptions};
+use std::io::Error;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use advise;
+
+// st_size is 0 for block devices, ask the device itself
+#[cfg(target_os = "linux")]
+pub(crate) fn block_device_size(f: &File) -> Result<u64, Error> {
+    const BLKGETSIZE64: libc::Ioctl = libc::_IOR::<u64>(0x12, 114);
+    let mut size: u64 = 0;
+    if unsafe { libc::ioctl(f.as_raw_fd(), BLKGETSIZE64, &mut size) } < 0 {
+        return Err(Error::last_os_error())
+    }
+    Ok(size)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn block_device_size(mut f: &File) -> Result<u64, Error> {
+    use std::io::{Seek, SeekFrom};
+
+    let size = f.seek(SeekFrom::End(0))?;
+    f.seek(SeekFrom::Start(0))?;
+    Ok(size)
+}
+
+// Solaris has no posix_fadvise at all, illumos accepts it but mostly ignores it
+#[cfg(any(target_os = "illumos", target_os = "solaris"))]
+extern "C" {
+    fn directio(fildes: libc::c_int, advice: libc::c_int) -> libc::c_int;
+}
+
+#[cfg(any(target_os = "illumos", target_os = "solaris"))]
+const DIRECTIO_ON: libc::c_int = 1;
+
+pub(crate) fn advise_sequential(f: &File) {
+    let _ = advise::sequential(f, 0, 0);
+    // make sure the heuristic readahead is on, advise_willneed sizes it
+    #[cfg(target_os = "freebsd")]
+    unsafe {
+        libc::fcntl(f.as_raw_fd(), libc::F_RDAHEAD, 1);
+    }
+}
+
+// records are read out of order, kernel readahead would only pull in unwanted data
+pub(crate) fn advise_random(f: &File) {
+    let _ = advise::random(f, 0, 0);
+}
+
+pub(crate) fn advise_willneed(f: &File, offset: u64, len: u64) -> Result<(), Error> {
+    let result = advise::willneed(f, offset, len);
+    // most FreeBSD filesystems ignore WILLNEED, widen the per-descriptor readahead to
+    // cover everything up to the end of the range instead
+    #[cfg(target_os = "freebsd")]
+    unsafe {
+        let pos = libc::lseek(f.as_raw_fd(), 0, libc::SEEK_CUR);
+        if pos >= 0 {
+            let window = (offset + len).saturating_sub(pos as u64);
+            libc::fcntl(f.as_raw_fd(), libc::F_READAHEAD, std::cmp::min(window, libc::c_int::MAX as u64) as libc::c_int);
+        }
+    }
+    result
+}
+
+pub(crate) fn advise_dontneed(f: &File, offset: u64, len: u64) {
+    let _ = advise::dontneed(f, offset, len);
+    // pages can't be dropped here, but the rest of the file can bypass the cache instead.
+    // UFS honors this, other filesystems reject it which leaves reads cached
+    #[cfg(any(target_os = "illumos", target_os = "solaris"))]
+    unsafe {
+        directio(f.as_raw_fd(), DIRECTIO_ON);
+    }
+}
+
+// non-blocking so that opening a FIFO without writer doesn't hang the planner
+pub(crate) fn open_nonblocking(p: &Path) -> Result<File, Error> {
+    OpenOptions::new().read(true).custom_flags(libc::O_NONBLOCK).open(p)
+}
+
+pub(crate) fn clear_nonblocking(f: &File) -> Result<(), Error> {
+    unsafe {
+        let flags = libc::fcntl(f.as_raw_fd(), libc::F_GETFL);
+        if flags < 0 || libc::fcntl(f.as_raw_fd(), libc::F_SETFL, flags & !libc::O_NONBLOCK) < 0 {
+            return Err(Error::last_os_error())
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn anonymous_file() -> Result<File, Error> {
+    use std::os::unix::io::FromRawFd;
+
+    let fd = unsafe { libc::memfd_create(b"reapfrog\0".as_ptr() as *const libc::c_char, libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(Error::last_os_error())
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn anonymous_file() -> Result<File, Error> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let name = format!("reapfrog-{}-{}", std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed));
+    let p = std::env::temp_dir().join(name);
+    let f = OpenOptions::new().read(true).write(true).create_new(true).open(&p)?;
+    std::fs::remove_file(&p)?;
+    Ok(f)
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn start_writeback(f: &File, offset: u64, len: u64) {
+    unsafe {
+        libc::sync_file_range(f.as_raw_fd(), offset as i64, len as i64, libc::SYNC_FILE_RANGE_WRITE);
+    }
+}
+
+// nothing to kick off writeback asynchronously, wait_writeback does the work
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn start_writeback(_f: &File, _offset: u64, _len: u64) {}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn wait_writeback(f: &File, offset: u64, len: u64) -> Result<(), Error> {
+    let flags = libc::SYNC_FILE_RANGE_WAIT_BEFORE | libc::SYNC_FILE_RANGE_WRITE | libc::SYNC_FILE_RANGE_WAIT_AFTER;
+    if unsafe { libc::sync_file_range(f.as_raw_fd(), offset as i64, len as i64, flags) } < 0 {
+        return Err(Error::last_os_error())
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn wait_writeback(f: &File, _offset: u64, _len: u64) -> Result<(), Error> {
+    f.sync_data()
+}
+
+// both use and advance the file offsets, so they can be mixed with plain reads and writes
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+pub(crate) fn copy_file_range(from: &File, to: &File, len: usize) -> Result<usize, Error> {
+    let n = unsafe { libc::copy_file_range(from.as_raw_fd(), std::ptr::null_mut(), to.as_raw_fd(), std::ptr::null_mut(), len, 0) };
+    if n < 0 {
+        return Err(Error::last_os_error())
+    }
+    Ok(n as usize)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "freebsd")))]
+pub(crate) fn copy_file_range(_from: &File, _to: &File, _len: usize) -> Result<usize, Error> {
+    Err(Error::from(std::io::ErrorKind::Unsupported))
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn sendfile(from: &File, to: &File, len: usize) -> Result<usize, Error> {
+    let n = unsafe { libc::sendfile(to.as_raw_fd(), from.as_raw_fd(), std::ptr::null_mut(), len) };
+    if n < 0 {
+        return Err(Error::last_os_error())
+    }
+    Ok(n as usize)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn sendfile(_from: &File, _to: &File, _len: usize) -> Result<usize, Error> {
+    Err(Error::from(std::io::ErrorKind::Unsupported))
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn clone_file(from: &File, to: &File) -> Result<(), Error> {
+    if unsafe { libc::ioctl(to.as_raw_fd(), libc::FICLONE, from.as_raw_fd()) } < 0 {
+        return Err(Error::last_os_error())
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn clone_file(_from: &File, _to: &File) -> Result<(), Error> {
+    Err(Error::from(std::io::ErrorKind::Unsupported))
+}
+
+// whether the first len bytes of the file are in the page cache
+pub(crate) fn is_resident(f: &File, len: u64) -> Result<bool, Error> {
+    let len = len as usize;
+    if len == 0 {
+        return Ok(true)
+    }
+    let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let pages = len.div_ceil(page);
+    let mut vec = vec![0u8; pages];
+    unsafe {
+        let addr = libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ, libc::MAP_SHARED, f.as_raw_fd(), 0);
+        if addr == libc::MAP_FAILED {
+            return Err(Error::last_os_error())
+        }
+        let ret = libc::mincore(addr as _, len, vec.as_mut_ptr() as _);
+        let e = Error::last_os_error();
+        libc::munmap(addr, len);
+        if ret < 0 {
+            return Err(e)
+        }
+    }
+    Ok(vec.iter().all(|&b| b & 1 == 1))
+}
+
+// a non-blocking descriptor pair that polls readable while signalled
+#[cfg(target_os = "linux")]
+pub(crate) fn notification_fd() -> Result<(File, File), Error> {
+    use std::os::unix::io::FromRawFd;
+
+    let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+    if fd < 0 {
+        return Err(Error::last_os_error())
+    }
+    let f = unsafe { File::from_raw_fd(fd) };
+    let w = f.try_clone()?;
+    Ok((f, w))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn notification_fd() -> Result<(File, File), Error> {
+    use std::os::unix::io::FromRawFd;
+
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) } < 0 {
+        return Err(Error::last_os_error())
+    }
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+// bytes of memory the process could still use without evicting anything
+#[cfg(target_os = "linux")]
+pub(crate) fn available_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let kb = meminfo.lines().find_map(|l| l.strip_prefix("MemAvailable:"))?.trim().trim_end_matches("kB").trim().parse::<u64>().ok()?;
+    let system = kb * 1024;
+
+    let groups = std::fs::read_to_string("/proc/self/cgroup").unwrap_or_default();
+    let read = |p: std::path::PathBuf| std::fs::read_to_string(p).ok()?.trim().parse::<u64>().ok();
+    let cgroup = groups.lines().find_map(|l| {
+        let mut parts = l.splitn(3, ':');
+        let (_, controllers, group) = (parts.next()?, parts.next()?, parts.next()?.trim_start_matches('/'));
+        let (limit, usage) = match controllers {
+            // v1, unlimited groups report a huge limit
+            "memory" => {
+                let dir = Path::new("/sys/fs/cgroup/memory").join(group);
+                (read(dir.join("memory.limit_in_bytes"))?, read(dir.join("memory.usage_in_bytes"))?)
+            }
+            // v2, "max" if unlimited
+            "" => {
+                let dir = Path::new("/sys/fs/cgroup").join(group);
+                (read(dir.join("memory.max"))?, read(dir.join("memory.current"))?)
+            }
+            _ => return None
+        };
+        Some(limit.saturating_sub(usage))
+    });
+    Some(cgroup.map_or(system, |c| std::cmp::min(c, system)))
+}
+
+#[cfg(target_os = "freebsd")]
+pub(crate) fn available_memory() -> Option<u64> {
+    let mut free: libc::c_uint = 0;
+    let mut len = std::mem::size_of::<libc::c_uint>();
+    let ret = unsafe { libc::sysctlbyname(b"vm.stats.vm.v_free_count\0".as_ptr() as *const libc::c_char, &mut free as *mut _ as *mut libc::c_void, &mut len, std::ptr::null(), 0) };
+    if ret < 0 {
+        return None
+    }
+    let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Some(free as u64 * page as u64)
+}
+
+#[cfg(any(target_os = "illumos", target_os = "solaris"))]
+pub(crate) fn available_memory() -> Option<u64> {
+    let (pages, page) = unsafe { (libc::sysconf(libc::_SC_AVPHYS_PAGES), libc::sysconf(libc::_SC_PAGESIZE)) };
+    if pages < 0 || page < 0 {
+        return None
+    }
+    Some(pages as u64 * page as u64)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "freebsd", target_os = "illumos", target_os = "solaris")))]
+pub(crate) fn available_memory() -> Option<u64> {
+    None
+}
+
+// whether the block device is a spinning disk, None if it's not a local block device
+#[cfg(target_os = "linux")]
+pub(crate) fn is_rotational(dev: u64) -> Option<bool> {
+    let (major, minor) = (libc::major(dev), libc::minor(dev));
+    let dir = format!("/sys/dev/block/{}:{}", major, minor);
+    // partitions share the queue of their disk
+    let flag = std::fs::read_to_string(format!("{}/queue/rotational", dir))
+        .or_else(|_| std::fs::read_to_string(format!("{}/../queue/rotational", dir))).ok()?;
+    Some(flag.trim() == "1")
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn is_rotational(_dev: u64) -> Option<bool> {
+    None
+}
+
+// the preferred I/O size of the filesystem the file is on
+pub(crate) fn fs_block_size(f: &File) -> Option<u64> {
+    let mut st = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    if unsafe { libc::fstatvfs(f.as_raw_fd(), st.as_mut_ptr()) } < 0 {
+        return None
+    }
+    // narrower on 32-bit targets
+    #[allow(clippy::unnecessary_cast)]
+    Some(unsafe { st.assume_init() }.f_bsize as u64)
+}
+
+// the I/O size the block device prefers, e.g. the stripe width of a RAID, None if it doesn't say
+#[cfg(target_os = "linux")]
+pub(crate) fn optimal_io_size(dev: u64) -> Option<u64> {
+    let dir = format!("/sys/dev/block/{}:{}", libc::major(dev), libc::minor(dev));
+    let size = std::fs::read_to_string(format!("{}/queue/optimal_io_size", dir))
+        .or_else(|_| std::fs::read_to_string(format!("{}/../queue/optimal_io_size", dir))).ok()?;
+    size.trim().parse().ok().filter(|&s| s > 0)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn optimal_io_size(_dev: u64) -> Option<u64> {
+    None
+}
+
+// cumulative time in microseconds during which some task was stalled on I/O
+#[cfg(target_os = "linux")]
+pub(crate) fn io_stall_micros() -> Option<u64> {
+    let psi = std::fs::read_to_string("/proc/pressure/io").ok()?;
+    let some = psi.lines().find_map(|l| l.strip_prefix("some "))?;
+    some.split_whitespace().find_map(|f| f.strip_prefix("total="))?.parse().ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn io_stall_micros() -> Option<u64> {
+    None
+}
+
+// bytes the whole process caused to be fetched from storage so far
+#[cfg(target_os = "linux")]
+pub(crate) fn storage_read_bytes() -> Option<u64> {
+    let io = std::fs::read_to_string("/proc/self/io").ok()?;
+    io.lines().find_map(|l| l.strip_prefix("read_bytes:"))?.trim().parse().ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn storage_read_bytes() -> Option<u64> {
+    None
+}
+
+// a file in dir without a name until it's linked somewhere
+#[cfg(target_os = "linux")]
+pub(crate) fn unnamed_file(dir: &Path, mode: u32) -> Result<File, Error> {
+    OpenOptions::new().write(true).custom_flags(libc::O_TMPFILE).mode(mode).open(dir)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn unnamed_file(_dir: &Path, _mode: u32) -> Result<File, Error> {
+    Err(Error::from(std::io::ErrorKind::Unsupported))
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn link_file(f: &File, to: &Path) -> Result<(), Error> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let from = CString::new(format!("/proc/self/fd/{}", f.as_raw_fd())).unwrap();
+    let to = CString::new(to.as_os_str().as_bytes()).map_err(|_| Error::from(std::io::ErrorKind::InvalidInput))?;
+    if unsafe { libc::linkat(libc::AT_FDCWD, from.as_ptr(), libc::AT_FDCWD, to.as_ptr(), libc::AT_SYMLINK_FOLLOW) } < 0 {
+        return Err(Error::last_os_error())
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn link_file(_f: &File, _to: &Path) -> Result<(), Error> {
+    Err(Error::from(std::io::ErrorKind::Unsupported))
+}
+
+// reserves blocks without changing the file size
+#[cfg(target_os = "linux")]
+pub(crate) fn preallocate(f: &File, len: u64) -> Result<(), Error> {
+    if unsafe { libc::fallocate(f.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, len as libc::off_t) } < 0 {
+        return Err(Error::last_os_error())
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn preallocate(_f: &File, _len: u64) -> Result<(), Error> {
+    Err(Error::from(std::io::ErrorKind::Unsupported))
+}
@@ -0,0 +1,41 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::io::{Error, ErrorKind, Write};
+use std::path::PathBuf;
+use Reader;
+
+const TEE_BUFFER: usize = 128 * 1024;
+
+impl<'a, T> Reader<'a, T> where T: Iterator<Item=PathBuf> {
+
+    /// Copies the rest of the file to both sinks in a single pass, e.g. to hash while writing a copy.
+    ///
+    /// Uses a buffer owned by the queue that is reused across files. Returns the number of bytes copied.
+    pub fn tee<A: Write, B: Write>(&mut self, a: &mut A, b: &mut B) -> Result<u64, Error> {
+        let mut buf = std::mem::take(&mut self.owner.scratch);
+        buf.resize(TEE_BUFFER, 0);
+        let result = self.tee_with(&mut buf, a, b);
+        self.owner.scratch = buf;
+        result
+    }
+
+    fn tee_with<A: Write, B: Write>(&mut self, buf: &mut [u8], a: &mut A, b: &mut B) -> Result<u64, Error> {
+        let mut total = 0;
+        loop {
+            let n = match self.owner.read_entry(self.idx, buf) {
+                Ok(0) => return Ok(total),
+                Ok(n) => n,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e)
+            };
+            a.write_all(&buf[..n])?;
+            b.write_all(&buf[..n])?;
+            total += n as u64;
+        }
+    }
+}
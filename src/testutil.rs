@@ -0,0 +1,60 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// fixtures shared by the unit tests
+
+use std::path::PathBuf;
+use {MemoryFs, MultiFileReadahead};
+
+pub(crate) type Queue = MultiFileReadahead<std::vec::IntoIter<PathBuf>>;
+
+/// Files with fixed contents for a test, served from memory.
+///
+/// Names that were never created fail to open.
+pub(crate) struct Files {
+    files: Vec<(PathBuf, Vec<u8>)>,
+}
+
+impl Files {
+
+    pub(crate) fn new(files: &[(&str, &[u8])]) -> Self {
+        Files {files: files.iter().map(|&(name, contents)| (PathBuf::from(name), contents.to_vec())).collect()}
+    }
+
+    /// The path a name is queued under.
+    pub(crate) fn path(&self, name: &str) -> PathBuf {
+        PathBuf::from(name)
+    }
+
+    /// A filesystem serving the files, for queues the test sets up itself.
+    pub(crate) fn fs(&self) -> MemoryFs {
+        let mut fs = MemoryFs::new();
+        for (path, contents) in &self.files {
+            fs.insert(path.clone(), contents.clone());
+        }
+        fs
+    }
+
+    /// A queue over the named files in the given order.
+    pub(crate) fn queue(&self, order: &[&str]) -> Queue {
+        let mut q = MultiFileReadahead::new(order.iter().map(|name| self.path(name)).collect::<Vec<_>>().into_iter());
+        q.filesystem(Some(Box::new(self.fs())));
+        q
+    }
+}
+
+/// Reads the file at the front of the queue to its end.
+pub(crate) fn read_front<Src: Iterator<Item=PathBuf>>(q: &mut MultiFileReadahead<Src>) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        match q.read_entry(0, &mut buf).unwrap() {
+            0 => return data,
+            n => data.extend_from_slice(&buf[..n])
+        }
+    }
+}
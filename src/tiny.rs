@@ -0,0 +1,20 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::path::PathBuf;
+use MultiFileReadahead;
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Files shorter than `threshold` get a single whole-file advice while queued and none at all
+    /// once they are being read, since the read follows right away. They also skip the sequential
+    /// access advice given on open. This saves syscalls on workloads made up of many small files,
+    /// a threshold of one or two prefetch blocks (64KiB each) is a good start.
+    pub fn tiny_files(&mut self, threshold: Option<u64>) {
+        self.tiny = threshold.unwrap_or(0);
+    }
+}
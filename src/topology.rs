@@ -0,0 +1,66 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use {sys, MultiFileReadahead, Prefetch, DROPBEHIND_BLOCK, PREFETCH_BLOCK};
+
+// network filesystems may report huge blocks, don't let them blow up the windows
+const MAX_UNIT: u64 = 16 * 1024 * 1024;
+
+// I/O unit per device, 0 if there's nothing to align to
+pub(crate) struct Topology {
+    units: HashMap<u64, u64>,
+}
+
+fn io_unit(f: &File, dev: u64) -> u64 {
+    let block = sys::fs_block_size(f).unwrap_or(0);
+    let optimal = sys::optimal_io_size(dev).unwrap_or(0);
+    std::cmp::min(std::cmp::max(block, optimal), MAX_UNIT)
+}
+
+fn round_up(v: u64, unit: u64) -> u64 {
+    v.div_ceil(unit) * unit
+}
+
+impl Prefetch {
+    fn align_to(&mut self, unit: u64) {
+        if unit == 0 {
+            return
+        }
+        self.align = round_up(PREFETCH_BLOCK, unit);
+        self.drop_block = round_up(DROPBEHIND_BLOCK, unit);
+    }
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Aligns the ends of prefetched ranges and dropbehind to the I/O unit of the device a file is on,
+    /// instead of 64KiB and 512KiB. The unit is the larger of the filesystem's preferred block size
+    /// and, on Linux, the `optimal_io_size` a block device reports in sysfs, e.g. the stripe width of a RAID.
+    ///
+    /// Windows and dropped ranges become multiples of that unit, so they may grow on filesystems with
+    /// large blocks. Both are looked up once per device. Piece alignment takes precedence.
+    pub fn topology_alignment(&mut self, enabled: bool) {
+        self.topology = if enabled { Some(Topology {units: HashMap::new()}) } else { None };
+    }
+
+    pub(crate) fn apply_topology(&mut self, fetch: &mut Prefetch) {
+        let t = match self.topology {
+            Some(ref mut t) => t,
+            None => return
+        };
+        let dev = match fetch.f.metadata() {
+            Ok(m) => m.dev(),
+            Err(_) => return
+        };
+        let unit = *t.units.entry(dev).or_insert_with(|| io_unit(&fetch.f, dev));
+        fetch.align_to(unit);
+    }
+}
@@ -0,0 +1,340 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::{HashMap, VecDeque};
+use std::ffi::OsStr;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use {MultiFileReadahead, PREFETCH_BLOCK};
+
+const MAGIC: &[u8; 8] = b"RFTRACE1";
+
+const OPEN: u8 = 1;
+const OPEN_FAILED: u8 = 2;
+const READ: u8 = 3;
+const WILLNEED: u8 = 4;
+const DONTNEED: u8 = 5;
+const CLOSE: u8 = 6;
+
+/// An event of a trace recorded with [`trace`](struct.MultiFileReadahead.html#method.trace).
+/// Files are identified by the order in which they were opened.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TraceEvent {
+    Open {id: u64, path: PathBuf, len: u64},
+    OpenFailed {path: PathBuf},
+    /// The consumer read this many bytes
+    Read {id: u64, bytes: u64},
+    WillNeed {id: u64, offset: u64, len: u64},
+    DontNeed {id: u64, offset: u64, len: u64},
+    /// The consumer moved on from the file
+    Close {id: u64},
+}
+
+// events are a tag byte followed by LEB128 integers, paths are length-prefixed bytes
+pub(crate) struct Tracer {
+    w: BufWriter<Box<dyn Write + Send>>,
+    error: Option<Error>,
+}
+
+impl Tracer {
+    fn new(w: Box<dyn Write + Send>) -> Self {
+        let mut t = Tracer {w: BufWriter::new(w), error: None};
+        t.error = t.w.write_all(MAGIC).err();
+        t
+    }
+
+    fn event(&mut self, tag: u8, values: &[u64], path: Option<&Path>) {
+        if self.error.is_some() {
+            return
+        }
+        let mut buf = Vec::with_capacity(32);
+        buf.push(tag);
+        for &v in values {
+            put_varint(&mut buf, v);
+        }
+        if let Some(p) = path {
+            let bytes = p.as_os_str().as_bytes();
+            put_varint(&mut buf, bytes.len() as u64);
+            buf.extend_from_slice(bytes);
+        }
+        self.error = self.w.write_all(&buf).err();
+    }
+
+    pub(crate) fn opened(&mut self, id: u64, p: &Path, len: u64) {
+        self.event(OPEN, &[id, len], Some(p));
+    }
+
+    pub(crate) fn open_failed(&mut self, p: &Path) {
+        self.event(OPEN_FAILED, &[], Some(p));
+    }
+
+    pub(crate) fn read(&mut self, id: u64, bytes: u64) {
+        self.event(READ, &[id, bytes], None);
+    }
+
+    pub(crate) fn willneed(&mut self, id: u64, offset: u64, len: u64) {
+        self.event(WILLNEED, &[id, offset, len], None);
+    }
+
+    pub(crate) fn dontneed(&mut self, id: u64, offset: u64, len: u64) {
+        self.event(DONTNEED, &[id, offset, len], None);
+    }
+
+    pub(crate) fn closed(&mut self, id: u64) {
+        self.event(CLOSE, &[id], None);
+    }
+
+    fn finish(mut self) -> Result<(), Error> {
+        if let Some(e) = self.error.take() {
+            return Err(e)
+        }
+        self.w.flush()
+    }
+}
+
+pub(crate) fn put_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+pub(crate) fn get_varint<R: Read>(r: &mut R) -> Result<u64, Error> {
+    let mut v = 0u64;
+    for shift in (0..64).step_by(7) {
+        let mut b = [0u8];
+        r.read_exact(&mut b)?;
+        v |= u64::from(b[0] & 0x7f) << shift;
+        if b[0] & 0x80 == 0 {
+            return Ok(v)
+        }
+    }
+    Err(Error::new(ErrorKind::InvalidData, "overlong integer"))
+}
+
+pub(crate) fn get_path<R: Read>(r: &mut R) -> Result<PathBuf, Error> {
+    let len = get_varint(r)?;
+    // grow the buffer along with the data instead of trusting a possibly corrupt length
+    let mut bytes = Vec::new();
+    r.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(Error::new(ErrorKind::InvalidData, "path extends past the end of the data"))
+    }
+    Ok(PathBuf::from(OsStr::from_bytes(&bytes)))
+}
+
+/// Parses a trace recorded with [`trace`](struct.MultiFileReadahead.html#method.trace).
+pub fn read_trace<R: Read>(r: R) -> Result<Vec<TraceEvent>, Error> {
+    let mut r = BufReader::new(r);
+    let mut magic = [0u8; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(Error::new(ErrorKind::InvalidData, "not a reapfrog trace"))
+    }
+    let mut events = Vec::new();
+    loop {
+        let mut tag = [0u8];
+        match r.read(&mut tag) {
+            Ok(0) => return Ok(events),
+            Ok(_) => {}
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        }
+        let event = match tag[0] {
+            OPEN => {
+                let (id, len) = (get_varint(&mut r)?, get_varint(&mut r)?);
+                TraceEvent::Open {id, len, path: get_path(&mut r)?}
+            }
+            OPEN_FAILED => TraceEvent::OpenFailed {path: get_path(&mut r)?},
+            READ => TraceEvent::Read {id: get_varint(&mut r)?, bytes: get_varint(&mut r)?},
+            WILLNEED => TraceEvent::WillNeed {id: get_varint(&mut r)?, offset: get_varint(&mut r)?, len: get_varint(&mut r)?},
+            DONTNEED => TraceEvent::DontNeed {id: get_varint(&mut r)?, offset: get_varint(&mut r)?, len: get_varint(&mut r)?},
+            CLOSE => TraceEvent::Close {id: get_varint(&mut r)?},
+            _ => return Err(Error::new(ErrorKind::InvalidData, "unknown event in trace"))
+        };
+        events.push(event);
+    }
+}
+
+/// Re-executes the consumer side of a trace against a fresh queue configured by `configure`:
+/// the traced paths are queued in their original order and read in chunks of the recorded sizes.
+///
+/// The planner of the new queue makes its own decisions, so recording a trace of the replay
+/// allows comparing scheduler changes or settings on a real workload. Reads are replayed one file
+/// at a time. Returns the number of bytes read, stops at the first read error.
+pub fn replay_trace<F>(events: &[TraceEvent], configure: F) -> Result<u64, Error>
+    where F: FnOnce(&mut MultiFileReadahead<std::vec::IntoIter<PathBuf>>)
+{
+    let mut paths = Vec::new();
+    let mut reads: HashMap<u64, Vec<u64>> = HashMap::new();
+    let mut ids = Vec::new();
+    for e in events {
+        match *e {
+            TraceEvent::Open {id, ref path, ..} => {
+                paths.push(path.clone());
+                ids.push((path.clone(), id));
+            }
+            TraceEvent::OpenFailed {ref path} => paths.push(path.clone()),
+            TraceEvent::Read {id, bytes} => reads.entry(id).or_default().push(bytes),
+            _ => {}
+        }
+    }
+    // match files by path so that a file failing differently than in the trace doesn't shift the rest
+    let mut by_path: HashMap<PathBuf, VecDeque<Vec<u64>>> = HashMap::new();
+    for (path, id) in ids {
+        by_path.entry(path).or_default().push_back(reads.remove(&id).unwrap_or_default());
+    }
+
+    let mut q = MultiFileReadahead::new(paths.into_iter());
+    configure(&mut q);
+    let mut buf = vec![0; PREFETCH_BLOCK as usize];
+    let mut total = 0;
+    while let Some(entry) = q.next_entry() {
+        if entry.is_err() {
+            continue
+        }
+        let path = &q.open[0].as_ref().expect("expect that next_entry only leaves successfully opened files at the front").info.path;
+        let sizes = by_path.get_mut(path).and_then(|v| v.pop_front()).unwrap_or_default();
+        for size in sizes {
+            // the recorded size may be anything, read it in bounded chunks
+            let mut remaining = size;
+            while remaining > 0 {
+                let chunk = std::cmp::min(remaining, PREFETCH_BLOCK) as usize;
+                let n = read_up_to(&mut q, &mut buf[..chunk])?;
+                total += n as u64;
+                remaining -= n as u64;
+                if n < chunk {
+                    break
+                }
+            }
+        }
+    }
+    Ok(total)
+}
+
+// reads of the recorded size may come back short, fill them like the consumer presumably did
+fn read_up_to<Src: Iterator<Item=PathBuf>>(q: &mut MultiFileReadahead<Src>, buf: &mut [u8]) -> Result<usize, Error> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match q.read_entry(0, &mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(filled)
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Records opens, consumer reads and the planner's advice to `w` in a compact binary format,
+    /// `None` stops recording.
+    ///
+    /// Reads on worker threads of `for_each_parallel` are not recorded, only the advice for them.
+    /// Write errors end the recording. They, or a failure to flush, are returned when the
+    /// recording is stopped or replaced.
+    pub fn trace(&mut self, w: Option<Box<dyn Write + Send>>) -> Result<(), Error> {
+        let old = std::mem::replace(&mut self.hooks.trace, w.map(Tracer::new));
+        match old {
+            Some(t) => t.finish(),
+            None => Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use testutil::{read_front, Files};
+
+    #[derive(Clone, Default)]
+    struct Recording(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Recording {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    fn encoded(len: u64, path: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        put_varint(&mut buf, len);
+        buf.extend_from_slice(path);
+        buf
+    }
+
+    #[test]
+    fn path_round_trip() {
+        let buf = encoded(4, b"/a/b");
+        assert_eq!(get_path(&mut &buf[..]).unwrap(), PathBuf::from("/a/b"));
+    }
+
+    #[test]
+    fn path_length_past_the_data() {
+        let buf = encoded(10, b"/a/b");
+        assert_eq!(get_path(&mut &buf[..]).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn huge_path_length_is_not_allocated() {
+        let buf = encoded(u64::MAX, b"/a/b");
+        assert_eq!(get_path(&mut &buf[..]).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn overlong_varint() {
+        let buf = [0xffu8; 11];
+        assert_eq!(get_varint(&mut &buf[..]).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let files = Files::new(&[("/a", &[1; 100_000]), ("/c", b"hello")]);
+        let mut q = files.queue(&["/a", "/b", "/c"]);
+        let recording = Recording::default();
+        q.trace(Some(Box::new(recording.clone()))).unwrap();
+        while let Some(r) = q.next() {
+            match r {
+                // peeked bytes are only reported once, when they are read from the file
+                Ok(mut r) => r.peek_head(3).map(|_| ()).unwrap(),
+                Err(_) => continue
+            }
+            read_front(&mut q);
+        }
+        q.trace(None).unwrap();
+
+        let events = read_trace(&recording.0.lock().unwrap()[..]).unwrap();
+        let read: u64 = events.iter().map(|e| match *e {
+            TraceEvent::Read {bytes, ..} => bytes,
+            _ => 0
+        }).sum();
+        assert_eq!(read, 100_005);
+        assert!(events.contains(&TraceEvent::OpenFailed {path: files.path("/b")}));
+
+        let replayed = replay_trace(&events, |q| q.filesystem(Some(Box::new(files.fs())))).unwrap();
+        assert_eq!(replayed, 100_005);
+    }
+
+    #[test]
+    fn replay_bounds_recorded_sizes() {
+        let files = Files::new(&[("/a", b"abc")]);
+        let events = [
+            TraceEvent::Open {id: 0, path: files.path("/a"), len: 3},
+            TraceEvent::Read {id: 0, bytes: u64::MAX},
+        ];
+        assert_eq!(replay_trace(&events, |q| q.filesystem(Some(Box::new(files.fs())))).unwrap(), 3);
+    }
+}
@@ -0,0 +1,156 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::io::{Error, ErrorKind, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use copy::{create_destination, CopySources};
+use {Budget, CopyFailed, MultiFileReadahead, WriteBehind, DROPBEHIND_BLOCK};
+
+const TRANSFORM_CHUNK: usize = DROPBEHIND_BLOCK as usize;
+
+/// Turns the data of a file into the data of its destination, e.g. a compressor or an encryptor.
+/// A transform is created for each file and fed the file front to back.
+pub trait Transform {
+    /// Transforms the next chunk of the file, appending the result to `out`.
+    fn update(&mut self, input: &[u8], out: &mut Vec<u8>) -> Result<(), Error>;
+
+    /// Appends whatever is left once the whole file was passed to `update`, e.g. a trailer.
+    fn finish(&mut self, _out: &mut Vec<u8>) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<F: FnMut(&[u8], &mut Vec<u8>) -> Result<(), Error>> Transform for F {
+    fn update(&mut self, input: &[u8], out: &mut Vec<u8>) -> Result<(), Error> {
+        self(input, out)
+    }
+}
+
+/// Creates the transform for a `(source, destination)` pair.
+pub type TransformFn<T> = Box<dyn FnMut(&Path, &Path) -> T + Send>;
+
+/// A successfully transformed file.
+#[derive(Debug)]
+pub struct Transformed {
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    /// Bytes read from the source
+    pub bytes_read: u64,
+    /// Bytes written to the destination
+    pub bytes_written: u64,
+}
+
+/// Streams (source, destination) pairs through a [`Transform`](trait.Transform.html), with
+/// readahead and dropbehind on the sources and [`WriteBehind`](struct.WriteBehind.html) on the
+/// destinations.
+///
+/// The transform's output and the written data that isn't on disk yet count against the prefetch
+/// budget, so a slow destination or a transform that expands its input holds back the readahead
+/// instead of piling up memory. Destinations are created like those of [`MultiFileCopy`](struct.MultiFileCopy.html).
+pub struct MultiFileTransform<Src, T> {
+    inner: MultiFileReadahead<CopySources<Src>>,
+    make: TransformFn<T>,
+    output: Vec<u8>,
+    max_dirty: Option<u64>,
+    dropbehind: bool,
+    atomic: bool,
+}
+
+impl<Src: Iterator<Item=(PathBuf, PathBuf)>, T: Transform> MultiFileTransform<Src, T> {
+
+    pub fn new(pairs: Src, make: TransformFn<T>) -> Self {
+        MultiFileTransform {
+            inner: MultiFileReadahead::new(CopySources::new(pairs)),
+            make,
+            output: Vec::new(),
+            max_dirty: None,
+            dropbehind: false,
+            atomic: false,
+        }
+    }
+
+    /// Drops both sources and destinations from the page cache behind the transform.
+    pub fn dropbehind(&mut self, v: bool) {
+        self.dropbehind = v;
+        self.inner.dropbehind(v);
+    }
+
+    /// See [`WriteBehind::max_dirty`](struct.WriteBehind.html#method.max_dirty).
+    pub fn max_dirty(&mut self, bytes: u64) {
+        self.max_dirty = Some(bytes);
+    }
+
+    /// See [`MultiFileCopy::atomic`](struct.MultiFileCopy.html#method.atomic).
+    pub fn atomic(&mut self, v: bool) {
+        self.atomic = v;
+    }
+
+    /// The budget shared by the readahead, the transform's output and the destination data that
+    /// isn't on disk yet, see [`MultiFileReadahead::budget`](struct.MultiFileReadahead.html#method.budget).
+    pub fn budget(&mut self, budget: Budget) {
+        self.inner.budget(budget);
+    }
+
+    /// Transforms the next file. Failures are reported per file and work continues with the next one.
+    pub fn next_transform(&mut self) -> Option<Result<Transformed, CopyFailed>> {
+        let entry = self.inner.next_entry()?;
+        let (source, destination) = self.inner.source.pairs.pop_front().expect("expect one pair per queue entry");
+        let result = entry.and_then(|_| self.transform_current(&source, &destination));
+        Some(match result {
+            Ok((bytes_read, bytes_written)) => Ok(Transformed {source, destination, bytes_read, bytes_written}),
+            Err(error) => Err(CopyFailed {source, destination, error}),
+        })
+    }
+
+    fn transform_current(&mut self, source: &Path, destination: &Path) -> Result<(u64, u64), Error> {
+        let meta = self.inner.open[0].as_ref().expect("expect that next_entry only leaves successfully opened files at the front").f.metadata()?;
+        let mut out = create_destination(destination, meta.mode(), self.atomic)?;
+        if let Some(bytes) = self.max_dirty {
+            out.max_dirty(bytes);
+        }
+        out.dropbehind(self.dropbehind);
+        let mut t = (self.make)(source, destination);
+
+        let mut input = std::mem::take(&mut self.inner.scratch);
+        input.resize(TRANSFORM_CHUNK, 0);
+        let mut output = std::mem::take(&mut self.output);
+        let result = self.stream(&mut t, &mut out, &mut input, &mut output);
+        self.inner.scratch = input;
+        self.output = output;
+        // the queue counts its scratch buffer by itself
+        self.inner.buffered = self.output.capacity() as u64;
+
+        let counts = result?;
+        out.persist()?;
+        Ok(counts)
+    }
+
+    fn stream(&mut self, t: &mut T, out: &mut WriteBehind, input: &mut [u8], output: &mut Vec<u8>) -> Result<(u64, u64), Error> {
+        let (mut read, mut written) = (0, 0);
+        loop {
+            output.clear();
+            self.inner.buffered = (input.len() + output.capacity()) as u64 + out.dirty();
+            let n = match self.inner.read_entry(0, input) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e)
+            };
+            if n == 0 {
+                t.finish(output)?;
+            } else {
+                t.update(&input[..n], output)?;
+            }
+            out.write_all(output)?;
+            read += n as u64;
+            written += output.len() as u64;
+            if n == 0 {
+                return Ok((read, written))
+            }
+        }
+    }
+}
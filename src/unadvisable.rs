@@ -0,0 +1,198 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::fs::File;
+use std::io::{Error, ErrorKind};
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Weak};
+use std::thread;
+use hooks::Hooks;
+use {MultiFileReadahead, Prefetch};
+
+// WillNeed failures in a row after which a file, and the device it's on, are no longer advised
+const ADVISE_ATTEMPTS: u32 = 3;
+// read size of the helper thread standing in for the advice by default
+pub(crate) const FALLBACK_CHUNK: usize = 1024 * 1024;
+
+// errors that won't go away by retrying, e.g. from filesystems without fadvise support or seccomp filters
+fn is_permanent(e: &Error) -> bool {
+    // ENOTSUP and EOPNOTSUPP only differ on some platforms
+    e.kind() == ErrorKind::Unsupported || e.raw_os_error().is_some_and(|c| [libc::EOPNOTSUPP, libc::ENOTSUP, libc::EPERM, libc::EACCES, libc::ENOSYS].contains(&c))
+}
+
+struct Warm {
+    f: File,
+    offset: u64,
+    len: u64,
+    // the consumer's read position, gone once the queue retired the file
+    read_pos: Weak<AtomicU64>,
+}
+
+// reads the ranges the planner would have advised into a throwaway buffer so that they end up in the page cache
+pub(crate) struct Fallback {
+    chunk: usize,
+    worker: Option<Sender<Warm>>,
+}
+
+fn warm(buf: &mut [u8], req: Warm) {
+    let end = req.offset + req.len;
+    let mut pos = req.offset;
+    while pos < end {
+        // nothing left to do once the consumer got here first or the file was retired
+        let read_pos = match req.read_pos.upgrade() {
+            Some(p) => p.load(Ordering::Relaxed),
+            None => return
+        };
+        pos = std::cmp::max(pos, read_pos);
+        if pos >= end {
+            return
+        }
+        let n = std::cmp::min(buf.len() as u64, end - pos) as usize;
+        match req.f.read_at(&mut buf[..n], pos) {
+            Ok(0) => return,
+            Ok(n) => pos += n as u64,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(_) => return
+        }
+    }
+}
+
+impl Fallback {
+    pub(crate) fn new(chunk: usize) -> Self {
+        Fallback {chunk: std::cmp::max(chunk, 4096), worker: None}
+    }
+
+    pub(crate) fn footprint(&self) -> u64 {
+        if self.worker.is_some() { self.chunk as u64 } else { 0 }
+    }
+
+    fn spawn(chunk: usize) -> Sender<Warm> {
+        let (tx, rx) = channel::<Warm>();
+        thread::spawn(move || {
+            let mut buf = vec![0u8; chunk];
+            for req in rx {
+                warm(&mut buf, req);
+            }
+        });
+        tx
+    }
+
+    fn warm(&mut self, f: &File, offset: u64, len: u64, read_pos: &Arc<AtomicU64>) {
+        let f = match f.try_clone() {
+            Ok(f) => f,
+            Err(_) => return
+        };
+        let chunk = self.chunk;
+        let req = Warm {f, offset, len, read_pos: Arc::downgrade(read_pos)};
+        if self.worker.get_or_insert_with(|| Fallback::spawn(chunk)).send(req).is_err() {
+            // the worker died, start a fresh one next time
+            self.worker = None;
+        }
+    }
+}
+
+impl Prefetch {
+
+    // gives up on advising once it keeps failing, e.g. with EINVAL on filesystems that don't
+    // support it, or right away if the error says that it never will work
+    pub(crate) fn willneed(&mut self, offset: u64, len: u64, hooks: &mut Hooks) {
+        if let Some(ref read_pos) = self.emulated {
+            hooks.emulate(self.id, &self.f, offset, len, read_pos);
+            return
+        }
+        // io_uring reports the outcome once the batch completes, see advice_completed
+        if let Some(result) = hooks.willneed(self.id, &self.f, offset, len) {
+            self.advised(result, offset, len, hooks);
+        }
+    }
+
+    // accounts for the outcome of advising the range, when it failed for good the range is read instead
+    fn advised(&mut self, result: Result<(), Error>, offset: u64, len: u64, hooks: &mut Hooks) {
+        match result {
+            Ok(()) => self.advise_failures = 0,
+            Err(e) => {
+                self.advise_failures += 1;
+                if self.advise_failures >= ADVISE_ATTEMPTS || is_permanent(&e) {
+                    if let Ok(m) = self.f.metadata() {
+                        hooks.unadvisable_devs.insert(m.dev());
+                    }
+                    self.give_up(hooks);
+                    if let Some(ref read_pos) = self.emulated {
+                        hooks.emulate(self.id, &self.f, offset, len, read_pos);
+                    }
+                }
+            }
+        }
+    }
+
+    fn give_up(&mut self, hooks: &Hooks) {
+        if hooks.fallback.is_some() {
+            self.emulated = Some(Arc::new(AtomicU64::new(self.read_pos)));
+        } else {
+            self.unadvisable = true;
+        }
+    }
+
+    // files opened on a device that rejected advice before skip it from the start
+    pub(crate) fn check_advisable(&mut self, hooks: &Hooks) {
+        if hooks.unadvisable_devs.is_empty() {
+            return
+        }
+        if let Ok(m) = self.f.metadata() {
+            if hooks.unadvisable_devs.contains(&m.dev()) {
+                self.give_up(hooks);
+            }
+        }
+    }
+}
+
+impl Hooks {
+    pub(crate) fn emulate(&mut self, id: u64, f: &File, offset: u64, len: u64, read_pos: &Arc<AtomicU64>) {
+        if let Some(ref mut t) = self.trace {
+            t.willneed(id, offset, len);
+        }
+        if self.dry_run {
+            return
+        }
+        if let Some(ref mut fallback) = self.fallback {
+            fallback.warm(f, offset, len, read_pos);
+        }
+    }
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Files whose prefetch advice keeps getting rejected are no longer advised, nor are other
+    /// files on the same device. Errors such as `EOPNOTSUPP` or `EPERM`, e.g. from FUSE filesystems or
+    /// seccomp sandboxes, give up on the first attempt.
+    ///
+    /// With `Some(chunk)` the ranges the planner picks for such files are instead read into a
+    /// throwaway buffer of `chunk` bytes on a helper thread, which fills the page cache ahead of the consumer
+    /// under the same budget. That's the default with 1 MiB chunks, the thread is only started once it's needed.
+    /// `None` reads them on the calling thread without any prefetching.
+    pub fn fallback_reads(&mut self, chunk: Option<usize>) {
+        self.hooks.fallback = chunk.map(Fallback::new);
+    }
+
+    // applies the outcome of advice submitted through io_uring
+    pub(crate) fn advice_completed(&mut self) {
+        for (id, result) in self.hooks.take_completed() {
+            let p = self.open.iter_mut().filter_map(|o| o.as_mut().ok()).find(|p| p.id == id);
+            if let Some(p) = p {
+                if p.emulated.is_some() || p.unadvisable {
+                    continue
+                }
+                // the range is unknown by now, read what was advised ahead of the consumer instead
+                let (offset, len) = (p.read_pos, p.prefetch_pos.saturating_sub(p.read_pos));
+                p.advised(result, offset, len, &mut self.hooks);
+            }
+        }
+    }
+}
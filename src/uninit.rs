@@ -0,0 +1,80 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read};
+use std::mem::MaybeUninit;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use {Reader, PREFETCH_BLOCK};
+
+// the read paths only ever write initialized bytes into the buffer, so it stays initialized
+pub(crate) fn as_uninit(buf: &mut [u8]) -> &mut [MaybeUninit<u8>] {
+    unsafe { &mut *(buf as *mut [u8] as *mut [MaybeUninit<u8>]) }
+}
+
+// zero-fills the buffer unless it's known to be initialized already
+pub(crate) fn init(buf: &mut [MaybeUninit<u8>], initialized: bool) -> &mut [u8] {
+    if !initialized {
+        unsafe { std::ptr::write_bytes(buf.as_mut_ptr(), 0, buf.len()) };
+    }
+    unsafe { &mut *(buf as *mut [MaybeUninit<u8>] as *mut [u8]) }
+}
+
+pub(crate) fn copy(buf: &mut [MaybeUninit<u8>], src: &[u8]) {
+    assert!(buf.len() >= src.len());
+    unsafe { std::ptr::copy_nonoverlapping(src.as_ptr(), buf.as_mut_ptr() as *mut u8, src.len()) };
+}
+
+pub(crate) fn read(f: &File, buf: &mut [MaybeUninit<u8>]) -> Result<usize, Error> {
+    let len = std::cmp::min(buf.len(), isize::MAX as usize);
+    let n = unsafe { libc::read(f.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, len) };
+    if n < 0 {
+        return Err(Error::last_os_error())
+    }
+    Ok(n as usize)
+}
+
+impl<'a, T> Reader<'a, T> where T: Iterator<Item=PathBuf> {
+
+    /// Like `read`, but into a buffer that doesn't have to be initialized, e.g. the spare capacity of a `Vec`.
+    /// The first `n` bytes of `buf` are initialized after returning `Ok(n)`.
+    ///
+    /// Skips zero-filling the buffer, which is noticeable at cached read speeds. `read_to_end` uses it,
+    /// as does `read_buf` with the `read_buf` feature on nightly.
+    pub fn read_uninit(&mut self, buf: &mut [MaybeUninit<u8>]) -> Result<usize, Error> {
+        self.owner.read_entry_uninit(self.idx, buf, false)
+    }
+
+    pub(crate) fn read_to_vec(&mut self, v: &mut Vec<u8>) -> Result<usize, Error> {
+        let start = v.len();
+        let progress = self.progress();
+        v.reserve(progress.len.saturating_sub(progress.read_pos) as usize);
+        loop {
+            if v.capacity() == v.len() {
+                // probe for the end before growing the vec in case it was sized exactly
+                let mut probe = [0u8; 32];
+                match self.read(&mut probe) {
+                    Ok(0) => return Ok(v.len() - start),
+                    Ok(n) => {
+                        v.extend_from_slice(&probe[..n]);
+                        v.reserve(PREFETCH_BLOCK as usize);
+                    }
+                    Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e)
+                }
+                continue
+            }
+            match self.read_uninit(v.spare_capacity_mut()) {
+                Ok(0) => return Ok(v.len() - start),
+                Ok(n) => unsafe { v.set_len(v.len() + n) },
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e)
+            }
+        }
+    }
+}
@@ -0,0 +1,92 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use io_uring::{opcode, types, IoUring, Probe};
+use std::fs::File;
+use std::io::{Error, ErrorKind};
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use MultiFileReadahead;
+
+const ENTRIES: u32 = 256;
+
+// collects fadvise calls and submits them with a single syscall
+pub(crate) struct AdviseRing {
+    ring: IoUring,
+    pending: u32,
+    // outcomes of WillNeed advice by file id, until the queue picks them up
+    completed: Vec<(u64, Result<(), Error>)>,
+}
+
+// user data of submissions whose outcome doesn't matter, ids of files whose advice does are stored off by one
+const IGNORED: u64 = 0;
+
+impl AdviseRing {
+    fn new() -> Result<Self, Error> {
+        let ring = IoUring::new(ENTRIES)?;
+        let mut probe = Probe::new();
+        ring.submitter().register_probe(&mut probe)?;
+        if !probe.is_supported(opcode::Fadvise::CODE) {
+            return Err(Error::new(ErrorKind::Unsupported, "io_uring lacks IORING_OP_FADVISE"))
+        }
+        Ok(AdviseRing {ring, pending: 0, completed: Vec::new()})
+    }
+
+    // false if the advice has to be issued directly instead, the outcome is reported for `file` if set
+    pub(crate) fn push(&mut self, f: &File, offset: u64, len: u64, advice: libc::c_int, file: Option<u64>) -> bool {
+        // the length field of a submission only has 32 bits
+        if len > u64::from(u32::MAX) {
+            return false
+        }
+        if self.pending == ENTRIES {
+            self.flush();
+        }
+        let entry = opcode::Fadvise::new(types::Fd(f.as_raw_fd()), len as libc::off_t, advice).offset(offset).build()
+            .user_data(file.map_or(IGNORED, |id| id + 1));
+        if unsafe { self.ring.submission().push(&entry) }.is_err() {
+            return false
+        }
+        self.pending += 1;
+        true
+    }
+
+    // submits everything pushed so far, must happen before the pushed files are closed
+    pub(crate) fn flush(&mut self) {
+        if self.pending > 0 {
+            // like the direct calls, advice is best effort
+            let _ = self.ring.submit();
+            self.pending = 0;
+        }
+        for cqe in self.ring.completion() {
+            if cqe.user_data() == IGNORED {
+                continue
+            }
+            let result = if cqe.result() < 0 { Err(Error::from_raw_os_error(-cqe.result())) } else { Ok(()) };
+            self.completed.push((cqe.user_data() - 1, result));
+        }
+    }
+
+    pub(crate) fn take_completed(&mut self) -> Vec<(u64, Result<(), Error>)> {
+        std::mem::take(&mut self.completed)
+    }
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Submits the advice of each planning step as one io_uring batch instead of a syscall per
+    /// range, which saves CPU when prefetching many small files. Fails if the kernel doesn't
+    /// support `IORING_OP_FADVISE`, which needs Linux 5.6.
+    ///
+    /// Only covers advice issued by the queue itself, not that of worker threads or shuffled records.
+    pub fn io_uring_advise(&mut self, enabled: bool) -> Result<(), Error> {
+        if let Some(ref mut r) = self.hooks.ring {
+            r.flush();
+        }
+        self.hooks.ring = if enabled { Some(AdviseRing::new()?) } else { None };
+        Ok(())
+    }
+}
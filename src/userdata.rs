@@ -0,0 +1,71 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use MultiFileReadahead;
+
+// shared so that repeated passes can hand out the payload of the first one again
+pub(crate) type UserData = Arc<dyn Any + Send + Sync>;
+
+// payloads of paths pulled from the source but not yet opened, the same path may be queued more than once
+#[derive(Default)]
+pub(crate) struct Stash(Mutex<HashMap<PathBuf, VecDeque<UserData>>>);
+
+impl Stash {
+    fn push(&self, p: PathBuf, data: UserData) {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).entry(p).or_default().push_back(data);
+    }
+
+    pub(crate) fn pop(&self, p: &Path) -> Option<UserData> {
+        let mut map = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        let queued = map.get_mut(p)?;
+        let data = queued.pop_front();
+        if queued.is_empty() {
+            map.remove(p);
+        }
+        data
+    }
+
+    // a deferred open gets its payload back ahead of later duplicates
+    pub(crate) fn unpop(&self, p: &Path, data: UserData) {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).entry(p.to_owned()).or_default().push_front(data);
+    }
+}
+
+/// Source adapter of [`MultiFileReadahead::with_user_data`](struct.MultiFileReadahead.html#method.with_user_data).
+pub struct WithUserData<Src> {
+    inner: Src,
+    stash: Arc<Stash>,
+}
+
+impl<Src: Iterator<Item=(PathBuf, T)>, T: Send + Sync + 'static> Iterator for WithUserData<Src> {
+    type Item = PathBuf;
+
+    fn next(&mut self) -> Option<PathBuf> {
+        let (p, data) = self.inner.next()?;
+        self.stash.push(p.clone(), Arc::new(data));
+        Some(p)
+    }
+}
+
+impl<Src: Iterator<Item=(PathBuf, T)>, T: Send + Sync + 'static> MultiFileReadahead<WithUserData<Src>> {
+
+    /// Queues `(path, data)` pairs and carries the data through the queue so that each delivered file
+    /// can be matched up with the record it came from, see [`Reader::user_data`](struct.Reader.html#method.user_data).
+    ///
+    /// Members of expanded directories don't inherit the data of their directory. Passes of
+    /// [`repeat`](struct.MultiFileReadahead.html#method.repeat) carry the data of the first one.
+    pub fn with_user_data(src: Src) -> Self {
+        let stash = Arc::new(Stash::default());
+        let mut q = MultiFileReadahead::new(WithUserData {inner: src, stash: stash.clone()});
+        q.user_data = Some(stash);
+        q
+    }
+}
@@ -0,0 +1,252 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use sys;
+use DROPBEHIND_BLOCK;
+
+const DEFAULT_MAX_DIRTY : u64 = 16*1024*1024;
+
+// where an atomically written file ends up
+struct Pending {
+    destination: PathBuf,
+    // the name it's written under until then if the filesystem can't create unnamed files
+    temporary: Option<PathBuf>,
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        if let Some(ref t) = self.temporary {
+            let _ = std::fs::remove_file(t);
+        }
+    }
+}
+
+// a hidden name next to the destination
+fn temporary_name(destination: &Path) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let name = destination.file_name().map_or("".into(), |n| n.to_string_lossy());
+    let name = format!(".{}.reapfrog-{}-{}", name, std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed));
+    destination.with_file_name(name)
+}
+
+fn no_unnamed_files(e: &Error) -> bool {
+    e.kind() == ErrorKind::Unsupported || matches!(e.raw_os_error(), Some(libc::EOPNOTSUPP) | Some(libc::EISDIR) | Some(libc::EINVAL))
+}
+
+/// The write-side counterpart of the queue, for sequentially written files.
+///
+/// Writeback is started for every block behind the write cursor and the writer waits for the
+/// oldest data once more than [`max_dirty`](#method.max_dirty) bytes are not yet on disk, so that
+/// bulk writes don't pile up until the kernel's dirty limits stall the whole process.
+pub struct WriteBehind {
+    f: File,
+    pos: u64,
+    // everything below has been submitted for writeback
+    started: u64,
+    // everything below is on disk
+    synced: u64,
+    max_dirty: u64,
+    dropbehind: bool,
+    pending: Option<Pending>,
+}
+
+impl WriteBehind {
+
+    pub fn new(f: File) -> Self {
+        WriteBehind{f, pos: 0, started: 0, synced: 0, max_dirty: DEFAULT_MAX_DIRTY, dropbehind: false, pending: None}
+    }
+
+    /// Creates `destination` so that it only appears once it's complete. The data goes to an
+    /// unnamed `O_TMPFILE` in the destination's directory that [`persist`](#method.persist) links
+    /// into place, replacing an existing file. If the writer is dropped or the process dies before,
+    /// nothing becomes visible.
+    ///
+    /// Where unnamed files aren't supported the data is written to a hidden file next to the
+    /// destination and renamed instead. That file is removed when the writer is dropped, it's only
+    /// left behind if the process dies.
+    pub fn atomic(destination: &Path, mode: u32) -> Result<Self, Error> {
+        let dir = match destination.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new(".")
+        };
+        let (f, temporary) = match sys::unnamed_file(dir, mode) {
+            Ok(f) => (f, None),
+            Err(ref e) if no_unnamed_files(e) => {
+                let t = temporary_name(destination);
+                let f = OpenOptions::new().write(true).create_new(true).mode(mode).open(&t)?;
+                (f, Some(t))
+            }
+            Err(e) => return Err(e)
+        };
+        let mut w = Self::new(f);
+        w.pending = Some(Pending {destination: destination.to_owned(), temporary});
+        Ok(w)
+    }
+
+    /// Syncs an [`atomic`](#method.atomic) file and moves it into place. Other writers are
+    /// returned as is.
+    pub fn persist(mut self) -> Result<File, Error> {
+        let mut pending = match self.pending.take() {
+            Some(p) => p,
+            None => return Ok(self.f)
+        };
+        self.f.sync_data()?;
+        if let Some(t) = pending.temporary.take() {
+            std::fs::rename(&t, &pending.destination).inspect_err(|_| {
+                let _ = std::fs::remove_file(&t);
+            })?;
+            return Ok(self.f)
+        }
+        match sys::link_file(&self.f, &pending.destination) {
+            Ok(()) => {}
+            // linking can't replace, link under another name and rename that over the destination
+            Err(ref e) if e.kind() == ErrorKind::AlreadyExists => {
+                let t = temporary_name(&pending.destination);
+                sys::link_file(&self.f, &t)?;
+                std::fs::rename(&t, &pending.destination).inspect_err(|_| {
+                    let _ = std::fs::remove_file(&t);
+                })?;
+            }
+            Err(e) => return Err(e)
+        }
+        Ok(self.f)
+    }
+
+    /// Bounds the amount of written data that may not yet be on disk, at least one block.
+    pub fn max_dirty(&mut self, bytes: u64) {
+        self.max_dirty = std::cmp::max(bytes, DROPBEHIND_BLOCK);
+    }
+
+    /// Drops written data from the page cache once it is on disk.
+    pub fn dropbehind(&mut self, v: bool) {
+        self.dropbehind = v;
+    }
+
+    /// Reserves space for `len` bytes up front, which avoids fragmentation and surfaces `ENOSPC`
+    /// before anything is written. The file size is unchanged. Fails with `ErrorKind::Unsupported`
+    /// where the filesystem or platform can't preallocate.
+    pub fn preallocate(&mut self, len: u64) -> Result<(), Error> {
+        sys::preallocate(&self.f, len)
+    }
+
+    pub fn get_ref(&self) -> &File {
+        &self.f
+    }
+
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    /// An [`atomic`](#method.atomic) file that wasn't persisted is discarded.
+    pub fn into_inner(self) -> File {
+        self.f
+    }
+
+    // bytes written but not yet on disk
+    pub(crate) fn dirty(&self) -> u64 {
+        self.pos - self.synced
+    }
+
+    // accounts for data written to the file behind our back, e.g. by copy_file_range
+    pub(crate) fn wrote(&mut self, bytes: u64) -> Result<(), Error> {
+        self.pos += bytes;
+        self.throttle()
+    }
+
+    fn throttle(&mut self) -> Result<(), Error> {
+        if self.pos - self.started >= DROPBEHIND_BLOCK {
+            sys::start_writeback(&self.f, self.started, self.pos - self.started);
+            self.started = self.pos;
+        }
+        if self.pos - self.synced > self.max_dirty {
+            self.wait(self.started)?;
+        }
+        Ok(())
+    }
+
+    fn wait(&mut self, until: u64) -> Result<(), Error> {
+        if until <= self.synced {
+            return Ok(())
+        }
+        sys::wait_writeback(&self.f, self.synced, until - self.synced)?;
+        if self.dropbehind {
+            sys::advise_dontneed(&self.f, self.synced, until - self.synced);
+        }
+        self.synced = until;
+        Ok(())
+    }
+}
+
+impl Write for WriteBehind {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        // throttle for the previous writes first, an error must not follow a successful write
+        self.throttle()?;
+        let bytes = self.f.write(buf)?;
+        self.pos += bytes as u64;
+        Ok(bytes)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.f.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Seek, SeekFrom};
+    use std::os::unix::io::FromRawFd;
+    use std::thread;
+
+    #[test]
+    fn throttled_writes_arrive() {
+        let mut w = WriteBehind::new(sys::anonymous_file().unwrap());
+        w.max_dirty(DROPBEHIND_BLOCK);
+        let data: Vec<u8> = (0..3 * DROPBEHIND_BLOCK + 5).map(|i| i as u8).collect();
+        for chunk in data.chunks(100_000) {
+            w.write_all(chunk).unwrap();
+        }
+        w.flush().unwrap();
+        assert_eq!(w.position(), data.len() as u64);
+        let mut f = w.into_inner();
+        f.seek(SeekFrom::Start(0)).unwrap();
+        let mut written = Vec::new();
+        f.read_to_end(&mut written).unwrap();
+        assert_eq!(written, data);
+    }
+
+    #[test]
+    fn failed_throttle_writes_nothing() {
+        // writeback can't be waited for on a pipe
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let (mut rx, tx) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        let drain = thread::spawn(move || std::io::copy(&mut rx, &mut std::io::sink()).unwrap());
+        let mut w = WriteBehind::new(tx);
+        w.max_dirty(DROPBEHIND_BLOCK);
+        let buf = [0u8; 64 * 1024];
+        loop {
+            let before = w.position();
+            match w.write(&buf) {
+                Ok(n) => assert_eq!(w.position(), before + n as u64),
+                Err(_) => {
+                    assert_eq!(w.position(), before);
+                    break
+                }
+            }
+        }
+        let written = w.position();
+        drop(w);
+        assert_eq!(drain.join().unwrap(), written);
+    }
+}
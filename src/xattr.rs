@@ -0,0 +1,81 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::ffi::{CStr, OsStr, OsString};
+use std::fs::File;
+use std::io::Error;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use MultiFileReadahead;
+
+/// An extended attribute of a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Xattr {
+    pub name: OsString,
+    pub value: Vec<u8>,
+}
+
+// calls f with growing buffers until the value fits
+fn sized_call<F>(mut f: F) -> Result<Vec<u8>, Error> where F: FnMut(*mut libc::c_void, usize) -> isize {
+    loop {
+        let size = f(std::ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(Error::last_os_error())
+        }
+        let mut buf = vec![0u8; size as usize];
+        let got = f(buf.as_mut_ptr() as *mut libc::c_void, buf.len());
+        if got >= 0 {
+            buf.truncate(got as usize);
+            return Ok(buf)
+        }
+        let err = Error::last_os_error();
+        // attributes changed between the calls
+        if err.raw_os_error() != Some(libc::ERANGE) {
+            return Err(err)
+        }
+    }
+}
+
+pub(crate) fn read_xattrs(f: &File) -> Result<Vec<Xattr>, Error> {
+    read_matching(f, |_| true)
+}
+
+// reads only the attributes whose names pass the filter
+pub(crate) fn read_matching<F>(f: &File, wanted: F) -> Result<Vec<Xattr>, Error> where F: Fn(&[u8]) -> bool {
+    let fd = f.as_raw_fd();
+    let names = match sized_call(|buf, len| unsafe { libc::flistxattr(fd, buf as *mut libc::c_char, len) }) {
+        Ok(n) => n,
+        Err(ref e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(Vec::new()),
+        Err(e) => return Err(e)
+    };
+
+    let mut attrs = Vec::new();
+    for name in names.split(|&b| b == 0).filter(|n| !n.is_empty() && wanted(n)) {
+        let mut cname = name.to_vec();
+        cname.push(0);
+        let cname = CStr::from_bytes_with_nul(&cname).expect("names from flistxattr don't contain NULs");
+        let value = match sized_call(|buf, len| unsafe { libc::fgetxattr(fd, cname.as_ptr(), buf, len) }) {
+            Ok(v) => v,
+            // removed in the meantime
+            Err(ref e) if e.raw_os_error() == Some(libc::ENODATA) => continue,
+            Err(e) => return Err(e)
+        };
+        attrs.push(Xattr {name: OsStr::from_bytes(name).to_owned(), value});
+    }
+    Ok(attrs)
+}
+
+impl<Src: Iterator<Item=PathBuf>> MultiFileReadahead<Src> {
+
+    /// Reads extended attributes of files while they are queued so that the syscalls overlap
+    /// with prefetching instead of happening after the data has been read.
+    /// See [`Reader::xattrs`](struct.Reader.html#method.xattrs).
+    pub fn xattrs(&mut self, enabled: bool) {
+        self.xattrs = enabled;
+    }
+}
@@ -0,0 +1,176 @@
+//   reapfrog
+//   Copyright (C) 2017 The 8472
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use {MultiFileReadahead, Reader, PREFETCH_BLOCK};
+
+// a side may prefetch twice as much as the other, or this much while the other has nothing prefetched
+const MIN_LEAD: u64 = 16 * PREFETCH_BLOCK;
+
+/// Two separately configured queues read pairwise, obtained from [`zip`](struct.MultiFileReadahead.html#method.zip).
+pub struct Zip<A, B> {
+    left: MultiFileReadahead<A>,
+    right: MultiFileReadahead<B>,
+}
+
+/// The current files of both sides of a [`Zip`](struct.Zip.html).
+pub struct ZipPair<'a, A: 'a, B: 'a> {
+    left: &'a mut MultiFileReadahead<A>,
+    right: &'a mut MultiFileReadahead<B>,
+}
+
+impl<A: Iterator<Item=PathBuf>> MultiFileReadahead<A> {
+
+    /// Pairs the files of this queue with those of `other` for byte-wise comparisons of two
+    /// trees on different devices, where [`paired`](#method.paired) would plan both under one budget.
+    /// Iteration ends as soon as either side does.
+    ///
+    /// Each side keeps its own budget and configuration, but neither prefetches more than twice
+    /// as much as the other has prefetched ahead of its consumer, e.g. when one of them backs off
+    /// under I/O pressure or has the larger budget. That's checked on every
+    /// [`ZipPair::read_both`](struct.ZipPair.html#method.read_both), which also keeps the consumer at
+    /// the same offset on both sides.
+    pub fn zip<B: Iterator<Item=PathBuf>>(self, other: MultiFileReadahead<B>) -> Zip<A, B> {
+        Zip {left: self, right: other}
+    }
+
+    // bytes prefetched ahead of the consumer
+    fn ahead(&self) -> u64 {
+        self.open.iter().filter_map(|o| o.as_ref().ok()).map(|p| p.outstanding()).sum()
+    }
+}
+
+// holds back the side that got further ahead
+fn coordinate<A: Iterator<Item=PathBuf>, B: Iterator<Item=PathBuf>>(left: &mut MultiFileReadahead<A>, right: &mut MultiFileReadahead<B>) {
+    let (l, r) = (left.ahead(), right.ahead());
+    left.lead = Some(std::cmp::max(r.saturating_mul(2), MIN_LEAD));
+    right.lead = Some(std::cmp::max(l.saturating_mul(2), MIN_LEAD));
+}
+
+impl<A: Iterator<Item=PathBuf>, B: Iterator<Item=PathBuf>> Zip<A, B> {
+
+    pub fn next_pair(&mut self) -> Option<ZipPair<'_, A, B>> {
+        coordinate(&mut self.left, &mut self.right);
+        if !self.left.next_group(1) {
+            return None
+        }
+        if !self.right.next_group(1) {
+            return None
+        }
+        Some(ZipPair {left: &mut self.left, right: &mut self.right})
+    }
+
+    pub fn into_inner(mut self) -> (MultiFileReadahead<A>, MultiFileReadahead<B>) {
+        self.left.lead = None;
+        self.right.lead = None;
+        (self.left, self.right)
+    }
+}
+
+impl<'a, A: Iterator<Item=PathBuf>, B: Iterator<Item=PathBuf>> ZipPair<'a, A, B> {
+
+    pub fn left(&mut self) -> Result<Reader<'_, A>, &Error> {
+        if self.left.open[0].is_err() {
+            return Err(&self.left.open[0].as_ref().err().unwrap().1)
+        }
+        Ok(Reader::new(self.left, 0).expect("expect that the entry was checked to be open"))
+    }
+
+    pub fn right(&mut self) -> Result<Reader<'_, B>, &Error> {
+        if self.right.open[0].is_err() {
+            return Err(&self.right.open[0].as_ref().err().unwrap().1)
+        }
+        Ok(Reader::new(self.right, 0).expect("expect that the entry was checked to be open"))
+    }
+
+    /// Reads the same number of bytes from both sides unless one of them ends first, returns
+    /// how many were read into each buffer. Only `min(left.len(), right.len())` bytes are used.
+    /// Once the left file has ended the right one is still read, so files of different lengths
+    /// don't look equal.
+    ///
+    /// Fails with `NotFound` if either side failed to open, its error can be retrieved through
+    /// [`left`](#method.left) or [`right`](#method.right).
+    pub fn read_both(&mut self, left: &mut [u8], right: &mut [u8]) -> Result<(usize, usize), Error> {
+        if self.left.open[0].is_err() || self.right.open[0].is_err() {
+            return Err(Error::new(ErrorKind::NotFound, "one side of the pair failed to open"))
+        }
+        coordinate(self.left, self.right);
+        let len = std::cmp::min(left.len(), right.len());
+        let l = fill(|buf| self.left.read_entry(0, buf), &mut left[..len])?;
+        let want = if l == 0 { len } else { l };
+        let r = fill(|buf| self.right.read_entry(0, buf), &mut right[..want])?;
+        Ok((l, r))
+    }
+}
+
+// reads until buf is full or the file ends
+fn fill<F: FnMut(&mut [u8]) -> Result<usize, Error>>(mut read: F, buf: &mut [u8]) -> Result<usize, Error> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::{Files, Queue};
+
+    // files of the given lengths, queued in that order
+    fn queue(files: &[(&str, usize)]) -> Queue {
+        let contents: Vec<_> = files.iter().map(|&(_, len)| vec![7u8; len]).collect();
+        let named: Vec<_> = files.iter().zip(&contents).map(|(&(p, _), c)| (p, &c[..])).collect();
+        Files::new(&named).queue(&files.iter().map(|f| f.0).collect::<Vec<_>>())
+    }
+
+    fn read_all(left: &[(&str, usize)], right: &[(&str, usize)]) -> Vec<Vec<(usize, usize)>> {
+        let mut zip = queue(left).zip(queue(right));
+        let mut pairs = Vec::new();
+        let (mut l, mut r) = ([0u8; 8], [0u8; 8]);
+        while let Some(mut pair) = zip.next_pair() {
+            let mut reads = Vec::new();
+            loop {
+                let n = pair.read_both(&mut l, &mut r).unwrap();
+                reads.push(n);
+                if n == (0, 0) { break }
+            }
+            pairs.push(reads);
+        }
+        pairs
+    }
+
+    #[test]
+    fn right_is_read_after_left_ends() {
+        assert_eq!(read_all(&[("/l", 10)], &[("/r", 20)]), vec![vec![(8, 8), (2, 2), (0, 8), (0, 2), (0, 0)]]);
+    }
+
+    #[test]
+    fn right_ends_first() {
+        assert_eq!(read_all(&[("/l", 10)], &[("/r", 3)]), vec![vec![(8, 3), (2, 0), (0, 0)]]);
+    }
+
+    #[test]
+    fn pairs_end_with_the_shorter_side() {
+        assert_eq!(read_all(&[("/a", 8), ("/b", 0)], &[("/c", 8)]), vec![vec![(8, 8), (0, 0)]]);
+    }
+
+    #[test]
+    fn failed_side() {
+        let mut zip = queue(&[("/l", 4)]).zip(Files::new(&[]).queue(&["/missing"]));
+        let mut pair = zip.next_pair().unwrap();
+        let (mut l, mut r) = ([0u8; 8], [0u8; 8]);
+        assert_eq!(pair.read_both(&mut l, &mut r).unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(pair.right().err().unwrap().kind(), ErrorKind::NotFound);
+    }
+}